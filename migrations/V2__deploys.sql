@@ -0,0 +1,10 @@
+CREATE TABLE deploys (
+	did				BIGSERIAL NOT NULL PRIMARY KEY UNIQUE,
+	trigger			TEXT NOT NULL,
+	status			TEXT NOT NULL DEFAULT 'running',
+	commit_id		TEXT,
+	started_at		BIGINT NOT NULL,
+	finished_at		BIGINT,
+	build_log		TEXT,
+	error			TEXT
+);
@@ -0,0 +1,8 @@
+-- analytics and deploys are partitioned by the domain of the site they belong to.
+-- rows from before multi-site support are left with an empty site and get
+-- claimed by the first configured site on startup
+ALTER TABLE paths ADD COLUMN site TEXT NOT NULL DEFAULT '';
+ALTER TABLE paths DROP CONSTRAINT paths_path_key;
+ALTER TABLE paths ADD CONSTRAINT paths_site_path_key UNIQUE (site, path);
+
+ALTER TABLE deploys ADD COLUMN site TEXT NOT NULL DEFAULT '';
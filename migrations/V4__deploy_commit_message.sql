@@ -0,0 +1 @@
+ALTER TABLE deploys ADD COLUMN commit_message TEXT;
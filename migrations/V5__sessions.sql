@@ -0,0 +1,6 @@
+CREATE TABLE sessions (
+	token_hash		TEXT NOT NULL PRIMARY KEY UNIQUE,
+	username		TEXT NOT NULL,
+	created_at		BIGINT NOT NULL,
+	expires_at		BIGINT NOT NULL
+);
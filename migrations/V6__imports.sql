@@ -0,0 +1,10 @@
+-- files imported into the analytics, by a hash of their contents so the same
+-- file isn't counted twice however it's renamed or compressed
+CREATE TABLE imports (
+	site			TEXT NOT NULL,
+	source_hash		TEXT NOT NULL,
+	name			TEXT NOT NULL,
+	requests		BIGINT NOT NULL,
+	imported_at		BIGINT NOT NULL,
+	PRIMARY KEY (site, source_hash)
+);
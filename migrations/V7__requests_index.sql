@@ -0,0 +1,2 @@
+-- graphs and comparisons count a path's requests over a range of time
+CREATE INDEX requests_pid_created_at ON requests (pid, created_at);
@@ -0,0 +1,3 @@
+-- preview deploys are recorded under the site they preview, with the branch
+-- here. null for deploys of the site itself
+ALTER TABLE deploys ADD COLUMN preview TEXT;
@@ -7,15 +7,18 @@ use actix_web::{
     dev::{ServiceRequest, ServiceResponse},
     middleware::Next,
     web::Data,
-    Error,
+    Error, HttpMessage,
 };
 
 use crate::{
     config::Config,
     db::{conn::Conn, pg::PgConn},
+    serve::CanonicalPath,
 };
 
 pub struct AnalyticsRequest {
+    /// domain of the site the request was for
+    pub site: String,
     pub hashed_ip: String,
     pub path: String,
     pub created_at_milis: i64,
@@ -25,11 +28,16 @@ pub async fn simple_analytics(
     req: ServiceRequest,
     next: Next<impl MessageBody>,
 ) -> Result<ServiceResponse<impl MessageBody>, Error> {
-    let real_ip_header = req
+    let config = req
         .app_data::<Data<Config>>()
-        .expect("missing config from app data")
-        .real_ip_header
-        .clone();
+        .expect("missing config from app data");
+    let real_ip_header = config.real_ip_header.clone();
+    let host = req.connection_info().host().to_string();
+    // previews are for reviewing changes, they shouldn't skew the real numbers
+    if config.is_preview_host(&host) {
+        return next.call(req).await;
+    }
+    let site = config.site_for_host(&host).domain.clone();
 
     let conn = req
         .app_data::<Data<PgConn>>()
@@ -51,12 +59,14 @@ pub async fn simple_analytics(
 
     if let Ok(val) = &fut {
         if val.response().status().is_success() {
+            let path = recorded_path(val, path);
             let hashed_ip = sha256_hash(ip.as_bytes());
             let current_time = SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("time went backwards")
                 .as_millis() as i64;
             conn.new_request(AnalyticsRequest {
+                site,
                 hashed_ip,
                 path,
                 created_at_milis: current_time,
@@ -68,6 +78,15 @@ pub async fn simple_analytics(
     fut
 }
 
+/// the path a request for `path` is recorded under. pages are recorded under
+/// one path however they were requested
+pub(crate) fn recorded_path<B>(res: &ServiceResponse<B>, path: String) -> String {
+    match res.request().extensions().get::<CanonicalPath>() {
+        Some(CanonicalPath(x)) => x.clone(),
+        None => path,
+    }
+}
+
 /// generates an sha256 digest of the provided buffer encoded in base64
 pub fn sha256_hash(body: &[u8]) -> String {
     let mut hasher = Sha256::new();
@@ -1,16 +1,29 @@
 use actix_web::{
-    error::ErrorNotFound,
+    body::MessageBody,
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    error::{ErrorBadRequest, ErrorConflict, ErrorNotFound},
     get,
+    http::header::LOCATION,
+    middleware::{from_fn, Compress},
+    post,
     web::{self, Data},
-    HttpResponse, Result,
+    Error, HttpResponse, Result, Scope,
 };
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use tera::{Context, Tera};
 
-use crate::
-    db::{conn::Conn, pg::PgConn}
-;
+use crate::{
+    api::get_api_routes,
+    auth::{login, login_page, logout, require_login},
+    config::{Config, SiteConfig},
+    db::{
+        conn::{Conn, Path},
+        pg::PgConn,
+    },
+    deploy::{current_time_milis, rollback_site, DeployTrigger},
+    range::{GraphRange, Interval, Preset, RangeInfo},
+};
 
 lazy_static! {
     pub static ref TEMPLATES: Tera = {
@@ -27,78 +40,128 @@ lazy_static! {
     };
 }
 
-use std::{
-    ops::Rem,
-    time::{Duration, SystemTime},
-};
+use std::ops::Rem;
 
-trait DurationExt {
-    fn mins(mins: u64) -> Duration;
-    fn days(days: u64) -> Duration;
+#[derive(Deserialize, Debug)]
+struct SiteInfo {
+    site: Option<String>,
 }
 
-impl DurationExt for Duration {
-    fn mins(mins: u64) -> Duration {
-        Duration::from_secs(mins * 60)
+/// the site picked in the dashboard, defaulting to the first configured site
+fn selected_site<'a>(config: &'a Config, site: &Option<String>) -> Result<&'a SiteConfig> {
+    match site {
+        Some(domain) => config
+            .get_site(domain)
+            .ok_or_else(|| ErrorNotFound(format!("site {} not found", domain))),
+        None => Ok(&config.sites[0]),
     }
+}
+
+/// the range picker, and the query string that keeps the range in links
+fn insert_range(context: &mut Context, range: &GraphRange) {
+    let presets: Vec<_> = Preset::ALL.iter().map(|x| (x.key(), x.label())).collect();
+    let intervals: Vec<_> = Interval::ALL.iter().map(|x| x.key()).collect();
+    context.insert("presets", &presets);
+    context.insert("intervals", &intervals);
+    context.insert("preset", &range.preset.map(|x| x.key()));
+    context.insert("interval", range.interval.key());
+    context.insert("from", &range.first_day());
+    context.insert("to", &range.last_day());
+    context.insert("range_query", &range.query());
+    context.insert("range_only_query", &range.range_query());
+    context.insert("compare", &range.compare);
+    context.insert("compare_toggle_query", &range.query_with(!range.compare));
+}
 
-    fn days(days: u64) -> Duration {
-        Duration::from_secs(days * 60 * 60 * 24)
+/// how far `current` is up or down on `previous`, eg `+12.5%`. nothing
+/// before means there's nothing to compare with
+fn percent_change(current: i64, previous: i64) -> Option<String> {
+    match previous {
+        0 => None,
+        _ => Some(format!(
+            "{:+.1}%",
+            (current - previous) as f64 * 100.0 / previous as f64
+        )),
     }
 }
 
+/// the graphs of a path, or the whole site without `pid`, along with the
+/// period before when comparing
+async fn insert_graphs(
+    context: &mut Context,
+    conn: &PgConn,
+    site: &SiteConfig,
+    pid: Option<i64>,
+    range: &GraphRange,
+    now: i64,
+) -> Result<()> {
+    let previous_bounds = match range.compare {
+        true => Some(
+            range
+                .previous_bounds()
+                .ok_or_else(|| ErrorBadRequest("there's no period before that range"))?,
+        ),
+        false => None,
+    };
+    let comparison = conn
+        .get_period_comparison(
+            &site.domain,
+            pid,
+            range.bounds(),
+            previous_bounds.as_deref(),
+            range.elapsed(now),
+        )
+        .await;
+    let (current, previous) = (&comparison.current, &comparison.previous);
+    let previous_title = previous_bounds.map(|x| range.previous_title(&x, now));
+    let (total_change, unique_change) = match previous {
+        Some(previous) => (
+            percent_change(current.total_requests, previous.total_requests),
+            percent_change(current.unique_visitors, previous.unique_visitors),
+        ),
+        None => (None, None),
+    };
+    context.insert("previous_title", &previous_title);
+    context.insert("total_change", &total_change);
+    context.insert("unique_change", &unique_change);
+    context.insert("graph_title", &range.title());
+    context.insert("current", current);
+    context.insert("previous", previous);
+    Ok(())
+}
+
+/// the site selector shown on every dashboard page
+fn insert_sites(context: &mut Context, config: &Config, site: &SiteConfig) {
+    let sites: Vec<&str> = config.sites.iter().map(|x| x.domain.as_str()).collect();
+    context.insert("sites", &sites);
+    context.insert("site", &site.domain);
+    context.insert("logout", &!config.public_dashboard);
+}
+
 #[get("/path/{other_url:.*}")]
 async fn path_view(
     other_url: web::Path<String>,
-    // state: Data<Config>,
+    info: web::Query<SiteInfo>,
+    range: web::Query<RangeInfo>,
+    state: Data<Config>,
     conn: Data<PgConn>,
 ) -> Result<HttpResponse> {
-    let path = format!("/{}", other_url.to_string());
-    let pid = match conn.get_pid(&path).await {
+    let site = selected_site(&state, &info.site)?;
+    let path = format!("/{}", other_url);
+    let pid = match conn.get_pid(&site.domain, &path).await {
         Some(pid) => pid,
         None => return Err(ErrorNotFound(format!("{} not found", path))),
     };
-
-    const LIMIT: usize = 30;
-
-    use std::time::Duration;
-
-    let time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as i64;
-
-    let duration = Duration::mins(30).as_millis() as i64;
-    let half_hourly_total = conn
-        .get_graph_total(pid, "Half Hourly".to_string(), duration, LIMIT, time)
-        .await;
-    let half_hourly_unique = conn
-        .get_graph_unique(pid, "Half Hourly".to_string(), duration, LIMIT, time)
-        .await;
-
-    let duration = Duration::days(1).as_millis() as i64;
-    let daily_total = conn
-        .get_graph_total(pid, "Daily".to_string(), duration, LIMIT, time)
-        .await;
-    let daily_unique = conn
-        .get_graph_unique(pid, "Daily".to_string(), duration, LIMIT, time)
-        .await;
-
-    let duration = Duration::days(30).as_millis() as i64;
-    let monthly_total = conn
-        .get_graph_total(pid, "Monthly (30 days)".to_string(), duration, LIMIT, time)
-        .await;
-    let monthly_unique = conn
-        .get_graph_unique(pid, "Monthly (30 days)".to_string(), duration, LIMIT, time)
-        .await;
-    let totals = vec![half_hourly_total, daily_total, monthly_total];
-    let uniques = vec![half_hourly_unique, daily_unique, monthly_unique];
+    let now = current_time_milis();
+    let range = GraphRange::from_info(&range, now, state.timezone).map_err(ErrorBadRequest)?;
     let path = conn.get_path(pid).await;
 
     let mut context = Context::new();
-    context.insert("graphs_total", &totals);
-    context.insert("graphs_unique", &uniques);
+    insert_graphs(&mut context, &conn, site, Some(pid), &range, now).await?;
     context.insert("path", &path);
+    context.insert("range_url", &format!("/analytics/path{}", path.path));
+    insert_range(&mut context, &range);
+    insert_sites(&mut context, &state, site);
 
     let val = TEMPLATES
         .render("path.html", &context)
@@ -107,49 +170,213 @@ async fn path_view(
     Ok(HttpResponse::Ok().body(val))
 }
 
+/// how a list of paths is sorted, shared with the api
 #[derive(Deserialize, Serialize, Debug, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
-enum Ordering {
+pub(crate) enum Ordering {
     Alphabetical,
     Unique,
 }
 
+impl Ordering {
+    /// a page of the site's paths in this order
+    pub(crate) async fn get_paths(
+        self,
+        conn: &PgConn,
+        site: &str,
+        limit: i64,
+        page: i64,
+    ) -> Vec<Path> {
+        match self {
+            Ordering::Alphabetical => conn.get_paths_alphabetic(site, limit, page).await,
+            Ordering::Unique => conn.get_paths_unique_visitors_dec(site, limit, page).await,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct Info {
     page: Option<u64>,
     order_by: Option<Ordering>,
+    site: Option<String>,
 }
+
+/// the most visited pages shown on the overview
+const TOP_PATHS: i64 = 10;
+
 #[get("")]
-async fn index(info: web::Query<Info>, conn: Data<PgConn>) -> Result<HttpResponse> {
+async fn overview(
+    info: web::Query<SiteInfo>,
+    range: web::Query<RangeInfo>,
+    state: Data<Config>,
+    conn: Data<PgConn>,
+) -> Result<HttpResponse> {
+    let site = selected_site(&state, &info.site)?;
+    let now = current_time_milis();
+    let range = GraphRange::from_info(&range, now, state.timezone).map_err(ErrorBadRequest)?;
+    let top_paths = conn
+        .get_top_paths(&site.domain, range.from, range.to, TOP_PATHS)
+        .await;
+
+    let mut context = Context::new();
+    insert_graphs(&mut context, &conn, site, None, &range, now).await?;
+    context.insert("top_paths", &top_paths);
+    context.insert("range_url", "/analytics");
+    insert_range(&mut context, &range);
+    insert_sites(&mut context, &state, site);
+
+    let val = TEMPLATES
+        .render("overview.html", &context)
+        .expect("tera rendering error");
+
+    Ok(HttpResponse::Ok().body(val))
+}
+
+#[get("/pages")]
+async fn pages(
+    info: web::Query<Info>,
+    range: web::Query<RangeInfo>,
+    state: Data<Config>,
+    conn: Data<PgConn>,
+) -> Result<HttpResponse> {
     const LIMIT: i64 = 20;
+    let site = selected_site(&state, &info.site)?;
     let ordering = info.order_by.unwrap_or(Ordering::Alphabetical);
     let page: i64 = info.page.unwrap_or(0).try_into().unwrap_or(0);
-    let total_pages = conn.get_total_paths().await;
+    let total_pages = conn.get_total_paths(&site.domain).await;
     let total_pages = match total_pages.rem(&LIMIT) != 0 {
         true => (total_pages / LIMIT) + 1,
         false => total_pages / LIMIT,
     };
 
-    let routes = match ordering {
-        Ordering::Alphabetical => conn.get_paths_alphabetic(LIMIT, page).await,
-        Ordering::Unique => conn.get_paths_unique_visitors_dec(LIMIT, page).await,
-    };
+    let routes = ordering.get_paths(&conn, &site.domain, LIMIT, page).await;
 
     let mut context = Context::new();
     context.insert("routes", &routes);
     context.insert("page", &page);
     context.insert("total_pages", &total_pages);
     context.insert("ordering", &ordering);
+    // a range picked elsewhere carries over to the paths
+    let range_query = match range.range.is_some() || range.from.is_some() {
+        true => GraphRange::from_info(&range, current_time_milis(), state.timezone)
+            .map(|x| x.query())
+            .unwrap_or_default(),
+        false => String::new(),
+    };
+    context.insert("range_query", &range_query);
+    insert_sites(&mut context, &state, site);
+
+    let val = TEMPLATES
+        .render("pages.html", &context)
+        .expect("tera rendering error");
+
+    Ok(HttpResponse::Ok().body(val))
+}
+
+#[derive(Deserialize, Debug)]
+struct DeploysInfo {
+    page: Option<u64>,
+    site: Option<String>,
+}
+#[get("/deploys")]
+async fn deploys(
+    info: web::Query<DeploysInfo>,
+    state: Data<Config>,
+    conn: Data<PgConn>,
+) -> Result<HttpResponse> {
+    const LIMIT: i64 = 20;
+    let site = selected_site(&state, &info.site)?;
+    let page: i64 = info.page.unwrap_or(0).try_into().unwrap_or(0);
+    let deploys = conn.get_deploys(&site.domain, LIMIT, page).await;
+
+    let mut context = Context::new();
+    context.insert("deploys", &deploys);
+    context.insert("page", &page);
+    context.insert("has_next", &(deploys.len() as i64 == LIMIT));
+    insert_sites(&mut context, &state, site);
+
+    let val = TEMPLATES
+        .render("deploys.html", &context)
+        .expect("tera rendering error");
+
+    Ok(HttpResponse::Ok().body(val))
+}
+
+/// puts the site back on the version it served before its last deploy
+#[post("/deploys/rollback")]
+async fn rollback(info: web::Query<SiteInfo>, state: Data<Config>) -> Result<HttpResponse> {
+    let site = selected_site(&state, &info.site)?;
+    rollback_site(site, DeployTrigger::Manual)
+        .await
+        .map_err(ErrorConflict)?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, format!("/analytics/deploys?site={}", site.domain)))
+        .finish())
+}
+
+#[get("/deploys/{did}")]
+async fn deploy_view(
+    did: web::Path<i64>,
+    state: Data<Config>,
+    conn: Data<PgConn>,
+) -> Result<HttpResponse> {
+    let did = did.into_inner();
+    let Some(deploy) = conn.get_deploy(did).await else {
+        return Err(ErrorNotFound(format!("deploy {} not found", did)));
+    };
+    let site = state
+        .deploy_site(&deploy.site)
+        .ok_or_else(|| ErrorNotFound(format!("site {} not found", deploy.site)))?;
+
+    let mut context = Context::new();
+    context.insert("deploy", &deploy);
+    insert_sites(&mut context, &state, site);
 
     let val = TEMPLATES
-        .render("index.html", &context)
+        .render("deploy.html", &context)
         .expect("tera rendering error");
 
     Ok(HttpResponse::Ok().body(val))
 }
 
-pub fn get_routes() -> actix_web::Scope {
+/// the dashboard is rendered on every request so it gets compressed on the
+/// fly, unlike the sites which are precompressed on deploy
+pub fn get_routes() -> Scope<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
     actix_web::web::scope("/analytics")
+        .wrap(from_fn(require_login))
+        .wrap(Compress::default())
+        .service(login_page)
+        .service(login)
+        .service(logout)
+        .service(get_api_routes())
         .service(path_view)
-        .service(index)
+        .service(deploys)
+        .service(rollback)
+        .service(deploy_view)
+        .service(pages)
+        .service(overview)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_changes_need_something_to_compare_with() {
+        assert_eq!(percent_change(15, 10), Some("+50.0%".to_string()));
+        assert_eq!(percent_change(5, 10), Some("-50.0%".to_string()));
+        assert_eq!(percent_change(10, 10), Some("+0.0%".to_string()));
+        assert_eq!(percent_change(1, 3), Some("-66.7%".to_string()));
+        assert_eq!(percent_change(0, 4), Some("-100.0%".to_string()));
+        assert_eq!(percent_change(5, 0), None);
+        assert_eq!(percent_change(0, 0), None);
+    }
 }
@@ -0,0 +1,658 @@
+use actix_web::{
+    get,
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web::{self, Data},
+    HttpResponse, Scope,
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    analytics_routes::Ordering,
+    config::{Config, SiteConfig},
+    db::{
+        conn::{Conn, Path},
+        pg::PgConn,
+    },
+    deploy::current_time_milis,
+    export::{export, parse_time, visitor_salt, ExportFormat, ExportKind},
+};
+
+const DEFAULT_PER_PAGE: i64 = 20;
+const MAX_PER_PAGE: i64 = 200;
+/// the buckets are counted in one query, this just keeps responses a
+/// sensible size
+const MAX_LIMIT: usize = 500;
+const DEFAULT_LIMIT: usize = 30;
+const DEFAULT_DURATION: i64 = 24 * 60 * 60 * 1000;
+
+fn api_error(res: &mut actix_web::HttpResponseBuilder, message: &str) -> HttpResponse {
+    res.json(json!({ "error": message }))
+}
+
+/// like `selected_site` in the dashboard but with a json error
+fn api_site<'a>(config: &'a Config, site: &Option<String>) -> Result<&'a SiteConfig, HttpResponse> {
+    match site {
+        Some(domain) => config.get_site(domain).ok_or_else(|| {
+            api_error(
+                &mut HttpResponse::NotFound(),
+                &format!("site {} not found", domain),
+            )
+        }),
+        None => Ok(&config.sites[0]),
+    }
+}
+
+#[get("/sites")]
+async fn sites(state: Data<Config>) -> HttpResponse {
+    let sites: Vec<&str> = state.sites.iter().map(|x| x.domain.as_str()).collect();
+    HttpResponse::Ok().json(json!({ "sites": sites }))
+}
+
+#[derive(Deserialize, Debug)]
+struct PathsInfo {
+    site: Option<String>,
+    page: Option<u32>,
+    per_page: Option<u32>,
+    order_by: Option<Ordering>,
+}
+
+#[derive(Serialize, Debug)]
+struct PathsPage {
+    site: String,
+    page: i64,
+    per_page: i64,
+    total_paths: i64,
+    total_pages: i64,
+    order_by: Ordering,
+    paths: Vec<Path>,
+}
+
+#[get("/paths")]
+async fn paths(
+    info: web::Query<PathsInfo>,
+    state: Data<Config>,
+    conn: Data<PgConn>,
+) -> HttpResponse {
+    let site = match api_site(&state, &info.site) {
+        Ok(x) => x,
+        Err(res) => return res,
+    };
+    let per_page = match info.per_page {
+        Some(x @ 1..) => (x as i64).min(MAX_PER_PAGE),
+        Some(_) => return api_error(&mut HttpResponse::BadRequest(), "per_page must be positive"),
+        None => DEFAULT_PER_PAGE,
+    };
+    let page = info.page.unwrap_or(0) as i64;
+    let order_by = info.order_by.unwrap_or(Ordering::Alphabetical);
+    let total_paths = conn.get_total_paths(&site.domain).await;
+    let paths = order_by
+        .get_paths(&conn, &site.domain, per_page, page)
+        .await;
+    HttpResponse::Ok().json(PathsPage {
+        site: site.domain.clone(),
+        page,
+        per_page,
+        total_paths,
+        total_pages: (total_paths + per_page - 1) / per_page,
+        order_by,
+        paths,
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct PathInfo {
+    site: Option<String>,
+    path: String,
+}
+
+#[get("/path")]
+async fn path(info: web::Query<PathInfo>, state: Data<Config>, conn: Data<PgConn>) -> HttpResponse {
+    let site = match api_site(&state, &info.site) {
+        Ok(x) => x,
+        Err(res) => return res,
+    };
+    match conn.get_pid(&site.domain, &info.path).await {
+        Some(pid) => HttpResponse::Ok().json(conn.get_path(pid).await),
+        None => api_error(
+            &mut HttpResponse::NotFound(),
+            &format!("{} not found", info.path),
+        ),
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Metric {
+    Total,
+    Unique,
+}
+
+#[derive(Deserialize, Debug)]
+struct GraphInfo {
+    site: Option<String>,
+    path: String,
+    metric: Option<Metric>,
+    /// length of each bucket in milliseconds
+    duration: Option<i64>,
+    /// number of buckets
+    limit: Option<usize>,
+    /// end of the most recent bucket in milliseconds since the epoch, defaults to now
+    end: Option<i64>,
+}
+
+#[get("/graph")]
+async fn graph(
+    info: web::Query<GraphInfo>,
+    state: Data<Config>,
+    conn: Data<PgConn>,
+) -> HttpResponse {
+    let site = match api_site(&state, &info.site) {
+        Ok(x) => x,
+        Err(res) => return res,
+    };
+    let duration = info.duration.unwrap_or(DEFAULT_DURATION);
+    if duration <= 0 {
+        return api_error(&mut HttpResponse::BadRequest(), "duration must be positive");
+    }
+    let limit = info.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return api_error(
+            &mut HttpResponse::BadRequest(),
+            &format!("limit must be between 1 and {}", MAX_LIMIT),
+        );
+    }
+    let end = info.end.unwrap_or_else(current_time_milis);
+    if duration
+        .checked_mul(limit as i64)
+        .and_then(|x| end.checked_sub(x))
+        .is_none()
+    {
+        return api_error(&mut HttpResponse::BadRequest(), "graph range out of bounds");
+    }
+    let bounds: Vec<i64> = (0..=limit as i64)
+        .map(|i| end - duration * (limit as i64 - i))
+        .collect();
+    let Some(pid) = conn.get_pid(&site.domain, &info.path).await else {
+        return api_error(
+            &mut HttpResponse::NotFound(),
+            &format!("{} not found", info.path),
+        );
+    };
+    let metric = info.metric.unwrap_or(Metric::Total);
+    let graph = match metric {
+        Metric::Total => {
+            conn.get_graph_total(pid, "total".to_string(), &bounds)
+                .await
+        }
+        Metric::Unique => {
+            conn.get_graph_unique(pid, "unique".to_string(), &bounds)
+                .await
+        }
+    };
+    HttpResponse::Ok().json(graph)
+}
+
+#[derive(Deserialize, Debug)]
+struct ExportInfo {
+    site: Option<String>,
+    /// milliseconds or `YYYY-MM-DD`, defaults to the beginning
+    from: Option<String>,
+    /// milliseconds or `YYYY-MM-DD` inclusive, defaults to now
+    to: Option<String>,
+    format: Option<ExportFormat>,
+}
+
+#[get("/export/{kind}")]
+async fn export_view(
+    kind: web::Path<ExportKind>,
+    info: web::Query<ExportInfo>,
+    state: Data<Config>,
+    conn: Data<PgConn>,
+) -> HttpResponse {
+    let site = match api_site(&state, &info.site) {
+        Ok(x) => x,
+        Err(res) => return res,
+    };
+    let from = match info
+        .from
+        .as_deref()
+        .map(|x| parse_time(x, false, state.timezone))
+    {
+        Some(Err(e)) => return api_error(&mut HttpResponse::BadRequest(), &e),
+        Some(Ok(x)) => x,
+        None => 0,
+    };
+    let to = match info
+        .to
+        .as_deref()
+        .map(|x| parse_time(x, true, state.timezone))
+    {
+        Some(Err(e)) => return api_error(&mut HttpResponse::BadRequest(), &e),
+        Some(Ok(x)) => x,
+        None => current_time_milis() + 1,
+    };
+    let format = info.format.unwrap_or_default();
+    let kind = kind.into_inner();
+    let body = export(
+        conn.get_ref(),
+        &site.domain,
+        kind,
+        format,
+        from,
+        to,
+        state.timezone,
+        visitor_salt(&state),
+    )
+    .await;
+    let filename = format!(
+        "{}-{}.{}",
+        site.domain,
+        match kind {
+            ExportKind::Requests => "requests",
+            ExportKind::Daily => "daily",
+        },
+        format.extension()
+    );
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(filename)],
+        })
+        .streaming(body.map(Ok::<_, actix_web::Error>))
+}
+
+#[get("/openapi.json")]
+async fn openapi() -> HttpResponse {
+    HttpResponse::Ok().json(openapi_document())
+}
+
+/// the api is small enough that the spec is kept by hand, keep it in sync
+/// with the handlers above
+fn openapi_document() -> serde_json::Value {
+    let site_param = json!({
+        "name": "site", "in": "query", "required": false,
+        "description": "domain of the site, defaults to the first configured site",
+        "schema": { "type": "string" }
+    });
+    let path_param = json!({
+        "name": "path", "in": "query", "required": true,
+        "description": "the path as recorded, eg /about",
+        "schema": { "type": "string" }
+    });
+    let error = json!({
+        "description": "error",
+        "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Error" } } }
+    });
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "ivyhost analytics",
+            "version": "1",
+            "description": "authenticate with an `Authorization: Bearer <token>` header using one of `api_tokens`, or a dashboard session"
+        },
+        "servers": [{ "url": "/analytics/api/v1" }],
+        "components": {
+            "securitySchemes": { "token": { "type": "http", "scheme": "bearer" } },
+            "schemas": {
+                "Error": {
+                    "type": "object",
+                    "properties": { "error": { "type": "string" } }
+                },
+                "Path": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string" },
+                        "total_unique": { "type": "integer", "format": "int64" },
+                        "total_requests": { "type": "integer", "format": "int64" }
+                    }
+                },
+                "Graphnode": {
+                    "type": "object",
+                    "properties": {
+                        "amount": { "type": "integer" },
+                        "timestamp_start": { "type": "integer", "format": "int64" },
+                        "timestamp_end": { "type": "integer", "format": "int64" }
+                    }
+                },
+                "GraphView": {
+                    "type": "object",
+                    "properties": {
+                        "title": { "type": "string" },
+                        "timeline": { "type": "array", "items": { "$ref": "#/components/schemas/Graphnode" } }
+                    }
+                },
+                "DailyPathCount": {
+                    "type": "object",
+                    "properties": {
+                        "site": { "type": "string" },
+                        "path": { "type": "string" },
+                        "day": { "type": "string", "format": "date" },
+                        "total_requests": { "type": "integer" },
+                        "unique_visitors": { "type": "integer" }
+                    }
+                },
+                "ExportedRequest": {
+                    "type": "object",
+                    "properties": {
+                        "site": { "type": "string" },
+                        "path": { "type": "string" },
+                        "visitor": { "type": "string", "description": "salted hash, see `export_salt`" },
+                        "timestamp": { "type": "integer", "format": "int64" },
+                        "time": { "type": "string", "format": "date-time" }
+                    }
+                },
+                "PathsPage": {
+                    "type": "object",
+                    "properties": {
+                        "site": { "type": "string" },
+                        "page": { "type": "integer" },
+                        "per_page": { "type": "integer" },
+                        "total_paths": { "type": "integer" },
+                        "total_pages": { "type": "integer" },
+                        "order_by": { "type": "string", "enum": ["alphabetical", "unique"] },
+                        "paths": { "type": "array", "items": { "$ref": "#/components/schemas/Path" } }
+                    }
+                }
+            }
+        },
+        "security": [{ "token": [] }],
+        "paths": {
+            "/sites": {
+                "get": {
+                    "summary": "configured sites",
+                    "responses": { "200": {
+                        "description": "site domains",
+                        "content": { "application/json": { "schema": {
+                            "type": "object",
+                            "properties": { "sites": { "type": "array", "items": { "type": "string" } } }
+                        } } }
+                    } }
+                }
+            },
+            "/paths": {
+                "get": {
+                    "summary": "paths with their request and visitor counts",
+                    "parameters": [
+                        site_param,
+                        { "name": "page", "in": "query", "schema": { "type": "integer", "minimum": 0, "default": 0 } },
+                        { "name": "per_page", "in": "query", "schema": { "type": "integer", "minimum": 1, "maximum": MAX_PER_PAGE, "default": DEFAULT_PER_PAGE } },
+                        { "name": "order_by", "in": "query", "schema": { "type": "string", "enum": ["alphabetical", "unique"], "default": "alphabetical" } }
+                    ],
+                    "responses": {
+                        "200": { "description": "a page of paths", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/PathsPage" } } } },
+                        "400": error, "404": error
+                    }
+                }
+            },
+            "/path": {
+                "get": {
+                    "summary": "counts for a single path",
+                    "parameters": [site_param, path_param],
+                    "responses": {
+                        "200": { "description": "the path", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Path" } } } },
+                        "404": error
+                    }
+                }
+            },
+            "/graph": {
+                "get": {
+                    "summary": "a timeline of requests or unique visitors for a path",
+                    "parameters": [
+                        site_param,
+                        path_param,
+                        { "name": "metric", "in": "query", "schema": { "type": "string", "enum": ["total", "unique"], "default": "total" } },
+                        { "name": "duration", "in": "query", "description": "length of each bucket in milliseconds", "schema": { "type": "integer", "format": "int64", "minimum": 1, "default": DEFAULT_DURATION } },
+                        { "name": "limit", "in": "query", "description": "number of buckets", "schema": { "type": "integer", "minimum": 1, "maximum": MAX_LIMIT, "default": DEFAULT_LIMIT } },
+                        { "name": "end", "in": "query", "description": "end of the last bucket in milliseconds since the epoch, defaults to now", "schema": { "type": "integer", "format": "int64" } }
+                    ],
+                    "responses": {
+                        "200": { "description": "buckets oldest first", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/GraphView" } } } },
+                        "400": error, "404": error
+                    }
+                }
+            },
+            "/export/{kind}": {
+                "get": {
+                    "summary": "every request, or per path counts for each day in the reporting timezone, streamed as csv or ndjson",
+                    "parameters": [
+                        { "name": "kind", "in": "path", "required": true, "schema": { "type": "string", "enum": ["requests", "daily"] } },
+                        site_param,
+                        { "name": "from", "in": "query", "description": "YYYY-MM-DD or milliseconds since the epoch, defaults to the beginning", "schema": { "type": "string" } },
+                        { "name": "to", "in": "query", "description": "YYYY-MM-DD inclusive or milliseconds exclusive, defaults to now", "schema": { "type": "string" } },
+                        { "name": "format", "in": "query", "schema": { "type": "string", "enum": ["csv", "ndjson"], "default": "csv" } }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "rows shaped like ExportedRequest or DailyPathCount, oldest first",
+                            "content": {
+                                "text/csv": { "schema": { "type": "string" } },
+                                "application/x-ndjson": { "schema": { "oneOf": [
+                                    { "$ref": "#/components/schemas/ExportedRequest" },
+                                    { "$ref": "#/components/schemas/DailyPathCount" }
+                                ] } }
+                            }
+                        },
+                        "400": error, "404": error
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// mounted inside the dashboard scope so it shares its login
+pub fn get_api_routes() -> Scope {
+    let query_config = web::QueryConfig::default().error_handler(|e, _| {
+        let res = api_error(&mut HttpResponse::BadRequest(), &e.to_string());
+        actix_web::error::InternalError::from_response(e, res).into()
+    });
+    web::scope("/api/v1")
+        .app_data(query_config)
+        .service(sites)
+        .service(paths)
+        .service(path)
+        .service(graph)
+        .service(export_view)
+        .service(openapi)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header::AUTHORIZATION, StatusCode},
+        test::{call_service, init_service, read_body_json, TestRequest},
+        App,
+    };
+
+    use super::*;
+    use crate::{
+        analytics::AnalyticsRequest,
+        analytics_routes::get_routes,
+        test_util::{test_config, TestDb},
+    };
+
+    const TOKEN: &str = "a-long-random-string";
+
+    fn config() -> Config {
+        let mut config = test_config(serde_json::json!([
+            {
+                "domain": "ivytime.gay",
+                "site_repo": "https://example.com/site.git",
+                "branch": "main",
+            },
+            {
+                "domain": "other.test",
+                "site_repo": "https://example.com/other.git",
+                "branch": "main",
+            },
+        ]));
+        config.api_tokens = vec![TOKEN.to_string()];
+        config
+    }
+
+    /// the api as mounted in the dashboard, with `conn` for the endpoints that
+    /// read analytics. the rest never touch it
+    macro_rules! api {
+        ($conn:expr) => {
+            init_service(
+                App::new()
+                    .app_data(Data::new(config()))
+                    .app_data(Data::new($conn))
+                    .service(get_routes()),
+            )
+            .await
+        };
+    }
+
+    fn get(uri: &str) -> TestRequest {
+        TestRequest::get()
+            .uri(&format!("/analytics/api/v1{}", uri))
+            .insert_header((AUTHORIZATION, format!("Bearer {}", TOKEN)))
+    }
+
+    #[actix_web::test]
+    async fn needs_a_token() {
+        let app = api!(config().create_conn());
+        let res = call_service(
+            &app,
+            TestRequest::get()
+                .uri("/analytics/api/v1/sites")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = call_service(&app, get("/sites").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(body, json!({ "sites": ["ivytime.gay", "other.test"] }));
+    }
+
+    #[actix_web::test]
+    async fn rejects_bad_parameters_with_json_errors() {
+        let app = api!(config().create_conn());
+        let cases = [
+            (
+                "/paths?site=nowhere.test",
+                StatusCode::NOT_FOUND,
+                "site nowhere.test not found",
+            ),
+            (
+                "/paths?per_page=0",
+                StatusCode::BAD_REQUEST,
+                "per_page must be positive",
+            ),
+            (
+                "/graph?path=/&duration=0",
+                StatusCode::BAD_REQUEST,
+                "duration must be positive",
+            ),
+            (
+                "/graph?path=/&limit=501",
+                StatusCode::BAD_REQUEST,
+                "limit must be between 1 and 500",
+            ),
+            (
+                "/graph?path=/&duration=9223372036854775807&limit=2",
+                StatusCode::BAD_REQUEST,
+                "graph range out of bounds",
+            ),
+            (
+                "/export/requests?from=yesterday",
+                StatusCode::BAD_REQUEST,
+                "",
+            ),
+            ("/paths?order_by=random", StatusCode::BAD_REQUEST, ""),
+        ];
+        for (uri, status, error) in cases {
+            let res = call_service(&app, get(uri).to_request()).await;
+            assert_eq!(res.status(), status, "{}", uri);
+            let body: serde_json::Value = read_body_json(res).await;
+            let message = body["error"].as_str().expect("has an error message");
+            assert!(message.contains(error), "{}: {}", uri, message);
+        }
+    }
+
+    #[actix_web::test]
+    async fn documents_every_endpoint() {
+        let app = api!(config().create_conn());
+        let res = call_service(&app, get("/openapi.json").to_request()).await;
+        let body: serde_json::Value = read_body_json(res).await;
+        let documented = body["paths"].as_object().expect("has paths");
+        for endpoint in ["/sites", "/paths", "/path", "/graph", "/export/{kind}"] {
+            assert!(
+                documented.contains_key(endpoint),
+                "{} isn't documented",
+                endpoint
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn reports_paths_and_graphs() {
+        let Some(db) = TestDb::new("api").await else {
+            return;
+        };
+        let hour = 60 * 60 * 1000;
+        let end = 100 * hour;
+        for (page, visitor, at) in [
+            ("/", "a", end - 3 * hour + 1),
+            ("/", "a", end - hour + 1),
+            ("/", "b", end - 1),
+            ("/about", "a", end - 1),
+        ] {
+            db.conn
+                .new_request(AnalyticsRequest {
+                    site: "ivytime.gay".to_string(),
+                    hashed_ip: visitor.to_string(),
+                    path: page.to_string(),
+                    created_at_milis: at,
+                })
+                .await;
+        }
+        let app = api!(db.conn.clone());
+
+        let res = call_service(&app, get("/paths?order_by=unique&per_page=1").to_request()).await;
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(
+            body,
+            json!({
+                "site": "ivytime.gay",
+                "page": 0,
+                "per_page": 1,
+                "total_paths": 2,
+                "total_pages": 2,
+                "order_by": "unique",
+                "paths": [{ "path": "/", "total_unique": 2, "total_requests": 3 }],
+            })
+        );
+        let res = call_service(&app, get("/path?path=/about").to_request()).await;
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(
+            body,
+            json!({ "path": "/about", "total_unique": 1, "total_requests": 1 })
+        );
+        let res = call_service(&app, get("/path?path=/missing").to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        for (metric, amounts) in [("total", [1, 0, 2]), ("unique", [1, 0, 2])] {
+            let uri = format!(
+                "/graph?path=/&metric={}&duration={}&limit=3&end={}",
+                metric, hour, end
+            );
+            let res = call_service(&app, get(&uri).to_request()).await;
+            let body: serde_json::Value = read_body_json(res).await;
+            assert_eq!(body["title"], metric);
+            let timeline = body["timeline"].as_array().unwrap();
+            let found: Vec<_> = timeline
+                .iter()
+                .map(|x| x["amount"].as_i64().unwrap())
+                .collect();
+            assert_eq!(found, amounts, "{}", metric);
+            assert_eq!(timeline[0]["timestamp_start"], end - 3 * hour);
+            assert_eq!(timeline[2]["timestamp_end"], end);
+        }
+    }
+}
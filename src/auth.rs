@@ -0,0 +1,437 @@
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    cookie::{time::Duration as CookieDuration, Cookie, SameSite},
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    http::header::{AUTHORIZATION, LOCATION, WWW_AUTHENTICATE},
+    middleware::Next,
+    post,
+    web::{self, Data},
+    Error, HttpRequest, HttpResponse,
+};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use base64::Engine;
+use rand::RngCore;
+use serde::Deserialize;
+use tera::Context;
+
+use crate::{
+    analytics::sha256_hash,
+    analytics_routes::TEMPLATES,
+    config::Config,
+    db::{conn::Conn, pg::PgConn},
+    deploy::current_time_milis,
+};
+
+pub const SESSION_COOKIE: &str = "ivyhost_session";
+const SESSION_TTL_SECS: i64 = 60 * 60 * 24 * 7;
+
+/// someone allowed into the dashboard
+#[derive(Deserialize, Debug, Clone)]
+pub struct DashboardUser {
+    pub username: String,
+    /// an argon2 hash in PHC format, see `ivyhost hash-password`
+    pub password_hash: String,
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|x| x.to_string())
+        .map_err(|e| format!("failed to hash password: {}", e))
+}
+
+fn verify_password(password_hash: &str, password: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(password_hash) else {
+        return false;
+    };
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok()
+}
+
+/// checked against when the username is unknown, so a login takes as long
+/// whether or not the user exists. made with the default argon2 parameters
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$GH9xxTXR2HAdXE8cymEdzg$3iSWlAijUT0vNr3eUrnJSOcLSazogiqB2DiyF2rLyfM";
+
+/// checks a password against the configured users, off the async runtime
+/// since argon2 is deliberately slow
+async fn check_login(config: &Config, username: &str, password: &str) -> bool {
+    let user = config
+        .dashboard_users
+        .iter()
+        .find(|x| x.username == username);
+    let password_hash = user.map_or(DUMMY_HASH, |x| &x.password_hash).to_string();
+    let password = password.to_string();
+    let verified = web::block(move || verify_password(&password_hash, &password))
+        .await
+        .unwrap_or(false);
+    verified && user.is_some()
+}
+
+/// whether the request has a valid api token or session. tokens are compared
+/// by their hashes so the comparison doesn't leak how much of one matched
+async fn is_authorized(req: &ServiceRequest, config: &Config, conn: &PgConn) -> bool {
+    if let Some(token) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+    {
+        let hashed = sha256_hash(token.trim().as_bytes());
+        return config
+            .api_tokens
+            .iter()
+            .any(|x| sha256_hash(x.as_bytes()) == hashed);
+    }
+    match req.cookie(SESSION_COOKIE) {
+        Some(cookie) => conn
+            .get_session(
+                &sha256_hash(cookie.value().as_bytes()),
+                current_time_milis(),
+            )
+            .await
+            .is_some(),
+        None => false,
+    }
+}
+
+/// guards the dashboard. browsers are sent to the login page while api
+/// clients get a 401. a public dashboard can be read by anyone, but changing
+/// anything still takes a login
+pub async fn require_login(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let config = req
+        .app_data::<Data<Config>>()
+        .expect("missing config from app data")
+        .clone();
+    let conn = req
+        .app_data::<Data<PgConn>>()
+        .expect("missing conn from app data")
+        .clone();
+    if (config.public_dashboard && req.method() == actix_web::http::Method::GET)
+        || req.path() == "/analytics/login"
+        || is_authorized(&req, &config, &conn).await
+    {
+        return next.call(req).await.map(|x| x.map_into_left_body());
+    }
+
+    let res = match req.method() == actix_web::http::Method::GET
+        && !req.headers().contains_key(AUTHORIZATION)
+        && !req.path().starts_with("/analytics/api/")
+    {
+        true => {
+            let next = req
+                .uri()
+                .path_and_query()
+                .map(|x| x.as_str())
+                .unwrap_or("/analytics");
+            let location = format!(
+                "/analytics/login?next={}",
+                percent_encoding::utf8_percent_encode(next, percent_encoding::NON_ALPHANUMERIC)
+            );
+            HttpResponse::SeeOther()
+                .insert_header((LOCATION, location))
+                .finish()
+        }
+        false => HttpResponse::Unauthorized()
+            .insert_header((WWW_AUTHENTICATE, "Bearer"))
+            .body("unauthorized"),
+    };
+    Ok(req.into_response(res).map_into_right_body())
+}
+
+#[derive(Deserialize, Debug)]
+struct LoginInfo {
+    next: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LoginForm {
+    username: String,
+    password: String,
+    next: Option<String>,
+}
+
+/// only redirect back into the dashboard, never off site
+fn login_redirect(next: &Option<String>) -> &str {
+    match next {
+        Some(x) if x.starts_with("/analytics") => x,
+        _ => "/analytics",
+    }
+}
+
+fn render_login(next: &str, error: Option<&str>) -> String {
+    let mut context = Context::new();
+    context.insert("next", next);
+    context.insert("error", &error);
+    TEMPLATES
+        .render("login.html", &context)
+        .expect("tera rendering error")
+}
+
+#[get("/login")]
+pub async fn login_page(info: web::Query<LoginInfo>) -> HttpResponse {
+    HttpResponse::Ok().body(render_login(login_redirect(&info.next), None))
+}
+
+#[post("/login")]
+pub async fn login(
+    req: HttpRequest,
+    form: web::Form<LoginForm>,
+    state: Data<Config>,
+    conn: Data<PgConn>,
+) -> HttpResponse {
+    let next = login_redirect(&form.next);
+    if !check_login(&state, &form.username, &form.password).await {
+        return HttpResponse::Unauthorized()
+            .body(render_login(next, Some("wrong username or password")));
+    }
+
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+    let token = base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(token);
+    let created_at = current_time_milis();
+    conn.new_session(
+        &sha256_hash(token.as_bytes()),
+        &form.username,
+        created_at,
+        created_at + SESSION_TTL_SECS * 1000,
+    )
+    .await;
+
+    let cookie = Cookie::build(SESSION_COOKIE, token)
+        .path("/analytics")
+        .http_only(true)
+        .secure(req.connection_info().scheme() == "https")
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::seconds(SESSION_TTL_SECS))
+        .finish();
+    HttpResponse::SeeOther()
+        .cookie(cookie)
+        .insert_header((LOCATION, next))
+        .finish()
+}
+
+#[post("/logout")]
+pub async fn logout(req: HttpRequest, conn: Data<PgConn>) -> HttpResponse {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        conn.delete_session(&sha256_hash(cookie.value().as_bytes()))
+            .await;
+    }
+    let mut cookie = Cookie::build(SESSION_COOKIE, "")
+        .path("/analytics")
+        .finish();
+    cookie.make_removal();
+    HttpResponse::SeeOther()
+        .cookie(cookie)
+        .insert_header((LOCATION, "/analytics/login"))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        cookie::Cookie,
+        http::{header::SET_COOKIE, StatusCode},
+        test::{call_service, init_service, read_body, TestRequest},
+        App,
+    };
+
+    use super::*;
+    use crate::{
+        analytics_routes::get_routes,
+        test_util::{test_config, TestDb},
+    };
+
+    fn config(public_dashboard: bool) -> Config {
+        let mut config = test_config(serde_json::json!([{
+            "domain": "ivytime.gay",
+            "site_repo": "https://example.com/site.git",
+            "branch": "main",
+        }]));
+        config.public_dashboard = public_dashboard;
+        config.api_tokens = vec!["a-long-random-string".to_string()];
+        config.dashboard_users = vec![DashboardUser {
+            username: "ivy".to_string(),
+            password_hash: hash_password("hunter2").unwrap(),
+        }];
+        config
+    }
+
+    /// the dashboard served with `config` and `conn`. only sessions need the
+    /// database, so the conn may point nowhere for everything else
+    macro_rules! dashboard {
+        ($config:expr, $conn:expr) => {
+            init_service(
+                App::new()
+                    .app_data(Data::new($config))
+                    .app_data(Data::new($conn))
+                    .service(get_routes()),
+            )
+            .await
+        };
+    }
+
+    #[actix_web::test]
+    async fn sends_browsers_to_the_login_and_api_clients_a_401() {
+        let config = config(false);
+        let conn = config.create_conn();
+        let app = dashboard!(config, conn);
+
+        let res = call_service(
+            &app,
+            TestRequest::get()
+                .uri("/analytics/deploys?page=1")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            res.headers().get(LOCATION).unwrap(),
+            "/analytics/login?next=%2Fanalytics%2Fdeploys%3Fpage%3D1"
+        );
+
+        for req in [
+            TestRequest::get().uri("/analytics/api/v1/sites"),
+            // a client that sent a token wants json, not a login page
+            TestRequest::get()
+                .uri("/analytics/deploys")
+                .insert_header((AUTHORIZATION, "Bearer wrong")),
+            TestRequest::post().uri("/analytics/deploys/rollback"),
+        ] {
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(res.headers().get(WWW_AUTHENTICATE).unwrap(), "Bearer");
+        }
+
+        let res = call_service(
+            &app,
+            TestRequest::get().uri("/analytics/login").to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn accepts_configured_bearer_tokens() {
+        let config = config(false);
+        let conn = config.create_conn();
+        let app = dashboard!(config, conn);
+        for (token, status) in [
+            ("Bearer a-long-random-string", StatusCode::OK),
+            ("Bearer  a-long-random-string ", StatusCode::OK),
+            ("Bearer a-long-random", StatusCode::UNAUTHORIZED),
+            ("a-long-random-string", StatusCode::UNAUTHORIZED),
+        ] {
+            let req = TestRequest::get()
+                .uri("/analytics/api/v1/sites")
+                .insert_header((AUTHORIZATION, token));
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), status, "{}", token);
+        }
+    }
+
+    #[actix_web::test]
+    async fn public_dashboards_are_read_only() {
+        let config = config(true);
+        let conn = config.create_conn();
+        let app = dashboard!(config, conn);
+        let res = call_service(
+            &app,
+            TestRequest::get()
+                .uri("/analytics/api/v1/sites")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = call_service(
+            &app,
+            TestRequest::post()
+                .uri("/analytics/deploys/rollback")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn rejects_wrong_passwords_and_unknown_users() {
+        let config = config(false);
+        assert!(check_login(&config, "ivy", "hunter2").await);
+        assert!(!check_login(&config, "ivy", "hunter3").await);
+        assert!(!check_login(&config, "ivy", "").await);
+        assert!(!check_login(&config, "mallory", "hunter2").await);
+        // the dummy hash is a real one, so unknown users cost a full verify
+        assert!(PasswordHash::new(DUMMY_HASH).is_ok());
+
+        let conn = config.create_conn();
+        let app = dashboard!(config, conn);
+        for (username, password) in [("ivy", "hunter3"), ("mallory", "hunter2")] {
+            let req = TestRequest::post()
+                .uri("/analytics/login")
+                .set_form([("username", username), ("password", password)]);
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert!(res.headers().get(SET_COOKIE).is_none());
+            let body = read_body(res).await;
+            assert!(String::from_utf8_lossy(&body).contains("wrong username or password"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn sessions_expire() {
+        let Some(db) = TestDb::new("sessions").await else {
+            return;
+        };
+        let config = config(false);
+        let app = dashboard!(config, db.conn.clone());
+
+        let req = TestRequest::post().uri("/analytics/login").set_form([
+            ("username", "ivy"),
+            ("password", "hunter2"),
+            ("next", "/analytics/api/v1/sites"),
+        ]);
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            res.headers().get(LOCATION).unwrap(),
+            "/analytics/api/v1/sites"
+        );
+        let cookie = Cookie::parse_encoded(
+            res.headers()
+                .get(SET_COOKIE)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string(),
+        )
+        .unwrap();
+        assert!(cookie.http_only().unwrap_or(false));
+        let with_session = |token: &str| {
+            TestRequest::get()
+                .uri("/analytics/api/v1/sites")
+                .cookie(Cookie::new(SESSION_COOKIE, token.to_string()))
+                .to_request()
+        };
+        let res = call_service(&app, with_session(cookie.value())).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // a session that ran out a second ago
+        let now = current_time_milis();
+        db.conn
+            .new_session(&sha256_hash(b"expired"), "ivy", now - 60_000, now - 1000)
+            .await;
+        let res = call_service(&app, with_session("expired")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = call_service(&app, with_session("made-up")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,164 @@
+use std::path::Path;
+
+use globset::{Glob, GlobMatcher};
+use serde::Deserialize;
+
+/// sets `Cache-Control` on the files it matches. every criteria that's set
+/// has to match, a rule without any matches everything
+#[derive(Deserialize, Debug, Clone)]
+pub struct CacheRule {
+    /// matched against the path within the site, eg `assets/**`
+    pub glob: Option<String>,
+    /// file extensions without the dot
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// only match files with a content hash in their name, eg `app.3f9a2c1b.js`
+    #[serde(default)]
+    pub fingerprinted: bool,
+    pub cache_control: String,
+}
+
+/// html has to be revalidated to pick up new deploys, while fingerprinted
+/// assets never change so they can be cached forever. html goes first since
+/// pages like `post-2f9a1c3e.html` look fingerprinted too
+pub fn default_cache_rules() -> Vec<CacheRule> {
+    vec![
+        CacheRule {
+            glob: None,
+            extensions: vec!["html".to_string(), "htm".to_string()],
+            fingerprinted: false,
+            cache_control: "no-cache".to_string(),
+        },
+        CacheRule {
+            glob: None,
+            extensions: Vec::new(),
+            fingerprinted: true,
+            cache_control: "public, max-age=31536000, immutable".to_string(),
+        },
+    ]
+}
+
+/// a site's [`CacheRule`]s with their globs compiled
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    rules: Vec<(Option<GlobMatcher>, CacheRule)>,
+}
+
+impl CachePolicy {
+    pub fn new(rules: &[CacheRule]) -> Result<CachePolicy, String> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let glob = match &rule.glob {
+                    Some(x) => Some(
+                        Glob::new(x)
+                            .map_err(|e| format!("invalid cache rule glob {}: {}", x, e))?
+                            .compile_matcher(),
+                    ),
+                    None => None,
+                };
+                Ok((glob, rule.clone()))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(CachePolicy { rules })
+    }
+
+    /// the `Cache-Control` value of the first rule matching `path`, which is
+    /// relative to the site's root
+    pub fn cache_control(&self, path: &Path) -> Option<&str> {
+        let extension = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_ascii_lowercase());
+        self.rules
+            .iter()
+            .find(|(glob, rule)| {
+                glob.as_ref().is_none_or(|x| x.is_match(path))
+                    && (rule.extensions.is_empty()
+                        || extension.as_ref().is_some_and(|x| {
+                            rule.extensions
+                                .iter()
+                                .any(|y| y.trim_start_matches('.').eq_ignore_ascii_case(x))
+                        }))
+                    && (!rule.fingerprinted || is_fingerprinted(path))
+            })
+            .map(|(_, rule)| rule.cache_control.as_str())
+    }
+}
+
+/// whether the file name has a part that looks like a content hash. that's
+/// either hex of 8 or more characters mixing letters and digits, eg
+/// `app.3f9a2c1b.js`, or the 8 character base64 style hashes of vite, rollup
+/// and esbuild, eg `index-BvK3f9aZ.js`, with at least two capitals so
+/// capitalised words don't count
+pub fn is_fingerprinted(path: &Path) -> bool {
+    let Some(stem) = path.file_stem().and_then(|x| x.to_str()) else {
+        return false;
+    };
+    stem.split(['.', '-', '_'])
+        .skip(1)
+        .any(|part| is_hex_hash(part) || is_bundler_hash(part))
+}
+
+fn is_hex_hash(part: &str) -> bool {
+    part.len() >= 8
+        && part.chars().all(|c| c.is_ascii_hexdigit())
+        && part.chars().any(|c| c.is_ascii_digit())
+        && part.chars().any(|c| c.is_ascii_alphabetic())
+}
+
+fn is_bundler_hash(part: &str) -> bool {
+    let count = |f: fn(&char) -> bool| part.chars().filter(f).count();
+    part.len() == 8
+        && part.chars().all(|c| c.is_ascii_alphanumeric())
+        && count(char::is_ascii_uppercase) >= 2
+        && count(char::is_ascii_lowercase) + count(char::is_ascii_digit) >= 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_rules_revalidate_pages_that_look_fingerprinted() {
+        let policy = CachePolicy::new(&default_cache_rules()).unwrap();
+        for page in [
+            "post-2f9a1c3e.html",
+            "install-ubuntu2404.html",
+            "index.html",
+        ] {
+            assert_eq!(policy.cache_control(Path::new(page)), Some("no-cache"));
+        }
+        assert_eq!(
+            policy.cache_control(Path::new("assets/app.3f9a2c1b.js")),
+            Some("public, max-age=31536000, immutable")
+        );
+        assert_eq!(policy.cache_control(Path::new("images/cat.png")), None);
+    }
+
+    #[test]
+    fn recognises_hashes_but_not_words_with_digits() {
+        for hashed in [
+            "app.3f9a2c1b.js",
+            "main.0a1b2c3d4e5f6a7b8c9d.css",
+            "chunk-5f3c9e21.js",
+            "index-BvK3f9aZ.js",
+            "index-DiwrgTda.js",
+            "chunk-ABCD1234.js",
+        ] {
+            assert!(is_fingerprinted(Path::new(hashed)), "{}", hashed);
+        }
+        for plain in [
+            "roboto-latin400.woff2",
+            "logo-darkmode2.png",
+            "report-q3summary.pdf",
+            "report-20240101.pdf",
+            "photo-deadbeef.jpg",
+            "team-Ivyhost2.png",
+            "app.js",
+            "3f9a2c1b.js",
+        ] {
+            assert!(!is_fingerprinted(Path::new(plain)), "{}", plain);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use actix_web::http::header::{ContentEncoding, Encoding};
+use flate2::{write::GzEncoder, Compression};
+
+/// files smaller than this aren't worth compressing
+const MIN_SIZE: u64 = 1024;
+const BROTLI_QUALITY: u32 = 11;
+const BROTLI_WINDOW: u32 = 22;
+
+/// extensions of text assets that get precompressed siblings on deploy
+const TEXT_EXTENSIONS: &[&str] = &[
+    "html",
+    "htm",
+    "css",
+    "js",
+    "mjs",
+    "json",
+    "xml",
+    "svg",
+    "txt",
+    "md",
+    "map",
+    "wasm",
+    "webmanifest",
+    "atom",
+    "rss",
+];
+
+/// the precompressed encodings we look for next to a file, in order of preference
+pub const PRECOMPRESSED: &[(ContentEncoding, &str)] = &[
+    (ContentEncoding::Brotli, "br"),
+    (ContentEncoding::Gzip, "gz"),
+];
+
+/// the path of `file` precompressed with the encoding using `extension`, eg `app.js.br`
+pub fn sibling_path(file: &Path, extension: &str) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+pub fn to_encoding(encoding: ContentEncoding) -> Encoding {
+    match encoding {
+        ContentEncoding::Brotli => Encoding::brotli(),
+        ContentEncoding::Gzip => Encoding::gzip(),
+        _ => Encoding::identity(),
+    }
+}
+
+/// writes `.br` and `.gz` siblings for every text asset under `dir`. siblings
+/// that already exist are left alone so sites can ship their own, and ones
+/// that don't end up smaller than the original are skipped
+pub fn precompress_dir(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            precompress_dir(&path)?;
+        } else if is_text_asset(&path) && entry.metadata()?.len() >= MIN_SIZE {
+            precompress_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_text_asset(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| TEXT_EXTENSIONS.contains(&x.to_ascii_lowercase().as_str()))
+}
+
+fn precompress_file(path: &Path) -> io::Result<()> {
+    let data = fs::read(path)?;
+    for (encoding, extension) in PRECOMPRESSED {
+        let target = sibling_path(path, extension);
+        if target.exists() {
+            continue;
+        }
+        let compressed = match encoding {
+            ContentEncoding::Brotli => {
+                let mut writer =
+                    brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                writer.write_all(&data)?;
+                writer.into_inner()
+            }
+            _ => {
+                let mut writer = GzEncoder::new(Vec::new(), Compression::best());
+                writer.write_all(&data)?;
+                writer.finish()?
+            }
+        };
+        if compressed.len() < data.len() {
+            fs::write(target, compressed)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::test_util::{write_file, TempDir};
+
+    #[test]
+    fn precompresses_large_text_assets_only() {
+        let dir = TempDir::new("precompress");
+        let text = "body { color: hotpink; }\n".repeat(100);
+        write_file(&dir.path().join("css/style.css"), &text);
+        write_file(&dir.path().join("small.js"), "let a = 1;");
+        write_file(&dir.path().join("photo.png"), &text);
+        write_file(&dir.path().join("own.html"), &text);
+        write_file(&dir.path().join("own.html.br"), "shipped by the site");
+        precompress_dir(dir.path()).unwrap();
+
+        let style = dir.path().join("css/style.css");
+        let mut unzipped = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(sibling_path(&style, "gz")).unwrap())
+            .read_to_string(&mut unzipped)
+            .unwrap();
+        assert_eq!(unzipped, text);
+        let mut unbrotlied = String::new();
+        brotli::Decompressor::new(fs::File::open(sibling_path(&style, "br")).unwrap(), 4096)
+            .read_to_string(&mut unbrotlied)
+            .unwrap();
+        assert_eq!(unbrotlied, text);
+
+        assert!(!sibling_path(&dir.path().join("small.js"), "gz").exists());
+        assert!(!sibling_path(&dir.path().join("photo.png"), "gz").exists());
+        let own = dir.path().join("own.html");
+        assert_eq!(
+            fs::read_to_string(sibling_path(&own, "br")).unwrap(),
+            "shipped by the site"
+        );
+        assert!(sibling_path(&own, "gz").exists());
+    }
+}
@@ -1,16 +1,56 @@
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
+
+use chrono_tz::Tz;
 use config::ConfigError;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
-use crate::db::pg::PgConn;
+use crate::{
+    auth::DashboardUser,
+    cache::{default_cache_rules, CachePolicy, CacheRule},
+    db::pg::PgConn,
+    notify::NotificationConfig,
+    pull::{GitCredentials, RefSelector},
+    security::SecurityHeaders,
+};
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
-    pub domain: String,
     pub bind_address: String,
     pub port: u16,
-    pub site_repo: String,
-    pub branch: String,
+    /// also listen for https on this port, for every site with a certificate
+    pub tls_port: Option<u16>,
+    /// redirect plain http requests to `tls_port`
+    #[serde(default)]
+    pub redirect_to_https: bool,
     pub real_ip_header: String,
+    /// skip the login and let anyone see the dashboard
+    #[serde(default)]
+    pub public_dashboard: bool,
+    /// who can log in to the dashboard
+    #[serde(default)]
+    pub dashboard_users: Vec<DashboardUser>,
+    /// accepted as `Authorization: Bearer <token>` for programmatic access
+    #[serde(default)]
+    pub api_tokens: Vec<String>,
+    /// mixed into visitor ids in exports. without it every export gets a
+    /// random salt, so visitors can only be followed within a single export
+    pub export_salt: Option<String>,
+    /// graphs, date ranges and daily exports go by days in this timezone
+    #[serde(default)]
+    pub timezone: Tz,
+    /// security headers for the `/analytics` dashboard, these default to
+    /// something stricter than the sites get
+    #[serde(default)]
+    pub dashboard_security_headers: SecurityHeaders,
+    /// requests are routed to a site by their `Host` header. the first site
+    /// also answers any host that doesn't match a site. configs from before
+    /// `[[sites]]` existed have a single site's keys at the top level instead
+    #[serde(default)]
+    pub sites: Vec<SiteConfig>,
 
     pub pg_user: String,
     pub pg_password: String,
@@ -19,6 +59,314 @@ pub struct Config {
     pub pg_dbname: String,
 }
 
+/// everything needed to deploy and serve a single site
+#[derive(Deserialize, Debug, Clone)]
+pub struct SiteConfig {
+    /// also used as the site's id in analytics and the deploy history
+    pub domain: String,
+    /// other hosts serving the same site, eg `www.` variants
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    pub site_repo: String,
+    pub branch: String,
+    /// deploy the highest semver tag matching this glob instead of following `branch`
+    pub tag_pattern: Option<String>,
+    /// let `tag_pattern` pick prereleases like `v2.0.0-rc1`
+    #[serde(default)]
+    pub tag_prereleases: bool,
+    /// deploy exactly this tag or commit, takes priority over `tag_pattern`
+    pub pinned_ref: Option<String>,
+
+    /// only needed for private repos, see [`GitCredentials`]
+    pub ssh_username: Option<String>,
+    pub ssh_key_path: Option<String>,
+    pub ssh_public_key_path: Option<String>,
+    pub ssh_key_passphrase: Option<String>,
+    #[serde(default)]
+    pub ssh_agent: bool,
+    pub https_username: Option<String>,
+    pub https_token: Option<String>,
+
+    /// run in the checkout after every fetch, eg `zola build`. the output is
+    /// only published if it exits successfully
+    pub build_command: Option<String>,
+    pub build_timeout_secs: Option<u64>,
+    /// poll the remote for new commits this often, for when webhooks aren't an option
+    pub poll_interval_secs: Option<u64>,
+
+    /// where the site repo gets cloned to, defaults to `./static/<domain>/repo`
+    pub clone_dir: Option<String>,
+    /// the directory within the repo that gets published
+    #[serde(default = "default_publish_dir")]
+    pub publish_dir: String,
+    /// a copy of the publish dir from the last successful deploy, this is what
+    /// gets served. it's a symlink to the current copy in `<live_dir>.versions`.
+    /// defaults to `./static/<domain>/live`
+    pub live_dir: Option<String>,
+    #[serde(default = "default_index_file")]
+    pub index_file: String,
+    /// relative to the publish dir
+    #[serde(default = "default_error_page")]
+    pub error_page: String,
+    /// list the contents of directories without an index file
+    #[serde(default)]
+    pub show_files_listing: bool,
+    /// serve files and directories starting with a `.`, eg `.env`
+    #[serde(default)]
+    pub use_hidden_files: bool,
+    /// hidden paths that are served anyway, relative to the publish dir
+    #[serde(default = "default_hidden_allowlist")]
+    pub hidden_allowlist: Vec<String>,
+    /// redirect pages like `/about` and `/about.html` to one canonical form,
+    /// by default both are served as is
+    pub trailing_slash: Option<TrailingSlash>,
+    /// generate `.br` and `.gz` siblings for text assets on deploy, these are
+    /// served in place of the original to clients that accept them
+    #[serde(default = "default_precompress")]
+    pub precompress: bool,
+    /// security headers for the site and its previews
+    #[serde(default)]
+    pub security_headers: SecurityHeaders,
+    /// `Cache-Control` for the site's files, the first matching rule wins
+    #[serde(default = "default_cache_rules")]
+    pub cache_rules: Vec<CacheRule>,
+
+    /// deploy remote branches as previews served at `<slug>.<preview_domain>`,
+    /// see [`preview_slug`]
+    pub preview_domain: Option<String>,
+    /// only preview branches matching this glob, defaults to every branch
+    pub preview_branches: Option<String>,
+    /// where preview checkouts live, defaults to `./static/<domain>/previews`
+    pub preview_dir: Option<String>,
+    /// the domain of the site this is a preview of, set by [`SiteConfig::preview_site`]
+    #[serde(skip)]
+    pub preview_of: Option<String>,
+
+    /// pem certificate chain and private key for the https listener, picked
+    /// by SNI. they're reloaded when the files change or on SIGHUP. previews
+    /// use the same certificate, so it needs to cover `*.<preview_domain>`
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+
+    /// outgoing webhooks fired as the site deploys
+    #[serde(default)]
+    pub notifications: Vec<NotificationConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailingSlash {
+    /// `/about/`
+    Always,
+    /// `/about`
+    Never,
+}
+
+fn default_publish_dir() -> String {
+    "public".to_string()
+}
+fn default_index_file() -> String {
+    "index.html".to_string()
+}
+fn default_error_page() -> String {
+    "404.html".to_string()
+}
+fn default_hidden_allowlist() -> Vec<String> {
+    vec![".well-known".to_string()]
+}
+fn default_precompress() -> bool {
+    true
+}
+
+/// turns a branch name into something usable as a subdomain. names that had
+/// to change get a short hash of the original so `feat/a` and `feat-a` don't
+/// share a preview, eg `feat/New-Post` becomes `feat-new-post-0e05f3`
+pub fn preview_slug(branch: &str) -> String {
+    let slug: String = branch
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_lowercase(),
+            false => '-',
+        })
+        .collect();
+    let slug = slug.trim_matches('-');
+    if slug == branch {
+        return slug.to_string();
+    }
+    let hash = Sha256::digest(branch.as_bytes());
+    let suffix: String = hash[..3].iter().map(|x| format!("{:02x}", x)).collect();
+    match slug.is_empty() {
+        true => suffix,
+        false => format!("{}-{}", slug, suffix),
+    }
+}
+
+/// the host a preview with `slug` is served at, also its domain
+pub fn preview_host(slug: &str, preview_domain: &str) -> String {
+    format!("{}.{}", slug, preview_domain)
+}
+
+/// `host` without its port. ipv6 addresses keep their brackets, eg
+/// `[::1]:8080` becomes `[::1]`
+pub(crate) fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+    match host.rsplit_once(':') {
+        Some((name, _)) if !name.contains(':') => name,
+        _ => host,
+    }
+}
+
+impl SiteConfig {
+    pub fn clone_path(&self) -> PathBuf {
+        match &self.clone_dir {
+            Some(x) => PathBuf::from(x),
+            None => Path::new("./static").join(&self.domain).join("repo"),
+        }
+    }
+    /// the publish dir within the checkout, this is what the build should produce
+    pub fn publish_path(&self) -> PathBuf {
+        self.clone_path().join(&self.publish_dir)
+    }
+    pub fn live_path(&self) -> PathBuf {
+        match &self.live_dir {
+            Some(x) => PathBuf::from(x),
+            None => Path::new("./static").join(&self.domain).join("live"),
+        }
+    }
+    /// every host this site answers to
+    pub fn hosts(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.domain.as_str()).chain(self.aliases.iter().map(|x| x.as_str()))
+    }
+    /// whether `path`, relative to the live dir, may be served. hidden files
+    /// are only served when enabled or allowlisted
+    pub fn is_servable(&self, path: &Path) -> bool {
+        if self.use_hidden_files {
+            return true;
+        }
+        let hidden = path
+            .components()
+            .any(|x| x.as_os_str().to_string_lossy().starts_with('.'));
+        !hidden
+            || self
+                .hidden_allowlist
+                .iter()
+                .any(|x| path.starts_with(x.trim_matches('/')))
+    }
+    pub fn error_page_path(&self) -> PathBuf {
+        self.live_path().join(&self.error_page)
+    }
+    /// checks that the directories being served actually exist and that none
+    /// of the site's hosts fall under its preview domain, should be called
+    /// after the initial deploy. a missing error page is only a warning
+    /// since unknown paths fall back to a plain 404
+    pub fn validate_paths(&self) -> Result<(), String> {
+        if let Some(preview_domain) = &self.preview_domain {
+            let suffix = format!(".{}", preview_domain);
+            if let Some(host) = self.hosts().find(|x| x.ends_with(&suffix)) {
+                return Err(format!(
+                    "{}: {} is under preview_domain {}, so it would be served as a preview. \
+                     use a preview_domain that none of the site's hosts end with",
+                    self.domain, host, preview_domain
+                ));
+            }
+        }
+        if !self.clone_path().is_dir() {
+            return Err(format!(
+                "{}: clone_dir {} does not exist, check that site_repo can be cloned",
+                self.domain,
+                self.clone_path().display()
+            ));
+        }
+        if !self.publish_path().is_dir() {
+            return Err(format!(
+                "{}: publish_dir {} does not exist in the repo at {}",
+                self.domain,
+                self.publish_dir,
+                self.publish_path().display()
+            ));
+        }
+        if !self.live_path().is_dir() {
+            return Err(format!(
+                "{}: live_dir {} does not exist, the site has never been deployed successfully",
+                self.domain,
+                self.live_path().display()
+            ));
+        }
+        if !self.live_path().join(&self.index_file).is_file() {
+            println!(
+                "warning: {}: index_file {} not found in {}",
+                self.domain,
+                self.index_file,
+                self.live_path().display()
+            );
+        }
+        if !self.error_page_path().is_file() {
+            println!(
+                "warning: {}: error_page {} not found in {}, serving a plain 404 instead",
+                self.domain,
+                self.error_page,
+                self.live_path().display()
+            );
+        }
+        Ok(())
+    }
+    pub fn preview_path(&self) -> PathBuf {
+        match &self.preview_dir {
+            Some(x) => PathBuf::from(x),
+            None => Path::new("./static").join(&self.domain).join("previews"),
+        }
+    }
+    /// the config used to deploy `branch` as a preview. it shares everything
+    /// with the site except for where it's checked out and served from
+    pub fn preview_site(&self, branch: &str) -> Option<SiteConfig> {
+        let preview_domain = self.preview_domain.as_ref()?;
+        let slug = preview_slug(branch);
+        let dir = self.preview_path().join(&slug);
+        Some(SiteConfig {
+            domain: preview_host(&slug, preview_domain),
+            aliases: Vec::new(),
+            branch: branch.to_string(),
+            tag_pattern: None,
+            tag_prereleases: false,
+            pinned_ref: None,
+            poll_interval_secs: None,
+            clone_dir: Some(dir.join("repo").to_string_lossy().to_string()),
+            live_dir: Some(dir.join("live").to_string_lossy().to_string()),
+            preview_domain: None,
+            preview_branches: None,
+            preview_dir: None,
+            preview_of: Some(self.domain.clone()),
+            notifications: Vec::new(),
+            ..self.clone()
+        })
+    }
+    pub fn git_credentials(&self) -> GitCredentials {
+        GitCredentials {
+            ssh_username: self.ssh_username.clone(),
+            ssh_key_path: self.ssh_key_path.clone(),
+            ssh_public_key_path: self.ssh_public_key_path.clone(),
+            ssh_key_passphrase: self.ssh_key_passphrase.clone(),
+            ssh_agent: self.ssh_agent,
+            https_username: self.https_username.clone(),
+            https_token: self.https_token.clone(),
+        }
+    }
+    pub fn ref_selector(&self) -> RefSelector {
+        if let Some(pinned) = &self.pinned_ref {
+            return RefSelector::Pinned(pinned.clone());
+        }
+        if let Some(pattern) = &self.tag_pattern {
+            return RefSelector::LatestTag(pattern.clone(), self.tag_prereleases);
+        }
+        RefSelector::Branch(self.branch.clone())
+    }
+}
+
 impl Config {
     pub fn create_conn(&self) -> PgConn {
         let db_config = deadpool_postgres::Config {
@@ -33,6 +381,58 @@ impl Config {
         let pool = db_config.create_pool(None, tokio_postgres::NoTls).unwrap();
         PgConn { db: pool }
     }
+    /// the site answering to `host`, falling back to the first site. any port is ignored
+    pub fn site_for_host(&self, host: &str) -> &SiteConfig {
+        let host = strip_port(host);
+        self.sites
+            .iter()
+            .find(|site| site.hosts().any(|x| x.eq_ignore_ascii_case(host)))
+            .unwrap_or(&self.sites[0])
+    }
+    /// whether `host` is under any site's `preview_domain`, regardless of
+    /// whether that preview exists
+    pub fn is_preview_host(&self, host: &str) -> bool {
+        self.preview_slug_for_host(host).is_some()
+    }
+    fn preview_slug_for_host<'a>(&'a self, host: &'a str) -> Option<(&'a SiteConfig, &'a str)> {
+        let host = strip_port(host);
+        self.sites.iter().find_map(|site| {
+            let preview_domain = site.preview_domain.as_ref()?;
+            let slug = host
+                .strip_suffix(preview_domain.as_str())?
+                .strip_suffix('.')?;
+            Some((site, slug))
+        })
+    }
+    /// the branch preview answering to `host`, if it has been deployed. the
+    /// returned config is only good for serving, the slug in the host can't
+    /// be turned back into the original branch name
+    pub fn preview_for_host(&self, host: &str) -> Option<SiteConfig> {
+        let (site, slug) = self.preview_slug_for_host(host)?;
+        if slug.is_empty() || slug.contains('.') || preview_slug(slug) != slug {
+            return None;
+        }
+        let preview = site.preview_site(slug)?;
+        preview.live_path().is_dir().then_some(preview)
+    }
+    /// the site or branch preview that should serve `host`. hosts under a
+    /// preview domain without a deployed preview get nothing rather than
+    /// falling back to the first site
+    pub fn resolve_host(&self, host: &str) -> Option<Cow<'_, SiteConfig>> {
+        if self.is_preview_host(host) {
+            return self.preview_for_host(host).map(Cow::Owned);
+        }
+        Some(Cow::Borrowed(self.site_for_host(host)))
+    }
+    pub fn get_site(&self, domain: &str) -> Option<&SiteConfig> {
+        self.sites.iter().find(|x| x.domain == domain)
+    }
+    /// the site a deploy recorded under `site` belongs to. deploys of previews
+    /// used to be recorded under the preview's host
+    pub fn deploy_site<'a>(&'a self, site: &'a str) -> Option<&'a SiteConfig> {
+        self.get_site(site)
+            .or_else(|| self.preview_slug_for_host(site).map(|(site, _)| site))
+    }
     pub fn get_config() -> Result<Config, ConfigError> {
         let settings = config::Config::builder()
             // Add in `./Settings.toml`
@@ -48,13 +448,178 @@ impl Config {
                 return Err(x);
             }
         };
-
-        let config = match settings.try_deserialize::<Config>() {
+        Config::from_settings(settings)
+    }
+    fn from_settings(settings: config::Config) -> Result<Config, ConfigError> {
+        let mut config = match settings.clone().try_deserialize::<Config>() {
             Ok(config) => config,
             Err(error) => {
                 return Err(error);
             }
         };
+        if config.sites.is_empty() {
+            if settings.get_string("domain").is_err() {
+                return Err(ConfigError::Message(
+                    "at least one site must be configured in a [[sites]] table".to_string(),
+                ));
+            }
+            let site = settings.try_deserialize::<SiteConfig>().map_err(|e| {
+                ConfigError::Message(format!(
+                    "failed to read the site from the top level of the config: {}. \
+                     move domain, site_repo, branch and the other site keys into a [[sites]] table",
+                    e
+                ))
+            })?;
+            println!(
+                "warning: site keys at the top level of the config are deprecated, \
+                 move them into a [[sites]] table"
+            );
+            config.sites.push(site);
+        }
+        for site in &config.sites {
+            if let Err(e) = CachePolicy::new(&site.cache_rules) {
+                return Err(ConfigError::Message(format!("{}: {}", site.domain, e)));
+            }
+            if let Err(e) = site.security_headers.validate() {
+                return Err(ConfigError::Message(format!("{}: {}", site.domain, e)));
+            }
+        }
+        config
+            .dashboard_security_headers
+            .validate()
+            .map_err(ConfigError::Message)?;
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DB: &str = r#"
+        bind_address="127.0.0.1"
+        port=8029
+        real_ip_header="CF-Connecting-IP"
+        pg_user="ivy"
+        pg_password="password"
+        pg_host="127.0.0.1"
+        pg_port=5432
+        pg_dbname="ivyhost"
+    "#;
+
+    fn parse(toml: &str) -> Result<Config, ConfigError> {
+        let settings = config::Config::builder()
+            .add_source(config::File::from_str(
+                &format!("{}\n{}", DB, toml),
+                config::FileFormat::Toml,
+            ))
+            .build()?;
+        Config::from_settings(settings)
+    }
+
+    #[test]
+    fn reads_a_single_site_from_the_top_level() {
+        let config = parse(
+            r#"
+            domain="ivytime.gay"
+            site_repo="https://github.com/uberfig/ivytime.gay.git"
+            branch="main"
+            "#,
+        )
+        .expect("legacy config is accepted");
+        assert_eq!(config.sites.len(), 1);
+        assert_eq!(config.sites[0].domain, "ivytime.gay");
+        assert_eq!(config.sites[0].branch, "main");
+    }
+
+    #[test]
+    fn explains_a_config_without_sites() {
+        let error = parse("").unwrap_err().to_string();
+        assert!(error.contains("[[sites]]"), "{}", error);
+        let error = parse(r#"domain="ivytime.gay""#).unwrap_err().to_string();
+        assert!(error.contains("[[sites]]"), "{}", error);
+    }
+
+    #[test]
+    fn routes_hosts_with_ports() {
+        let config = parse(
+            r#"
+            [[sites]]
+            domain="one.test"
+            site_repo="https://example.com/one.git"
+            branch="main"
+            [[sites]]
+            domain="two.test"
+            aliases=["[::1]", "127.0.0.1"]
+            site_repo="https://example.com/two.git"
+            branch="main"
+            "#,
+        )
+        .unwrap();
+        for host in [
+            "two.test",
+            "two.test:8029",
+            "[::1]",
+            "[::1]:8029",
+            "127.0.0.1:80",
+        ] {
+            assert_eq!(config.site_for_host(host).domain, "two.test", "{}", host);
+        }
+        assert_eq!(config.site_for_host("[::2]:8029").domain, "one.test");
+        assert_eq!(strip_port("::1"), "::1");
+    }
+
+    #[test]
+    fn preview_slugs_dont_collide() {
+        assert_eq!(preview_slug("feat-a"), "feat-a");
+        assert_eq!(preview_slug("feat/New-Post"), "feat-new-post-0e05f3");
+        let slug = preview_slug("feat/a");
+        assert!(slug.starts_with("feat-a-"), "{}", slug);
+        assert_ne!(slug, preview_slug("Feat-A"));
+        // a slug with a suffix is already a slug, so its host maps back to it
+        assert_eq!(preview_slug(&slug), slug);
+    }
+
+    #[test]
+    fn rejects_hosts_under_the_preview_domain() {
+        let config = parse(
+            r#"
+            [[sites]]
+            domain="ivytime.gay"
+            aliases=["www.ivytime.gay"]
+            site_repo="https://example.com/site.git"
+            branch="main"
+            preview_domain="ivytime.gay"
+            "#,
+        )
+        .unwrap();
+        let error = config.sites[0].validate_paths().unwrap_err();
+        assert!(error.contains("www.ivytime.gay"), "{}", error);
+    }
+
+    #[test]
+    fn previews_belong_to_their_site() {
+        let config = parse(
+            r#"
+            [[sites]]
+            domain="ivytime.gay"
+            site_repo="https://example.com/site.git"
+            branch="main"
+            preview_domain="preview.ivytime.gay"
+            "#,
+        )
+        .unwrap();
+        let preview = config.sites[0].preview_site("feat/New-Post").unwrap();
+        assert_eq!(preview.domain, "feat-new-post-0e05f3.preview.ivytime.gay");
+        assert_eq!(preview.preview_of.as_deref(), Some("ivytime.gay"));
+        assert_eq!(preview.branch, "feat/New-Post");
+        // deploys recorded under the preview's host before it had a column
+        let site = config.deploy_site(&preview.domain).unwrap();
+        assert_eq!(site.domain, "ivytime.gay");
+        assert_eq!(
+            config.deploy_site("ivytime.gay").unwrap().domain,
+            "ivytime.gay"
+        );
+        assert!(config.deploy_site("other.test").is_none());
+    }
+}
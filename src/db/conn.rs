@@ -1,6 +1,7 @@
+use futures_util::stream::BoxStream;
 use serde::Serialize;
 
-use crate::analytics::AnalyticsRequest;
+use crate::{analytics::AnalyticsRequest, deploy::DeployOutcome};
 
 #[derive(Serialize, Debug)]
 pub struct Path {
@@ -22,39 +23,198 @@ pub struct GraphView {
     pub title: String,
 }
 
+/// requests and unique visitors over a period, per bucket and overall
+#[derive(Serialize, Debug)]
+pub struct Period {
+    pub total: Vec<Graphnode>,
+    pub unique: Vec<Graphnode>,
+    pub total_requests: i64,
+    /// visitors over the whole period, which is less than the sum of the
+    /// buckets when visitors come back
+    pub unique_visitors: i64,
+}
+
+/// a period and, when comparing, the one before it
+#[derive(Serialize, Debug)]
+pub struct PeriodComparison {
+    pub current: Period,
+    pub previous: Option<Period>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Deploy {
+    pub did: i64,
+    pub site: String,
+    /// the previewed branch, none for deploys of the site itself
+    pub preview: Option<String>,
+    pub trigger: String,
+    /// one of `running`, `success` or `failed`
+    pub status: String,
+    pub commit_id: Option<String>,
+    pub commit_message: Option<String>,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    pub build_log: Option<String>,
+    pub error: Option<String>,
+}
+
+/// a single recorded request, as exported
+#[derive(Debug)]
+pub struct ExportedRequest {
+    pub site: String,
+    pub path: String,
+    /// the stored hash of the visitor's ip, not to be exported as is
+    pub ip_address_hash: String,
+    pub created_at: i64,
+    /// whether it's the visitor's first request to the path, always false
+    /// unless asked for
+    pub first_visit: bool,
+}
+
+/// requests to a path over one day in the reporting timezone, as exported
+#[derive(Serialize, Debug)]
+pub struct DailyPathCount {
+    pub site: String,
+    pub path: String,
+    /// `YYYY-MM-DD`
+    pub day: String,
+    pub total_requests: i64,
+    pub unique_visitors: i64,
+}
+
 pub trait Conn {
     fn init(&self) -> impl std::future::Future<Output = Result<(), String>> + Send;
     fn new_request(
         &self,
         request: AnalyticsRequest,
     ) -> impl std::future::Future<Output = ()> + Send;
-    fn get_total_paths(&self) -> impl std::future::Future<Output = i64> + Send;
+    /// assigns analytics and deploys recorded before multi-site support to `site`
+    fn claim_unassigned(&self, site: &str) -> impl std::future::Future<Output = ()> + Send;
+    fn get_total_paths(&self, site: &str) -> impl std::future::Future<Output = i64> + Send;
     fn get_paths_alphabetic(
         &self,
+        site: &str,
         limit: i64,
         ofset: i64,
     ) -> impl std::future::Future<Output = Vec<Path>> + Send;
     fn get_paths_unique_visitors_dec(
         &self,
+        site: &str,
         limit: i64,
         ofset: i64,
     ) -> impl std::future::Future<Output = Vec<Path>> + Send;
+    /// the paths with the most unique visitors between `from` and `to`, with
+    /// their counts over just that time
+    fn get_top_paths(
+        &self,
+        site: &str,
+        from: i64,
+        to: i64,
+        limit: i64,
+    ) -> impl std::future::Future<Output = Vec<Path>> + Send;
+    /// requests to a path in each bucket. `bounds` are the start of every
+    /// bucket followed by the end of the last, each bucket includes its start
     fn get_graph_total(
         &self,
         pid: i64,
         title: String,
-        duration: i64,
-        limit: usize,
-        current_time: i64,
+        bounds: &[i64],
     ) -> impl std::future::Future<Output = GraphView> + Send;
+    /// unique visitors to a path in each bucket, see `get_graph_total`
     fn get_graph_unique(
         &self,
         pid: i64,
         title: String,
-        duration: i64,
-        limit: usize,
-        current_time: i64,
+        bounds: &[i64],
     ) -> impl std::future::Future<Output = GraphView> + Send;
-    fn get_pid(&self, path: &str) -> impl std::future::Future<Output = Option<i64>> + Send;
+    /// a path's graphs, or the whole site's without `pid`, over the buckets in
+    /// `bounds` and optionally over `previous_bounds` to compare them with.
+    /// the totals only count the first `elapsed` milliseconds of each period,
+    /// so one that's still going is compared like for like
+    fn get_period_comparison(
+        &self,
+        site: &str,
+        pid: Option<i64>,
+        bounds: &[i64],
+        previous_bounds: Option<&[i64]>,
+        elapsed: i64,
+    ) -> impl std::future::Future<Output = PeriodComparison> + Send;
+    fn get_pid(
+        &self,
+        site: &str,
+        path: &str,
+    ) -> impl std::future::Future<Output = Option<i64>> + Send;
     fn get_path(&self, pid: i64) -> impl std::future::Future<Output = Path> + Send;
+    /// records the start of a deploy, returning its id. `preview` is the
+    /// branch for deploys of one of the site's previews
+    fn new_deploy(
+        &self,
+        site: &str,
+        preview: Option<&str>,
+        trigger: &str,
+        started_at: i64,
+    ) -> impl std::future::Future<Output = i64> + Send;
+    fn finish_deploy(
+        &self,
+        did: i64,
+        outcome: &DeployOutcome,
+        finished_at: i64,
+    ) -> impl std::future::Future<Output = ()> + Send;
+    /// most recent first
+    fn get_deploys(
+        &self,
+        site: &str,
+        limit: i64,
+        ofset: i64,
+    ) -> impl std::future::Future<Output = Vec<Deploy>> + Send;
+    fn get_deploy(&self, did: i64) -> impl std::future::Future<Output = Option<Deploy>> + Send;
+    /// stores a dashboard login, clearing out any expired ones
+    fn new_session(
+        &self,
+        token_hash: &str,
+        username: &str,
+        created_at: i64,
+        expires_at: i64,
+    ) -> impl std::future::Future<Output = ()> + Send;
+    /// the user logged in with this session, if it hasn't expired
+    fn get_session(
+        &self,
+        token_hash: &str,
+        current_time: i64,
+    ) -> impl std::future::Future<Output = Option<String>> + Send;
+    fn delete_session(&self, token_hash: &str) -> impl std::future::Future<Output = ()> + Send;
+    /// records every request from an imported file in one transaction and
+    /// recounts the paths they touched. `requests` is read and inserted in
+    /// batches, the first error rolls the whole file back. returns `None`
+    /// without reading anything if the file's `source_hash` was already
+    /// imported for `site`
+    fn import_requests<I>(
+        &self,
+        site: &str,
+        source_hash: &str,
+        name: &str,
+        requests: I,
+        imported_at: i64,
+    ) -> impl std::future::Future<Output = Result<Option<u64>, String>> + Send
+    where
+        I: Iterator<Item = Result<AnalyticsRequest, String>> + Send;
+    /// requests between `from` inclusive and `to` exclusive, oldest first. rows
+    /// are read in batches as the stream is polled. `first_visits` also looks
+    /// up whether each is the visitor's first request to its path
+    fn export_requests(
+        &self,
+        site: &str,
+        from: i64,
+        to: i64,
+        first_visits: bool,
+    ) -> impl std::future::Future<Output = BoxStream<'static, Vec<ExportedRequest>>> + Send;
+    /// per path counts for each day in `timezone` between `from` and `to`, in
+    /// batches like `export_requests`
+    fn export_daily_counts(
+        &self,
+        site: &str,
+        from: i64,
+        to: i64,
+        timezone: &str,
+    ) -> impl std::future::Future<Output = BoxStream<'static, Vec<DailyPathCount>>> + Send;
 }
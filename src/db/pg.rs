@@ -1,11 +1,19 @@
-use std::ops::DerefMut;
+use std::{collections::HashSet, ops::DerefMut};
 
-use deadpool_postgres::Pool;
-use tokio_postgres::Row;
+use deadpool_postgres::{Object, Pool};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use tokio_postgres::{types::ToSql, Row};
 
-use crate::db::conn::Graphnode;
+use crate::{analytics::AnalyticsRequest, db::conn::Graphnode, deploy::DeployOutcome};
 
-use super::conn::{Conn, GraphView, Path};
+use super::conn::{
+    Conn, DailyPathCount, Deploy, ExportedRequest, GraphView, Path, Period, PeriodComparison,
+};
+
+/// rows fetched from an export cursor at a time
+const EXPORT_BATCH: i64 = 1000;
+/// rows inserted per statement when importing
+const IMPORT_BATCH: usize = 10000;
 
 mod embedded {
     use refinery::embed_migrations;
@@ -57,17 +65,18 @@ impl PgConn {
     }
     async fn select_or_init_path(
         transaction: &deadpool_postgres::Transaction<'_>,
+        site: &str,
         path: &str,
     ) -> i64 {
         let stmt = r#"
-        SELECT * FROM paths WHERE path = $1;
+        SELECT * FROM paths WHERE site = $1 AND path = $2;
         "#;
         let stmt = transaction
             .prepare(stmt)
             .await
             .expect("failed to prepare query");
         let result = transaction
-            .query(&stmt, &[&path])
+            .query(&stmt, &[&site, &path])
             .await
             .expect("failed to get path")
             .pop();
@@ -75,15 +84,15 @@ impl PgConn {
             Some(x) => x.get("pid"),
             None => {
                 let stmt = r#"
-                INSERT INTO paths (path)
-                VALUES ($1)
+                INSERT INTO paths (site, path)
+                VALUES ($1, $2)
                 RETURNING pid;"#;
                 let stmt = transaction
                     .prepare(stmt)
                     .await
                     .expect("failed to prepare query");
                 transaction
-                    .query(&stmt, &[&path])
+                    .query(&stmt, &[&site, &path])
                     .await
                     .expect("failed to insert path")
                     .pop()
@@ -162,6 +171,154 @@ impl PgConn {
     }
 }
 
+/// a client with an export cursor open in its transaction. if the stream is
+/// dropped part way through, eg because the download was cancelled, the
+/// connection is closed rather than returned to the pool mid transaction
+struct ExportCursor {
+    client: Option<Object>,
+}
+
+impl Drop for ExportCursor {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            drop(Object::take(client));
+        }
+    }
+}
+
+impl PgConn {
+    /// `count` of a path's requests in each bucket between `bounds`
+    async fn get_graph(&self, pid: i64, title: String, bounds: &[i64], count: &str) -> GraphView {
+        let mut timeline = empty_timeline(bounds);
+        let (Some(first), Some(last)) = (bounds.first(), bounds.last()) else {
+            return GraphView { timeline, title };
+        };
+        let client = self.db.get().await.expect("failed to get client");
+        // width_bucket numbers the buckets from 1, in one pass over the requests
+        let stmt = format!(
+            r#"
+                SELECT width_bucket(created_at, $2::bigint[]) AS bucket, {} AS count
+                FROM requests
+                WHERE pid = $1 AND created_at >= $3 AND created_at < $4
+                GROUP BY bucket;"#,
+            count
+        );
+        let rows = client
+            .query(&stmt, &[&pid, &bounds, first, last])
+            .await
+            .expect("failed to get path count");
+        for row in rows {
+            let bucket: i32 = row.get("bucket");
+            let amount: i64 = row.get("count");
+            if let Some(node) = timeline.get_mut(bucket as usize - 1) {
+                node.amount = amount as u32;
+            }
+        }
+        GraphView { timeline, title }
+    }
+
+    /// requests to a path, or the whole site without `pid`, in each bucket and
+    /// over the first `elapsed` milliseconds
+    async fn get_period(
+        client: &Object,
+        site: &str,
+        pid: Option<i64>,
+        bounds: &[i64],
+        elapsed: i64,
+    ) -> Period {
+        let mut total = empty_timeline(bounds);
+        let mut unique = empty_timeline(bounds);
+        let (Some(&first), Some(&last)) = (bounds.first(), bounds.last()) else {
+            return Period {
+                total,
+                unique,
+                total_requests: 0,
+                unique_visitors: 0,
+            };
+        };
+        let stmt = r#"
+                SELECT width_bucket(requests.created_at, $3::bigint[]) AS bucket,
+                    COUNT(*) AS total, COUNT(DISTINCT requests.uid) AS uniq
+                FROM requests
+                JOIN paths ON paths.pid = requests.pid
+                WHERE paths.site = $1 AND ($2::bigint IS NULL OR requests.pid = $2)
+                    AND requests.created_at >= $4 AND requests.created_at < $5
+                GROUP BY bucket;"#;
+        let rows = client
+            .query(stmt, &[&site, &pid, &bounds, &first, &last])
+            .await
+            .expect("failed to get period graph");
+        for row in rows {
+            let bucket = row.get::<_, i32>("bucket") as usize - 1;
+            if let (Some(t), Some(u)) = (total.get_mut(bucket), unique.get_mut(bucket)) {
+                t.amount = row.get::<_, i64>("total") as u32;
+                u.amount = row.get::<_, i64>("uniq") as u32;
+            }
+        }
+
+        let stmt = r#"
+                SELECT COUNT(*) AS total, COUNT(DISTINCT requests.uid) AS uniq
+                FROM requests
+                JOIN paths ON paths.pid = requests.pid
+                WHERE paths.site = $1 AND ($2::bigint IS NULL OR requests.pid = $2)
+                    AND requests.created_at >= $3 AND requests.created_at < $4;"#;
+        let end = first.saturating_add(elapsed).min(last);
+        let row = client
+            .query_one(stmt, &[&site, &pid, &first, &end])
+            .await
+            .expect("failed to get period totals");
+        Period {
+            total,
+            unique,
+            total_requests: row.get("total"),
+            unique_visitors: row.get("uniq"),
+        }
+    }
+
+    /// streams the rows of `query` through a server side cursor, so an export
+    /// never holds more than a batch in memory
+    async fn stream_query<T: Send + 'static>(
+        &self,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+        map: fn(&Row) -> T,
+    ) -> BoxStream<'static, Vec<T>> {
+        let client = self.db.get().await.expect("failed to get client");
+        client
+            .batch_execute("BEGIN READ ONLY;")
+            .await
+            .expect("failed to begin transaction");
+        client
+            .execute(
+                &format!("DECLARE export NO SCROLL CURSOR FOR {}", query),
+                params,
+            )
+            .await
+            .expect("failed to declare cursor");
+        let cursor = ExportCursor {
+            client: Some(client),
+        };
+        stream::unfold(cursor, move |mut cursor| async move {
+            let client = cursor.client.as_ref()?;
+            let rows = client
+                .query(&format!("FETCH {} FROM export;", EXPORT_BATCH), &[])
+                .await
+                .expect("failed to fetch from cursor");
+            if rows.is_empty() {
+                client
+                    .batch_execute("CLOSE export; COMMIT;")
+                    .await
+                    .expect("failed to close cursor");
+                // finished cleanly, so the client can go back to the pool
+                drop(cursor.client.take());
+                return None;
+            }
+            Some((rows.iter().map(map).collect(), cursor))
+        })
+        .boxed()
+    }
+}
+
 impl Conn for PgConn {
     async fn init(&self) -> Result<(), String> {
         init(self).await
@@ -174,7 +331,7 @@ impl Conn for PgConn {
             .await
             .expect("failed to begin transaction");
         let uid = PgConn::select_or_init_visitor(&transaction, &request.hashed_ip).await;
-        let pid = PgConn::select_or_init_path(&transaction, &request.path).await;
+        let pid = PgConn::select_or_init_path(&transaction, &request.site, &request.path).await;
         PgConn::incriment_unique(&transaction, pid, uid).await;
         PgConn::incriment_total(&transaction, pid).await;
         PgConn::insert_request(&transaction, pid, uid, request.created_at_milis).await;
@@ -184,12 +341,26 @@ impl Conn for PgConn {
             .expect("failed to commit transaction");
     }
 
-    async fn get_total_paths(&self) -> i64 {
+    async fn claim_unassigned(&self, site: &str) {
         let client = self.db.get().await.expect("failed to get client");
-        let stmt = r#"SELECT count(*) as count FROM paths;"#;
+        for stmt in [
+            r#"UPDATE paths SET site = $1 WHERE site = '';"#,
+            r#"UPDATE deploys SET site = $1 WHERE site = '';"#,
+        ] {
+            let stmt = client.prepare(stmt).await.expect("failed to prepare query");
+            client
+                .query(&stmt, &[&site])
+                .await
+                .expect("failed to claim unassigned rows");
+        }
+    }
+
+    async fn get_total_paths(&self, site: &str) -> i64 {
+        let client = self.db.get().await.expect("failed to get client");
+        let stmt = r#"SELECT count(*) as count FROM paths WHERE site = $1;"#;
         let stmt = client.prepare(stmt).await.expect("failed to prepare query");
         client
-            .query(&stmt, &[])
+            .query(&stmt, &[&site])
             .await
             .expect("failed to get path count")
             .pop()
@@ -197,16 +368,17 @@ impl Conn for PgConn {
             .get("count")
     }
 
-    async fn get_paths_alphabetic(&self, limit: i64, ofset: i64) -> Vec<Path> {
+    async fn get_paths_alphabetic(&self, site: &str, limit: i64, ofset: i64) -> Vec<Path> {
         let ofset = ofset * limit;
         let client = self.db.get().await.expect("failed to get client");
         let stmt = r#"
                 SELECT * FROM paths 
+                WHERE site = $1
                 ORDER BY path ASC
-                LIMIT $1 OFFSET $2;"#;
+                LIMIT $2 OFFSET $3;"#;
         let stmt = client.prepare(stmt).await.expect("failed to prepare query");
         client
-            .query(&stmt, &[&limit, &ofset])
+            .query(&stmt, &[&site, &limit, &ofset])
             .await
             .expect("failed to get paths")
             .iter()
@@ -216,6 +388,7 @@ impl Conn for PgConn {
 
     async fn get_paths_unique_visitors_dec(
         &self,
+        site: &str,
         limit: i64,
         ofset: i64,
     ) -> Vec<super::conn::Path> {
@@ -223,11 +396,12 @@ impl Conn for PgConn {
         let client = self.db.get().await.expect("failed to get client");
         let stmt = r#"
                 SELECT * FROM paths 
+                WHERE site = $1
                 ORDER BY unique_visitors DESC
-                LIMIT $1 OFFSET $2;"#;
+                LIMIT $2 OFFSET $3;"#;
         let stmt = client.prepare(stmt).await.expect("failed to prepare query");
         client
-            .query(&stmt, &[&limit, &ofset])
+            .query(&stmt, &[&site, &limit, &ofset])
             .await
             .expect("failed to get paths")
             .iter()
@@ -235,81 +409,70 @@ impl Conn for PgConn {
             .collect()
     }
 
+    async fn get_top_paths(&self, site: &str, from: i64, to: i64, limit: i64) -> Vec<Path> {
+        let client = self.db.get().await.expect("failed to get client");
+        let stmt = r#"
+                SELECT paths.path,
+                    COUNT(*) AS total_requests,
+                    COUNT(DISTINCT requests.uid) AS unique_visitors
+                FROM requests
+                JOIN paths ON paths.pid = requests.pid
+                WHERE paths.site = $1 AND requests.created_at >= $2 AND requests.created_at < $3
+                GROUP BY paths.path
+                ORDER BY unique_visitors DESC, total_requests DESC, paths.path
+                LIMIT $4;"#;
+        client
+            .query(stmt, &[&site, &from, &to, &limit])
+            .await
+            .expect("failed to get top paths")
+            .iter()
+            .map(|x| x.into())
+            .collect()
+    }
+
     async fn get_graph_total(
         &self,
         pid: i64,
         title: String,
-        duration: i64,
-        limit: usize,
-        current_time: i64,
+        bounds: &[i64],
     ) -> super::conn::GraphView {
-        let client = self.db.get().await.expect("failed to get client");
-        let stmt = r#"SELECT COUNT(*) as count FROM requests WHERE pid = $1 AND created_at <= $2  AND created_at > $3;"#;
-        let stmt = client.prepare(stmt).await.expect("failed to prepare query");
-
-        let mut timeline = Vec::<Graphnode>::with_capacity(limit);
-        for i in (0..limit as i64).rev() {
-            let range_recent = current_time - (duration * i);
-            let range_oldest = current_time - (duration * (i + 1));
-
-            let amount: i64 = client
-                .query(&stmt, &[&pid, &range_recent, &range_oldest])
-                .await
-                .expect("failed to get path count")
-                .pop()
-                .expect("did not return count")
-                .get("count");
-
-            timeline.push(Graphnode {
-                amount: amount as u32,
-                timestamp_start: range_oldest,
-                timestamp_end: range_recent,
-            });
-        }
-        GraphView { timeline, title }
+        self.get_graph(pid, title, bounds, "COUNT(*)").await
     }
 
     async fn get_graph_unique(
         &self,
         pid: i64,
         title: String,
-        duration: i64,
-        limit: usize,
-        current_time: i64,
+        bounds: &[i64],
     ) -> super::conn::GraphView {
-        let client = self.db.get().await.expect("failed to get client");
-        let stmt = r#"SELECT COUNT(DISTINCT uid) as count FROM requests WHERE pid = $1 AND created_at <= $2  AND created_at > $3;"#;
-        let stmt = client.prepare(stmt).await.expect("failed to prepare query");
-
-        let mut timeline = Vec::<Graphnode>::with_capacity(limit);
-        for i in (0..limit as i64).rev() {
-            let range_recent = current_time - (duration * i);
-            let range_oldest = current_time - (duration * (i + 1));
+        self.get_graph(pid, title, bounds, "COUNT(DISTINCT uid)")
+            .await
+    }
 
-            let amount: i64 = client
-                .query(&stmt, &[&pid, &range_recent, &range_oldest])
-                .await
-                .expect("failed to get path count")
-                .pop()
-                .expect("did not return count")
-                .get("count");
-
-            timeline.push(Graphnode {
-                amount: amount as u32,
-                timestamp_start: range_oldest,
-                timestamp_end: range_recent,
-            });
-        }
-        GraphView { timeline, title }
+    async fn get_period_comparison(
+        &self,
+        site: &str,
+        pid: Option<i64>,
+        bounds: &[i64],
+        previous_bounds: Option<&[i64]>,
+        elapsed: i64,
+    ) -> PeriodComparison {
+        let client = self.db.get().await.expect("failed to get client");
+        let current = Self::get_period(&client, site, pid, bounds, elapsed).await;
+        let previous = match previous_bounds {
+            Some(bounds) => Some(Self::get_period(&client, site, pid, bounds, elapsed).await),
+            None => None,
+        };
+        PeriodComparison { current, previous }
     }
 
-    async fn get_pid(&self, path: &str) -> Option<i64> {
+    async fn get_pid(&self, site: &str, path: &str) -> Option<i64> {
         let client = self.db.get().await.expect("failed to get client");
         let stmt = r#"
-                SELECT * FROM paths where path = $1;"#;
+                SELECT * FROM paths where site = $1 AND path = $2;"#;
         let stmt = client.prepare(stmt).await.expect("failed to prepare query");
         client
-            .query(&stmt, &[&path])
+            .query(&stmt, &[&site, &path])
             .await
             .expect("failed to get path count")
             .pop()
@@ -333,6 +496,304 @@ impl Conn for PgConn {
             total_requests: result.get("total_requests"),
         }
     }
+
+    async fn new_deploy(
+        &self,
+        site: &str,
+        preview: Option<&str>,
+        trigger: &str,
+        started_at: i64,
+    ) -> i64 {
+        let client = self.db.get().await.expect("failed to get client");
+        let stmt = r#"
+                INSERT INTO deploys (site, preview, trigger, started_at)
+                VALUES ($1, $2, $3, $4)
+                RETURNING did;"#;
+        let stmt = client.prepare(stmt).await.expect("failed to prepare query");
+        client
+            .query(&stmt, &[&site, &preview, &trigger, &started_at])
+            .await
+            .expect("failed to insert deploy")
+            .pop()
+            .expect("did not return did")
+            .get("did")
+    }
+
+    async fn finish_deploy(&self, did: i64, outcome: &DeployOutcome, finished_at: i64) {
+        let status = match outcome.is_success() {
+            true => "success",
+            false => "failed",
+        };
+        let client = self.db.get().await.expect("failed to get client");
+        let stmt = r#"
+                UPDATE deploys
+                SET status = $2, commit_id = $3, commit_message = $4, finished_at = $5,
+                    build_log = $6, error = $7
+                WHERE did = $1;"#;
+        let stmt = client.prepare(stmt).await.expect("failed to prepare query");
+        client
+            .query(
+                &stmt,
+                &[
+                    &did,
+                    &status,
+                    &outcome.commit_id,
+                    &outcome.commit_message,
+                    &finished_at,
+                    &outcome.build_log,
+                    &outcome.error,
+                ],
+            )
+            .await
+            .expect("failed to update deploy");
+    }
+
+    async fn get_deploys(&self, site: &str, limit: i64, ofset: i64) -> Vec<Deploy> {
+        let ofset = ofset * limit;
+        let client = self.db.get().await.expect("failed to get client");
+        let stmt = r#"
+                SELECT * FROM deploys
+                WHERE site = $1
+                ORDER BY did DESC
+                LIMIT $2 OFFSET $3;"#;
+        let stmt = client.prepare(stmt).await.expect("failed to prepare query");
+        client
+            .query(&stmt, &[&site, &limit, &ofset])
+            .await
+            .expect("failed to get deploys")
+            .iter()
+            .map(|x| x.into())
+            .collect()
+    }
+
+    async fn get_deploy(&self, did: i64) -> Option<Deploy> {
+        let client = self.db.get().await.expect("failed to get client");
+        let stmt = r#"
+                SELECT * FROM deploys where did = $1;"#;
+        let stmt = client.prepare(stmt).await.expect("failed to prepare query");
+        client
+            .query(&stmt, &[&did])
+            .await
+            .expect("failed to get deploy")
+            .pop()
+            .map(|x| (&x).into())
+    }
+
+    async fn new_session(
+        &self,
+        token_hash: &str,
+        username: &str,
+        created_at: i64,
+        expires_at: i64,
+    ) {
+        let client = self.db.get().await.expect("failed to get client");
+        let stmt = r#"
+                DELETE FROM sessions WHERE expires_at < $1;"#;
+        let stmt = client.prepare(stmt).await.expect("failed to prepare query");
+        client
+            .query(&stmt, &[&created_at])
+            .await
+            .expect("failed to delete expired sessions");
+
+        let stmt = r#"
+                INSERT INTO sessions (token_hash, username, created_at, expires_at)
+                VALUES ($1, $2, $3, $4);"#;
+        let stmt = client.prepare(stmt).await.expect("failed to prepare query");
+        client
+            .query(&stmt, &[&token_hash, &username, &created_at, &expires_at])
+            .await
+            .expect("failed to insert session");
+    }
+
+    async fn get_session(&self, token_hash: &str, current_time: i64) -> Option<String> {
+        let client = self.db.get().await.expect("failed to get client");
+        let stmt = r#"
+                SELECT username FROM sessions WHERE token_hash = $1 AND expires_at > $2;"#;
+        let stmt = client.prepare(stmt).await.expect("failed to prepare query");
+        client
+            .query(&stmt, &[&token_hash, &current_time])
+            .await
+            .expect("failed to get session")
+            .pop()
+            .map(|x| x.get("username"))
+    }
+
+    async fn delete_session(&self, token_hash: &str) {
+        let client = self.db.get().await.expect("failed to get client");
+        let stmt = r#"
+                DELETE FROM sessions WHERE token_hash = $1;"#;
+        let stmt = client.prepare(stmt).await.expect("failed to prepare query");
+        client
+            .query(&stmt, &[&token_hash])
+            .await
+            .expect("failed to delete session");
+    }
+
+    async fn import_requests<I>(
+        &self,
+        site: &str,
+        source_hash: &str,
+        name: &str,
+        mut requests: I,
+        imported_at: i64,
+    ) -> Result<Option<u64>, String>
+    where
+        I: Iterator<Item = Result<AnalyticsRequest, String>> + Send,
+    {
+        let mut client = self.db.get().await.expect("failed to get client");
+        let transaction = client
+            .transaction()
+            .await
+            .expect("failed to begin transaction");
+        let stmt = r#"
+                INSERT INTO imports (site, source_hash, name, requests, imported_at)
+                VALUES ($1, $2, $3, 0, $4)
+                ON CONFLICT DO NOTHING;"#;
+        let inserted = transaction
+            .execute(stmt, &[&site, &source_hash, &name, &imported_at])
+            .await
+            .expect("failed to record import");
+        if inserted == 0 {
+            return Ok(None);
+        }
+
+        let mut count = 0;
+        let mut touched: HashSet<(String, String)> = HashSet::new();
+        loop {
+            // dropping the transaction on an error rolls it back
+            let batch = requests
+                .by_ref()
+                .take(IMPORT_BATCH)
+                .collect::<Result<Vec<_>, _>>()?;
+            if batch.is_empty() {
+                break;
+            }
+            let sites: Vec<&str> = batch.iter().map(|x| x.site.as_str()).collect();
+            let paths: Vec<&str> = batch.iter().map(|x| x.path.as_str()).collect();
+            let ips: Vec<&str> = batch.iter().map(|x| x.hashed_ip.as_str()).collect();
+            let times: Vec<i64> = batch.iter().map(|x| x.created_at_milis).collect();
+            let stmt = r#"
+                INSERT INTO visitors (ip_address_hash)
+                SELECT DISTINCT unnest($1::text[])
+                ON CONFLICT DO NOTHING;"#;
+            transaction
+                .execute(stmt, &[&ips])
+                .await
+                .expect("failed to insert visitors");
+            let stmt = r#"
+                INSERT INTO paths (site, path)
+                SELECT DISTINCT * FROM unnest($1::text[], $2::text[])
+                ON CONFLICT DO NOTHING;"#;
+            transaction
+                .execute(stmt, &[&sites, &paths])
+                .await
+                .expect("failed to insert paths");
+            let stmt = r#"
+                INSERT INTO requests (uid, pid, created_at)
+                SELECT visitors.uid, paths.pid, r.created_at
+                FROM unnest($1::text[], $2::text[], $3::text[], $4::bigint[])
+                    AS r(site, path, ip_address_hash, created_at)
+                JOIN visitors ON visitors.ip_address_hash = r.ip_address_hash
+                JOIN paths ON paths.site = r.site AND paths.path = r.path;"#;
+            transaction
+                .execute(stmt, &[&sites, &paths, &ips, &times])
+                .await
+                .expect("failed to insert requests");
+            count += batch.len() as i64;
+            touched.extend(batch.into_iter().map(|x| (x.site, x.path)));
+        }
+
+        let stmt = "UPDATE imports SET requests = $3 WHERE site = $1 AND source_hash = $2;";
+        transaction
+            .execute(stmt, &[&site, &source_hash, &count])
+            .await
+            .expect("failed to record import");
+        // counted from scratch rather than incremented, the visitors in the
+        // file may or may not have been seen on a path before
+        let (sites, paths): (Vec<String>, Vec<String>) = touched.into_iter().unzip();
+        let stmt = r#"
+                UPDATE paths SET
+                    total_requests = (SELECT COUNT(*) FROM requests WHERE requests.pid = paths.pid),
+                    unique_visitors = (SELECT COUNT(DISTINCT uid) FROM requests WHERE requests.pid = paths.pid)
+                WHERE (site, path) IN (SELECT * FROM unnest($1::text[], $2::text[]));"#;
+        transaction
+            .execute(stmt, &[&sites, &paths])
+            .await
+            .expect("failed to recount paths");
+        transaction
+            .commit()
+            .await
+            .expect("failed to commit transaction");
+        Ok(Some(count as u64))
+    }
+
+    async fn export_requests(
+        &self,
+        site: &str,
+        from: i64,
+        to: i64,
+        first_visits: bool,
+    ) -> BoxStream<'static, Vec<ExportedRequest>> {
+        // one lookup per row on requests_pid_created_at, only paid when asked for
+        let first_visit = match first_visits {
+            true => {
+                r#"NOT EXISTS (
+                    SELECT 1 FROM requests AS earlier
+                    WHERE earlier.pid = requests.pid AND earlier.uid = requests.uid
+                        AND earlier.created_at < requests.created_at
+                )"#
+            }
+            false => "false",
+        };
+        let stmt = format!(
+            r#"
+                SELECT paths.site, paths.path, visitors.ip_address_hash, requests.created_at,
+                    {} AS first_visit
+                FROM requests
+                JOIN paths ON paths.pid = requests.pid
+                JOIN visitors ON visitors.uid = requests.uid
+                WHERE paths.site = $1 AND requests.created_at >= $2 AND requests.created_at < $3
+                ORDER BY requests.created_at"#,
+            first_visit
+        );
+        self.stream_query(&stmt, &[&site, &from, &to], |x| ExportedRequest::from(x))
+            .await
+    }
+
+    async fn export_daily_counts(
+        &self,
+        site: &str,
+        from: i64,
+        to: i64,
+        timezone: &str,
+    ) -> BoxStream<'static, Vec<DailyPathCount>> {
+        let stmt = r#"
+                SELECT paths.site, paths.path,
+                    to_char(to_timestamp(requests.created_at / 1000.0) AT TIME ZONE $4, 'YYYY-MM-DD') AS day,
+                    COUNT(*) AS total_requests,
+                    COUNT(DISTINCT requests.uid) AS unique_visitors
+                FROM requests
+                JOIN paths ON paths.pid = requests.pid
+                WHERE paths.site = $1 AND requests.created_at >= $2 AND requests.created_at < $3
+                GROUP BY paths.site, paths.path, day
+                ORDER BY day, paths.path"#;
+        self.stream_query(stmt, &[&site, &from, &to, &timezone], |x| {
+            DailyPathCount::from(x)
+        })
+        .await
+    }
+}
+
+/// a zero for every bucket between `bounds`
+fn empty_timeline(bounds: &[i64]) -> Vec<Graphnode> {
+    bounds
+        .windows(2)
+        .map(|x| Graphnode {
+            amount: 0,
+            timestamp_start: x[0],
+            timestamp_end: x[1],
+        })
+        .collect()
 }
 
 pub async fn init(conn: &PgConn) -> Result<(), String> {
@@ -374,3 +835,193 @@ impl From<&Row> for Path {
         }
     }
 }
+
+impl From<&Row> for Deploy {
+    fn from(value: &Row) -> Self {
+        Deploy {
+            did: value.get("did"),
+            site: value.get("site"),
+            preview: value.get("preview"),
+            trigger: value.get("trigger"),
+            status: value.get("status"),
+            commit_id: value.get("commit_id"),
+            commit_message: value.get("commit_message"),
+            started_at: value.get("started_at"),
+            finished_at: value.get("finished_at"),
+            build_log: value.get("build_log"),
+            error: value.get("error"),
+        }
+    }
+}
+
+impl From<&Row> for ExportedRequest {
+    fn from(value: &Row) -> Self {
+        ExportedRequest {
+            site: value.get("site"),
+            path: value.get("path"),
+            ip_address_hash: value.get("ip_address_hash"),
+            created_at: value.get("created_at"),
+            first_visit: value.get("first_visit"),
+        }
+    }
+}
+
+impl From<&Row> for DailyPathCount {
+    fn from(value: &Row) -> Self {
+        DailyPathCount {
+            site: value.get("site"),
+            path: value.get("path"),
+            day: value.get("day"),
+            total_requests: value.get("total_requests"),
+            unique_visitors: value.get("unique_visitors"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDb;
+
+    async fn request(conn: &PgConn, site: &str, path: &str, visitor: &str, created_at: i64) {
+        conn.new_request(AnalyticsRequest {
+            site: site.to_string(),
+            hashed_ip: visitor.to_string(),
+            path: path.to_string(),
+            created_at_milis: created_at,
+        })
+        .await;
+    }
+
+    fn amounts(nodes: &[Graphnode]) -> Vec<u32> {
+        nodes.iter().map(|x| x.amount).collect()
+    }
+
+    #[actix_web::test]
+    async fn compares_periods_like_for_like() {
+        let Some(db) = TestDb::new("period").await else {
+            return;
+        };
+        let conn = &db.conn;
+        for (path, visitor, created_at) in [
+            ("/", "a", -150),
+            ("/", "a", -120),
+            ("/blog", "b", -50),
+            ("/", "a", 10),
+            ("/", "b", 20),
+            ("/blog", "a", 120),
+            ("/", "c", 180),
+            // the end of a period isn't part of it
+            ("/", "c", 200),
+        ] {
+            request(conn, "ivytime.gay", path, visitor, created_at).await;
+        }
+        request(conn, "other.test", "/", "a", 10).await;
+        let bounds = [0, 100, 200];
+        let previous = [-200, -100, 0];
+
+        let whole = conn
+            .get_period_comparison("ivytime.gay", None, &bounds, Some(&previous), i64::MAX)
+            .await;
+        assert_eq!(amounts(&whole.current.total), [2, 2]);
+        assert_eq!(amounts(&whole.current.unique), [2, 2]);
+        assert_eq!(
+            (whole.current.total_requests, whole.current.unique_visitors),
+            (4, 3)
+        );
+        let before = whole.previous.unwrap();
+        assert_eq!(amounts(&before.total), [2, 1]);
+        assert_eq!((before.total_requests, before.unique_visitors), (3, 2));
+
+        // halfway through, both periods only count their first half but the
+        // graphs stay whole
+        let halfway = conn
+            .get_period_comparison("ivytime.gay", None, &bounds, Some(&previous), 150)
+            .await;
+        assert_eq!(amounts(&halfway.current.total), [2, 2]);
+        assert_eq!(
+            (
+                halfway.current.total_requests,
+                halfway.current.unique_visitors
+            ),
+            (3, 2)
+        );
+        // which leaves out the request at -50
+        let before = halfway.previous.unwrap();
+        assert_eq!((before.total_requests, before.unique_visitors), (2, 1));
+        let start = conn
+            .get_period_comparison("ivytime.gay", None, &bounds, Some(&previous), 0)
+            .await;
+        assert_eq!(start.current.total_requests, 0);
+        assert_eq!(start.previous.unwrap().total_requests, 0);
+
+        // a single path, and no comparison
+        let pid = conn.get_pid("ivytime.gay", "/blog").await.unwrap();
+        let blog = conn
+            .get_period_comparison("ivytime.gay", Some(pid), &bounds, None, i64::MAX)
+            .await;
+        assert_eq!(amounts(&blog.current.total), [0, 1]);
+        assert_eq!(blog.current.total_requests, 1);
+        assert!(blog.previous.is_none());
+    }
+
+    #[actix_web::test]
+    async fn top_paths_rank_visitors_then_requests() {
+        let Some(db) = TestDb::new("top_paths").await else {
+            return;
+        };
+        let conn = &db.conn;
+        for (path, visitor, created_at) in [
+            // most requests, but from one visitor
+            ("/a", "x", 10),
+            ("/a", "x", 11),
+            ("/a", "x", 12),
+            ("/a", "x", 13),
+            ("/b", "x", 10),
+            ("/b", "y", 11),
+            ("/c", "x", 10),
+            ("/c", "y", 11),
+            ("/c", "y", 12),
+            // ties fall back to the path
+            ("/e", "z", 10),
+            ("/d", "z", 10),
+            // outside the range
+            ("/old", "x", 5),
+            ("/old", "y", 5),
+            ("/old", "z", 5),
+            ("/new", "x", 100),
+        ] {
+            request(conn, "ivytime.gay", path, visitor, created_at).await;
+        }
+        for visitor in ["x", "y", "z"] {
+            request(conn, "other.test", "/other", visitor, 10).await;
+        }
+
+        let top = conn.get_top_paths("ivytime.gay", 10, 100, 10).await;
+        let ranked: Vec<_> = top
+            .iter()
+            .map(|x| (x.path.as_str(), x.total_unique, x.total_requests))
+            .collect();
+        assert_eq!(
+            ranked,
+            [
+                ("/c", 2, 3),
+                ("/b", 2, 2),
+                ("/a", 1, 4),
+                ("/d", 1, 1),
+                ("/e", 1, 1),
+            ]
+        );
+        let top = conn.get_top_paths("ivytime.gay", 10, 100, 2).await;
+        let paths: Vec<_> = top.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(paths, ["/c", "/b"]);
+        assert!(conn
+            .get_top_paths("ivytime.gay", 10, 100, 0)
+            .await
+            .is_empty());
+        assert!(conn
+            .get_top_paths("ivytime.gay", 200, 300, 10)
+            .await
+            .is_empty());
+    }
+}
@@ -0,0 +1,813 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Read,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::web;
+use git2::Repository;
+use lazy_static::lazy_static;
+
+use crate::{
+    compress::{precompress_dir, sibling_path},
+    config::{preview_host, preview_slug, SiteConfig},
+    db::{conn::Conn, pg::PgConn},
+    notify::{notify, DeployEvent, DeployNotification},
+    pull::{
+        checkout_detached, do_clone, do_fetch, do_merge, fetch_all, latest_tag, remote_branches,
+        resolve_pinned, update_submodules, GitCredentials, RefSelector,
+    },
+    redirects::{load_rules, set_rules},
+};
+
+const DEFAULT_BUILD_TIMEOUT_SECS: u64 = 300;
+
+lazy_static! {
+    /// webhooks can arrive while a deploy is still running, only one may touch
+    /// a site's checkout at a time. keyed by domain
+    static ref DEPLOY_LOCKS: Mutex<HashMap<String, Arc<Mutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+fn site_lock(domain: &str) -> Arc<Mutex<()>> {
+    DEPLOY_LOCKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(domain.to_string())
+        .or_default()
+        .clone()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DeployTrigger {
+    Startup,
+    Webhook,
+    Poll,
+    /// a rollback asked for from the dashboard or api
+    Manual,
+}
+
+impl DeployTrigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeployTrigger::Startup => "startup",
+            DeployTrigger::Webhook => "webhook",
+            DeployTrigger::Poll => "poll",
+            DeployTrigger::Manual => "manual",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DeployOutcome {
+    pub commit_id: Option<String>,
+    /// first line of the commit message
+    pub commit_message: Option<String>,
+    pub build_log: Option<String>,
+    pub error: Option<String>,
+    /// the deploy was published but failed its checks, so the site went back
+    /// to the version before
+    pub rolled_back: bool,
+}
+
+impl DeployOutcome {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+pub struct BuildOutput {
+    pub success: bool,
+    pub log: String,
+}
+
+/// pulls the latest changes, runs the build and publishes the result,
+/// recording the whole thing in the deploy history
+pub async fn deploy(site: &SiteConfig, conn: &PgConn, trigger: DeployTrigger) -> DeployOutcome {
+    let started_at = current_time_milis();
+    // previews are listed with the site they preview
+    let (record_as, preview) = match &site.preview_of {
+        Some(parent) => (parent.as_str(), Some(site.branch.as_str())),
+        None => (site.domain.as_str(), None),
+    };
+    let did = conn
+        .new_deploy(record_as, preview, trigger.as_str(), started_at)
+        .await;
+    notify(
+        &site.notifications,
+        DeployNotification::new(DeployEvent::Start, site, trigger),
+    );
+
+    let task_site = site.clone();
+    let outcome = match web::block(move || {
+        let lock = site_lock(&task_site.domain);
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        run_deploy(&task_site)
+    })
+    .await
+    {
+        Ok(x) => x,
+        Err(e) => DeployOutcome {
+            error: Some(format!("deploy task failed: {}", e)),
+            ..Default::default()
+        },
+    };
+
+    match &outcome.error {
+        Some(err) => println!("{}: deploy failed: {}", site.domain, err),
+        None => println!(
+            "{}: deployed {}",
+            site.domain,
+            outcome.commit_id.as_deref().unwrap_or("unknown commit")
+        ),
+    }
+    let finished_at = current_time_milis();
+    conn.finish_deploy(did, &outcome, finished_at).await;
+
+    let mut notification = DeployNotification::new(DeployEvent::Success, site, trigger);
+    notification.commit_id = outcome.commit_id.clone();
+    notification.commit_message = outcome.commit_message.clone();
+    notification.duration_milis = Some(finished_at - started_at);
+    notification.error = outcome.error.clone();
+    if !outcome.is_success() {
+        notification.event = DeployEvent::Failure;
+    }
+    if outcome.rolled_back {
+        notify(&site.notifications, notification.clone());
+        notification.event = DeployEvent::Rollback;
+    }
+    notify(&site.notifications, notification);
+    outcome
+}
+
+/// puts the site back on the version it served before its last deploy
+pub async fn rollback_site(site: &SiteConfig, trigger: DeployTrigger) -> Result<(), String> {
+    let task_site = site.clone();
+    web::block(move || {
+        let lock = site_lock(&task_site.domain);
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        rollback(&task_site.live_path())
+    })
+    .await
+    .map_err(|e| format!("rollback task failed: {}", e))??;
+    println!("{}: rolled back to the previous deploy", site.domain);
+    notify(
+        &site.notifications,
+        DeployNotification::new(DeployEvent::Rollback, site, trigger),
+    );
+    Ok(())
+}
+
+fn run_deploy(site: &SiteConfig) -> DeployOutcome {
+    let mut outcome = DeployOutcome::default();
+
+    match git_refresh(site) {
+        Ok((commit_id, commit_message)) => {
+            outcome.commit_id = Some(commit_id);
+            outcome.commit_message = Some(commit_message);
+        }
+        Err(e) => {
+            outcome.error = Some(e);
+            return outcome;
+        }
+    }
+    if let Err(e) = git_update_submodules(site) {
+        outcome.error = Some(e);
+        return outcome;
+    }
+
+    if let Some(command) = &site.build_command {
+        let timeout = Duration::from_secs(
+            site.build_timeout_secs
+                .unwrap_or(DEFAULT_BUILD_TIMEOUT_SECS),
+        );
+        match run_build(command, &site.clone_path(), timeout) {
+            Ok(build) => {
+                outcome.build_log = Some(build.log);
+                if !build.success {
+                    outcome.error = Some("build failed, keeping the previous deploy".to_string());
+                    return outcome;
+                }
+            }
+            Err(e) => {
+                outcome.error = Some(e);
+                return outcome;
+            }
+        }
+    }
+
+    let rules = match load_rules(&site.publish_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            outcome.error = Some(format!("invalid redirect or header rules:\n{}", e));
+            return outcome;
+        }
+    };
+    let version = match publish(&site.publish_path(), &site.live_path(), site.precompress) {
+        Ok(x) => x,
+        Err(e) => {
+            outcome.error = Some(e);
+            return outcome;
+        }
+    };
+    set_rules(&version, rules);
+    // a build that put its output somewhere else leaves an empty site behind
+    if !site.show_files_listing && !version.join(&site.index_file).is_file() {
+        let error = format!("the published site has no {}", site.index_file);
+        match rollback(&site.live_path()) {
+            Ok(_) => {
+                outcome.error = Some(format!("{}, rolled back to the previous deploy", error));
+                outcome.rolled_back = true;
+            }
+            Err(e) => outcome.error = Some(format!("{}, and couldn't roll back: {}", error, e)),
+        }
+    }
+    outcome
+}
+
+/// fetches whatever the configured [`RefSelector`] points at and returns
+/// the commit that should be deployed, without touching the working tree
+fn fetch_selected(
+    repo: &Repository,
+    selector: &RefSelector,
+    credentials: &GitCredentials,
+) -> Result<git2::Oid, String> {
+    let Ok(mut remote) = repo.find_remote("origin") else {
+        return Err("failed to find remote".to_string());
+    };
+    let fetched = match selector {
+        RefSelector::Branch(branch) => {
+            do_fetch(repo, &[branch], &mut remote, credentials).map(|x| x.id())
+        }
+        RefSelector::LatestTag(pattern, prereleases) => fetch_all(&mut remote, credentials)
+            .and_then(|_| latest_tag(repo, pattern, *prereleases)),
+        RefSelector::Pinned(name) => {
+            fetch_all(&mut remote, credentials).and_then(|_| resolve_pinned(repo, name))
+        }
+    };
+    fetched.map_err(|e| format!("failed to fetch commit: {}", e))
+}
+
+/// brings the checkout up to date with the configured ref, cloning first if
+/// needed. branches are merged like `git pull`, tags and pinned refs are
+/// checked out directly. returns the id and summary of the commit now checked out
+pub fn git_refresh(site: &SiteConfig) -> Result<(String, String), String> {
+    let credentials = site.git_credentials();
+    let repo = match Repository::open(site.clone_path()) {
+        Ok(repo) => repo,
+        Err(_e) => match do_clone(&site.site_repo, &site.clone_path(), &credentials) {
+            Ok(repo) => repo,
+            Err(e) => return Err(format!("failed to clone: {}", e)),
+        },
+    };
+
+    let selector = site.ref_selector();
+    let target = fetch_selected(&repo, &selector, &credentials)?;
+    let res = match &selector {
+        RefSelector::Branch(branch) => repo
+            .find_annotated_commit(target)
+            .and_then(|fetch_commit| do_merge(&repo, branch, fetch_commit)),
+        _ => checkout_detached(&repo, target),
+    };
+    if let Err(err) = res {
+        return Err(err.to_string());
+    }
+
+    let Some(commit) = repo.head().ok().and_then(|x| x.peel_to_commit().ok()) else {
+        return Err("checkout has no HEAD commit".to_string());
+    };
+    let summary = commit.summary().unwrap_or_default().to_string();
+    Ok((commit.id().to_string(), summary))
+}
+
+/// brings every submodule in the checkout in line with the commit that was just checked out
+pub fn git_update_submodules(site: &SiteConfig) -> Result<(), String> {
+    let repo = Repository::open(site.clone_path())
+        .map_err(|e| format!("failed to open checkout: {}", e))?;
+    update_submodules(&repo, &site.git_credentials())
+        .map_err(|e| format!("failed to update submodules:\n{}", e))
+}
+
+/// fetches the configured ref without checking it out, returning whether the
+/// fetched commit isn't checked out yet. since every deploy checks out what it
+/// fetched, this only reports true once per new commit even if its build fails
+pub async fn has_new_commit(site: &SiteConfig) -> Result<bool, String> {
+    let site = site.clone();
+    web::block(move || {
+        let lock = site_lock(&site.domain);
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        let repo = match Repository::open(site.clone_path()) {
+            Ok(repo) => repo,
+            // never cloned, the deploy will take care of it
+            Err(_) => return Ok(true),
+        };
+        let target = fetch_selected(&repo, &site.ref_selector(), &site.git_credentials())?;
+        Ok(!is_checked_out(&repo, target))
+    })
+    .await
+    .map_err(|e| format!("poll task failed: {}", e))?
+}
+
+/// whether `target` is HEAD or was merged into it. a branch that can't be
+/// fast forwarded gets a merge commit on top, which never equals the fetched tip
+fn is_checked_out(repo: &Repository, target: git2::Oid) -> bool {
+    let Some(head) = repo.head().ok().and_then(|x| x.target()) else {
+        return false;
+    };
+    head == target || repo.graph_descendant_of(head, target).unwrap_or(false)
+}
+
+/// deploys a preview of every remote branch matching `preview_branches` whose
+/// tip moved since it was last deployed, and deletes previews of branches that
+/// no longer exist. does nothing unless `preview_domain` is set
+pub async fn sync_previews(site: &SiteConfig, conn: &PgConn, trigger: DeployTrigger) {
+    if site.preview_domain.is_none() {
+        return;
+    }
+    let task_site = site.clone();
+    let (outdated, stale) = match web::block(move || plan_previews(&task_site)).await {
+        Ok(Ok(x)) => x,
+        Ok(Err(e)) => {
+            println!("{}: failed to sync previews: {}", site.domain, e);
+            return;
+        }
+        Err(e) => {
+            println!("{}: preview task failed: {}", site.domain, e);
+            return;
+        }
+    };
+
+    for branch in outdated {
+        if let Some(preview) = site.preview_site(&branch) {
+            deploy(&preview, conn, trigger).await;
+        }
+    }
+    if stale.is_empty() {
+        return;
+    }
+    let preview_path = site.preview_path();
+    let preview_domain = site.preview_domain.clone().unwrap_or_default();
+    let _ = web::block(move || {
+        for slug in stale {
+            // a deploy of the same preview could still be writing to it
+            let lock = site_lock(&preview_host(&slug, &preview_domain));
+            let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+            let dir = preview_path.join(&slug);
+            println!("removing preview {}", dir.display());
+            if let Err(e) = fs::remove_dir_all(&dir) {
+                println!("failed to remove preview {}: {}", dir.display(), e);
+            }
+        }
+    })
+    .await;
+}
+
+/// fetches every branch into the site's checkout, returning the branches
+/// whose preview needs deploying and the slugs of previews that can go
+fn plan_previews(site: &SiteConfig) -> Result<(Vec<String>, Vec<String>), String> {
+    let lock = site_lock(&site.domain);
+    let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let repo = Repository::open(site.clone_path())
+        .map_err(|e| format!("failed to open checkout: {}", e))?;
+    let Ok(mut remote) = repo.find_remote("origin") else {
+        return Err("failed to find remote".to_string());
+    };
+    fetch_all(&mut remote, &site.git_credentials())
+        .map_err(|e| format!("failed to fetch branches: {}", e))?;
+    let branches = remote_branches(&repo).map_err(|e| format!("failed to list branches: {}", e))?;
+
+    let matcher = globset::Glob::new(site.preview_branches.as_deref().unwrap_or("*"))
+        .map_err(|e| format!("invalid preview_branches pattern: {}", e))?
+        .compile_matcher();
+
+    let mut outdated = Vec::new();
+    let mut slugs = HashSet::new();
+    for (branch, oid) in branches {
+        if !matcher.is_match(&branch) {
+            continue;
+        }
+        slugs.insert(preview_slug(&branch));
+        let Some(preview) = site.preview_site(&branch) else {
+            continue;
+        };
+        let deployed = Repository::open(preview.clone_path())
+            .ok()
+            .and_then(|x| x.head().ok().and_then(|x| x.target()));
+        if deployed != Some(oid) {
+            outdated.push(branch);
+        }
+    }
+
+    let mut stale = Vec::new();
+    if let Ok(entries) = fs::read_dir(site.preview_path()) {
+        for entry in entries.flatten() {
+            let slug = entry.file_name().to_string_lossy().to_string();
+            if !slugs.contains(&slug) {
+                stale.push(slug);
+            }
+        }
+    }
+    Ok((outdated, stale))
+}
+
+/// runs `command` through the shell inside `dir`, killing it if it runs past `timeout`
+pub fn run_build(command: &str, dir: &Path, timeout: Duration) -> Result<BuildOutput, String> {
+    println!("running build: {}", command);
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // own process group so a timeout also takes out anything the build spawned
+        .process_group(0)
+        .spawn()
+        .map_err(|e| format!("failed to start build: {}", e))?;
+
+    // read both pipes on their own threads so a chatty build can't fill one up and stall
+    let stdout = child.stdout.take().map(read_pipe);
+    let stderr = child.stderr.take().map(read_pipe);
+
+    let start = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) if start.elapsed() > timeout => {
+                unsafe {
+                    libc::kill(-(child.id() as i32), libc::SIGKILL);
+                }
+                let _ = child.wait();
+                break None;
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(100)),
+            Err(e) => return Err(format!("failed to wait on build: {}", e)),
+        }
+    };
+
+    let stdout = stdout.and_then(|x| x.join().ok()).unwrap_or_default();
+    let stderr = stderr.and_then(|x| x.join().ok()).unwrap_or_default();
+    let mut log = format!(
+        "$ {}\n\n--- stdout ---\n{}\n--- stderr ---\n{}\n",
+        command, stdout, stderr
+    );
+
+    let success = match status {
+        Some(status) => {
+            log.push_str(&format!("\n{}\n", status));
+            status.success()
+        }
+        None => {
+            log.push_str(&format!("\nkilled after {}s timeout\n", timeout.as_secs()));
+            false
+        }
+    };
+    Ok(BuildOutput { success, log })
+}
+
+fn read_pipe(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        String::from_utf8_lossy(&buf).to_string()
+    })
+}
+
+/// copies `source` into a new version next to `live`, then points the `live`
+/// symlink at it with a single rename so requests never see a half copied
+/// site, or no site at all. the version before is kept for requests still
+/// reading from it and older ones are removed. every file is a fresh copy, so
+/// the strong etags `Files` derives from inode and mtime change with each
+/// deploy. with `precompress` the copy also gets `.br` and `.gz` siblings for
+/// its text assets before going live. returns the version now being served
+pub fn publish(source: &Path, live: &Path, precompress: bool) -> Result<PathBuf, String> {
+    if !source.is_dir() {
+        return Err(format!("{} does not exist", source.display()));
+    }
+    let versions = sibling_path(live, "versions");
+    let version = versions.join(current_time_milis().to_string());
+    let _ = fs::remove_dir_all(&version);
+
+    let copied = source
+        .canonicalize()
+        .and_then(|root| copy_dir(&root, &root, &version))
+        .map_err(|e| format!("failed to copy site: {}", e))
+        .and_then(|_| match precompress {
+            true => {
+                precompress_dir(&version).map_err(|e| format!("failed to precompress site: {}", e))
+            }
+            false => Ok(()),
+        });
+    if let Err(e) = copied {
+        // a partial copy would otherwise count as one of the kept versions
+        let _ = fs::remove_dir_all(&version);
+        return Err(e);
+    }
+    // sites published before versions were kept have a plain directory here,
+    // which a symlink can't replace. it's moved aside once, the only time
+    // there's a moment without a live site
+    if live.is_dir() && !live.is_symlink() {
+        fs::rename(live, versions.join("0"))
+            .map_err(|e| format!("failed to move old site: {}", e))?;
+    }
+    link_live(live, &version)?;
+
+    remove_old_versions(&versions, 2);
+    version
+        .canonicalize()
+        .map_err(|e| format!("failed to find published site: {}", e))
+}
+
+/// points `live` back at the version published before the one it links to,
+/// removing the versions after it. returns the version now being served
+pub fn rollback(live: &Path) -> Result<PathBuf, String> {
+    let current = live
+        .canonicalize()
+        .map_err(|e| format!("nothing is published: {}", e))?;
+    let found = list_versions(&sibling_path(live, "versions"));
+    let Some(i) = found
+        .iter()
+        .position(|dir| dir.canonicalize().is_ok_and(|x| x == current))
+    else {
+        return Err("the live dir isn't one of the kept versions".to_string());
+    };
+    let Some(previous) = i.checked_sub(1).map(|x| &found[x]) else {
+        return Err("there's no earlier version to roll back to".to_string());
+    };
+    link_live(live, previous)?;
+    for dir in &found[i..] {
+        if let Err(e) = fs::remove_dir_all(dir) {
+            println!("failed to remove version {}: {}", dir.display(), e);
+        }
+    }
+    previous
+        .canonicalize()
+        .map_err(|e| format!("failed to find published site: {}", e))
+}
+
+/// swaps the `live` symlink over to `version` in one step
+fn link_live(live: &Path, version: &Path) -> Result<(), String> {
+    let link = sibling_path(live, "link");
+    let _ = fs::remove_file(&link);
+    // relative, so the live dir can be moved along with its versions
+    let versions = sibling_path(live, "versions");
+    let target = Path::new(versions.file_name().expect("versions dir has a name"))
+        .join(version.file_name().expect("version dir has a name"));
+    std::os::unix::fs::symlink(&target, &link)
+        .map_err(|e| format!("failed to link new site: {}", e))?;
+    fs::rename(&link, live).map_err(|e| format!("failed to publish site: {}", e))
+}
+
+/// the versions in `versions`, oldest first
+fn list_versions(versions: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(versions) else {
+        return Vec::new();
+    };
+    let mut found: Vec<(i64, PathBuf)> = entries
+        .flatten()
+        .filter_map(|x| Some((x.file_name().to_str()?.parse().ok()?, x.path())))
+        .collect();
+    found.sort();
+    found.into_iter().map(|(_, dir)| dir).collect()
+}
+
+/// removes all but the `keep` newest versions in `versions`
+fn remove_old_versions(versions: &Path, keep: usize) {
+    for dir in list_versions(versions).iter().rev().skip(keep) {
+        if let Err(e) = fs::remove_dir_all(dir) {
+            println!("failed to remove old version {}: {}", dir.display(), e);
+        }
+    }
+}
+
+/// copies `source`, somewhere within `root`, to `dest`. symlinks are copied
+/// as links, and only if they point somewhere within `root` so a build can't
+/// publish files from elsewhere on the server
+fn copy_dir(root: &Path, source: &Path, dest: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            let linked = entry.path().canonicalize().map_err(|e| {
+                std::io::Error::other(format!("broken symlink {}: {}", entry.path().display(), e))
+            })?;
+            if !linked.starts_with(root) {
+                return Err(std::io::Error::other(format!(
+                    "symlink {} points outside the publish dir",
+                    entry.path().display()
+                )));
+            }
+            // relative so it points into the copy rather than the checkout
+            let link = relative_path(&source.canonicalize()?, &linked);
+            std::os::unix::fs::symlink(link, target)?;
+        } else if file_type.is_dir() {
+            copy_dir(root, &entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// the path to `to` from the directory `from`, both absolute
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for component in &to[common..] {
+        path.push(component);
+    }
+    path
+}
+
+pub fn current_time_milis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pull::{do_clone, do_fetch, do_merge, GitCredentials},
+        test_util::{commit_files, file_url, init_repo, write_file, TempDir},
+    };
+
+    #[test]
+    fn publish_swaps_a_symlink_and_keeps_one_old_version() {
+        let dir = TempDir::new("publish");
+        let source = dir.path().join("public");
+        let live = dir.path().join("live");
+        // a site published before versions were kept
+        write_file(&live.join("index.html"), "legacy");
+
+        for contents in ["first", "second", "third"] {
+            write_file(&source.join("index.html"), contents);
+            let version = publish(&source, &live, false).expect("failed to publish");
+            assert!(live.is_symlink());
+            assert_eq!(live.canonicalize().unwrap(), version);
+            assert_eq!(
+                fs::read_to_string(live.join("index.html")).unwrap(),
+                contents
+            );
+            // versions are named by the time they were published
+            thread::sleep(Duration::from_millis(2));
+        }
+        let versions = fs::read_dir(sibling_path(&live, "versions"))
+            .unwrap()
+            .count();
+        assert_eq!(versions, 2);
+    }
+
+    #[test]
+    fn rollback_goes_back_one_version() {
+        let dir = TempDir::new("rollback");
+        let source = dir.path().join("public");
+        let live = dir.path().join("live");
+        assert!(rollback(&live).is_err());
+
+        let mut published = Vec::new();
+        for contents in ["first", "second"] {
+            write_file(&source.join("index.html"), contents);
+            published.push(publish(&source, &live, false).unwrap());
+            thread::sleep(Duration::from_millis(2));
+        }
+        assert_eq!(rollback(&live).unwrap(), published[0]);
+        assert!(live.is_symlink());
+        assert_eq!(
+            fs::read_to_string(live.join("index.html")).unwrap(),
+            "first"
+        );
+        assert!(!published[1].exists());
+        let error = rollback(&live).unwrap_err();
+        assert!(error.contains("no earlier version"), "{}", error);
+    }
+
+    #[test]
+    fn a_deploy_without_its_index_is_rolled_back() {
+        let dir = TempDir::new("deploy-rollback");
+        let origin = init_repo(&dir.path().join("origin"));
+        commit_files(&origin, &[("public/index.html", "first")], "first");
+        let config = crate::test_util::test_config(serde_json::json!([{
+            "domain": "ivytime.gay",
+            "site_repo": file_url(&dir.path().join("origin")),
+            "branch": "main",
+            "clone_dir": dir.path().join("clone"),
+            "live_dir": dir.path().join("live"),
+        }]));
+        let site = &config.sites[0];
+        let outcome = run_deploy(site);
+        assert!(outcome.is_success(), "{:?}", outcome.error);
+        thread::sleep(Duration::from_millis(2));
+
+        fs::remove_file(dir.path().join("origin/public/index.html")).unwrap();
+        commit_files(&origin, &[("public/about.html", "about")], "lose the index");
+        let outcome = run_deploy(site);
+        assert!(outcome.rolled_back);
+        let error = outcome.error.unwrap();
+        assert!(error.contains("rolled back"), "{}", error);
+        let live = site.live_path();
+        assert_eq!(
+            fs::read_to_string(live.join("index.html")).unwrap(),
+            "first"
+        );
+        assert!(!live.join("about.html").exists());
+    }
+
+    #[test]
+    fn a_merged_commit_is_not_new() {
+        let dir = TempDir::new("merged");
+        let origin = init_repo(&dir.path().join("origin"));
+        commit_files(&origin, &[("index.html", "first")], "first");
+        let credentials = GitCredentials::default();
+        let repo = do_clone(
+            &file_url(&dir.path().join("origin")),
+            &dir.path().join("clone"),
+            &credentials,
+        )
+        .expect("failed to clone");
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "test").unwrap();
+        config.set_str("user.email", "test@example.com").unwrap();
+
+        // both sides move on, so pulling needs a merge commit
+        commit_files(&repo, &[("local.txt", "local")], "local");
+        let upstream = commit_files(&origin, &[("index.html", "second")], "second");
+        let mut remote = repo.find_remote("origin").unwrap();
+        let fetched = do_fetch(&repo, &["main"], &mut remote, &credentials).unwrap();
+        assert!(!is_checked_out(&repo, upstream));
+        do_merge(&repo, "main", fetched).expect("failed to merge");
+        assert_ne!(repo.head().unwrap().target(), Some(upstream));
+        assert!(is_checked_out(&repo, upstream));
+
+        let third = commit_files(&origin, &[("index.html", "third")], "third");
+        do_fetch(&repo, &["main"], &mut remote, &credentials).unwrap();
+        assert!(!is_checked_out(&repo, third));
+    }
+
+    #[test]
+    fn publish_keeps_symlinks_within_the_site() {
+        let dir = TempDir::new("symlinks");
+        let source = dir.path().join("public");
+        let live = dir.path().join("live");
+        write_file(&source.join("posts/hello.html"), "hello");
+        std::os::unix::fs::symlink("posts/hello.html", source.join("latest.html")).unwrap();
+        std::os::unix::fs::symlink("posts", source.join("blog")).unwrap();
+        std::os::unix::fs::symlink("..", source.join("posts/up")).unwrap();
+
+        let version = publish(&source, &live, false).expect("failed to publish");
+        assert_eq!(
+            fs::read_to_string(live.join("latest.html")).unwrap(),
+            "hello"
+        );
+        assert_eq!(
+            fs::read_to_string(live.join("blog/hello.html")).unwrap(),
+            "hello"
+        );
+        // the links point into the published copy, not back at the checkout
+        assert!(live
+            .join("latest.html")
+            .canonicalize()
+            .unwrap()
+            .starts_with(&version));
+        assert_eq!(live.join("posts/up").canonicalize().unwrap(), version);
+    }
+
+    #[test]
+    fn publish_refuses_symlinks_out_of_the_site() {
+        let dir = TempDir::new("symlinks-out");
+        let source = dir.path().join("public");
+        let live = dir.path().join("live");
+        write_file(&source.join("index.html"), "home");
+        write_file(&dir.path().join("secret.txt"), "secret");
+        std::os::unix::fs::symlink("../secret.txt", source.join("secret.txt")).unwrap();
+
+        let error = publish(&source, &live, false).unwrap_err();
+        assert!(
+            error.contains("points outside the publish dir"),
+            "{}",
+            error
+        );
+        assert!(!live.exists());
+        let versions = fs::read_dir(sibling_path(&live, "versions")).unwrap();
+        assert_eq!(versions.count(), 0);
+
+        fs::remove_file(source.join("secret.txt")).unwrap();
+        std::os::unix::fs::symlink("/etc", source.join("etc")).unwrap();
+        assert!(publish(&source, &live, false).is_err());
+    }
+}
@@ -0,0 +1,530 @@
+use std::str::FromStr;
+
+use actix_web::web::Bytes;
+use base64::Engine;
+use chrono::{DateTime, NaiveDate, SecondsFormat};
+use chrono_tz::Tz;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    analytics::sha256_hash,
+    config::Config,
+    db::conn::{Conn, ExportedRequest},
+    range::start_of_day,
+};
+
+/// how exported rows are written
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    /// one json object per line
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            _ => Err(format!("unknown export format {}, use csv or ndjson", s)),
+        }
+    }
+}
+
+/// what gets exported
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportKind {
+    /// every recorded request
+    Requests,
+    /// requests and unique visitors per path per day in the reporting timezone
+    Daily,
+}
+
+impl ExportKind {
+    fn columns(&self) -> &'static [&'static str] {
+        match self {
+            ExportKind::Requests => &["site", "path", "visitor", "timestamp", "time"],
+            ExportKind::Daily => &["site", "path", "day", "total_requests", "unique_visitors"],
+        }
+    }
+}
+
+impl FromStr for ExportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "requests" => Ok(ExportKind::Requests),
+            "daily" => Ok(ExportKind::Daily),
+            _ => Err(format!("unknown export {}, use requests or daily", s)),
+        }
+    }
+}
+
+/// an exported request. the visitor is a salted hash of the stored ip hash,
+/// which on its own could be matched against every ipv4 address
+#[derive(Serialize, Debug)]
+struct RequestRecord {
+    site: String,
+    path: String,
+    visitor: String,
+    /// milliseconds since the epoch
+    timestamp: i64,
+    /// the same as `timestamp` in rfc 3339, utc
+    time: String,
+}
+
+impl RequestRecord {
+    fn new(request: ExportedRequest, salt: &str) -> RequestRecord {
+        let time = DateTime::from_timestamp_millis(request.created_at)
+            .map(|x| x.to_rfc3339_opts(SecondsFormat::Millis, true))
+            .unwrap_or_default();
+        RequestRecord {
+            visitor: sha256_hash(format!("{}{}", salt, request.ip_address_hash).as_bytes()),
+            site: request.site,
+            path: request.path,
+            timestamp: request.created_at,
+            time,
+        }
+    }
+}
+
+/// parses either milliseconds since the epoch or a `YYYY-MM-DD` date in `tz`.
+/// dates mean the start of the day, or with `end_of_day` the start of the next
+/// one so a range `to` a date includes it
+pub fn parse_time(value: &str, end_of_day: bool, tz: Tz) -> Result<i64, String> {
+    if let Ok(x) = value.parse::<i64>() {
+        return Ok(x);
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("invalid time {}, use YYYY-MM-DD or milliseconds", value))?;
+    let date = match end_of_day {
+        true => date
+            .succ_opt()
+            .ok_or_else(|| format!("invalid time {}", value))?,
+        false => date,
+    };
+    Ok(start_of_day(date, tz))
+}
+
+/// the configured `export_salt`, or a fresh random one
+pub fn visitor_salt(config: &Config) -> String {
+    match &config.export_salt {
+        Some(salt) => salt.clone(),
+        None => {
+            let mut salt = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut salt);
+            base64::prelude::BASE64_STANDARD.encode(salt)
+        }
+    }
+}
+
+fn encode<T: Serialize>(rows: &[T], format: ExportFormat) -> Bytes {
+    let mut out = Vec::new();
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(&mut out);
+            for row in rows {
+                writer.serialize(row).expect("failed to write csv row");
+            }
+            writer.flush().expect("failed to write csv");
+        }
+        ExportFormat::Ndjson => {
+            for row in rows {
+                serde_json::to_writer(&mut out, row).expect("failed to write json row");
+                out.push(b'\n');
+            }
+        }
+    }
+    Bytes::from(out)
+}
+
+fn csv_header(columns: &[&str]) -> Bytes {
+    let mut out = Vec::new();
+    let mut writer = csv::Writer::from_writer(&mut out);
+    writer
+        .write_record(columns)
+        .expect("failed to write csv header");
+    writer.flush().expect("failed to write csv");
+    drop(writer);
+    Bytes::from(out)
+}
+
+/// streams a site's analytics between `from` and `to` as it's read from the
+/// database, with days in `tz`. csv always starts with a header, even if
+/// nothing matched
+#[allow(clippy::too_many_arguments)]
+pub async fn export(
+    conn: &impl Conn,
+    site: &str,
+    kind: ExportKind,
+    format: ExportFormat,
+    from: i64,
+    to: i64,
+    tz: Tz,
+    salt: String,
+) -> BoxStream<'static, Bytes> {
+    let rows = match kind {
+        ExportKind::Requests => conn
+            .export_requests(site, from, to, false)
+            .await
+            .map(move |batch| {
+                let records: Vec<_> = batch
+                    .into_iter()
+                    .map(|x| RequestRecord::new(x, &salt))
+                    .collect();
+                encode(&records, format)
+            })
+            .boxed(),
+        ExportKind::Daily => conn
+            .export_daily_counts(site, from, to, tz.name())
+            .await
+            .map(move |batch| encode(&batch, format))
+            .boxed(),
+    };
+    match format {
+        ExportFormat::Csv => stream::once(async move { csv_header(kind.columns()) })
+            .chain(rows)
+            .boxed(),
+        ExportFormat::Ndjson => rows,
+    }
+}
+
+/// the columns of a version 2 goatcounter export, the `2` marks the version
+const GOATCOUNTER_COLUMNS: &[&str] = &[
+    "2Path",
+    "Title",
+    "Event",
+    "UserAgent",
+    "Browser",
+    "System",
+    "Session",
+    "Bot",
+    "Referrer",
+    "Referrer scheme",
+    "Screen size",
+    "Location",
+    "FirstVisit",
+    "Date",
+];
+
+/// a pageview in goatcounter's csv export. ivyhost doesn't record most of
+/// what goatcounter does, so those columns are left empty
+#[derive(Serialize, Debug)]
+struct GoatcounterRecord {
+    path: String,
+    title: &'static str,
+    event: bool,
+    user_agent: &'static str,
+    browser: &'static str,
+    system: &'static str,
+    /// 32 hex characters, like goatcounter's 128 bit session ids
+    session: String,
+    bot: u8,
+    referrer: &'static str,
+    referrer_scheme: &'static str,
+    screen_size: &'static str,
+    location: &'static str,
+    first_visit: bool,
+    date: String,
+}
+
+impl GoatcounterRecord {
+    fn new(request: ExportedRequest, salt: &str) -> GoatcounterRecord {
+        let mut hasher = Sha256::new();
+        hasher.update(salt.as_bytes());
+        hasher.update(request.ip_address_hash.as_bytes());
+        let session = hasher.finalize()[..16]
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect();
+        GoatcounterRecord {
+            path: request.path,
+            title: "",
+            event: false,
+            user_agent: "",
+            browser: "",
+            system: "",
+            session,
+            bot: 0,
+            referrer: "",
+            referrer_scheme: "",
+            screen_size: "",
+            location: "",
+            first_visit: request.first_visit,
+            date: DateTime::from_timestamp_millis(request.created_at)
+                .map(|x| x.to_rfc3339_opts(SecondsFormat::Secs, true))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// checks the `--format` given for a goatcounter export, whose format is
+/// always csv
+pub fn goatcounter_format(format: Option<&str>) -> Result<ExportFormat, String> {
+    match format.map(|x| x.parse()).transpose()? {
+        None | Some(ExportFormat::Csv) => Ok(ExportFormat::Csv),
+        Some(_) => Err("goatcounter exports are always csv, leave out --format".to_string()),
+    }
+}
+
+/// streams a site's requests as a goatcounter csv export, which goatcounter
+/// can import with `goatcounter import`
+pub async fn export_goatcounter(
+    conn: &impl Conn,
+    site: &str,
+    from: i64,
+    to: i64,
+    salt: String,
+) -> BoxStream<'static, Bytes> {
+    let rows = conn
+        .export_requests(site, from, to, true)
+        .await
+        .map(move |batch| {
+            let records: Vec<_> = batch
+                .into_iter()
+                .map(|x| GoatcounterRecord::new(x, &salt))
+                .collect();
+            encode(&records, ExportFormat::Csv)
+        });
+    stream::once(async { csv_header(GOATCOUNTER_COLUMNS) })
+        .chain(rows)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analytics::AnalyticsRequest, db::conn::DailyPathCount, test_util::TestDb};
+
+    fn request(path: &str, created_at: i64) -> ExportedRequest {
+        ExportedRequest {
+            site: "ivytime.gay".to_string(),
+            path: path.to_string(),
+            ip_address_hash: "hashed-ip".to_string(),
+            created_at,
+            first_visit: true,
+        }
+    }
+
+    /// the header serde would write for `row`, to check it against the
+    /// columns we write ourselves
+    fn field_names<T: Serialize>(row: &T) -> String {
+        let mut out = Vec::new();
+        let mut writer = csv::Writer::from_writer(&mut out);
+        writer.serialize(row).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn headers_match_the_columns_written() {
+        let record = RequestRecord::new(request("/", 0), "salt");
+        let header = csv_header(ExportKind::Requests.columns());
+        assert_eq!(header, "site,path,visitor,timestamp,time\n");
+        assert_eq!(format!("{}\n", field_names(&record)), header);
+
+        let daily = DailyPathCount {
+            site: "ivytime.gay".to_string(),
+            path: "/".to_string(),
+            day: "2024-03-01".to_string(),
+            total_requests: 2,
+            unique_visitors: 1,
+        };
+        let header = csv_header(ExportKind::Daily.columns());
+        assert_eq!(format!("{}\n", field_names(&daily)), header);
+
+        let record = GoatcounterRecord::new(request("/", 0), "salt");
+        let columns = field_names(&record).split(',').count();
+        assert_eq!(columns, GOATCOUNTER_COLUMNS.len());
+        assert!(csv_header(GOATCOUNTER_COLUMNS).starts_with(b"2Path,Title,Event,"));
+    }
+
+    #[test]
+    fn escapes_csv_fields() {
+        let rows = [
+            RequestRecord::new(request("/plain", 1709251200000), "salt"),
+            RequestRecord::new(request("/a,b", 1709251200000), "salt"),
+            RequestRecord::new(request("/\"quoted\"", 1709251200000), "salt"),
+            RequestRecord::new(request("/line\nbreak", 1709251200000), "salt"),
+        ];
+        let visitor = rows[0].visitor.clone();
+        let csv = encode(&rows, ExportFormat::Csv);
+        let expected = [
+            "ivytime.gay,/plain,{v},1709251200000,2024-03-01T00:00:00.000Z",
+            "ivytime.gay,\"/a,b\",{v},1709251200000,2024-03-01T00:00:00.000Z",
+            "ivytime.gay,\"/\"\"quoted\"\"\",{v},1709251200000,2024-03-01T00:00:00.000Z",
+            "ivytime.gay,\"/line\nbreak\",{v},1709251200000,2024-03-01T00:00:00.000Z",
+        ]
+        .map(|x| format!("{}\n", x.replace("{v}", &visitor)))
+        .concat();
+        assert_eq!(csv, expected);
+
+        // and it reads back the same
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(&csv[..]);
+        let paths: Vec<_> = reader
+            .records()
+            .map(|x| x.unwrap()[1].to_string())
+            .collect();
+        assert_eq!(paths, ["/plain", "/a,b", "/\"quoted\"", "/line\nbreak"]);
+    }
+
+    #[test]
+    fn writes_one_json_object_per_line() {
+        let rows = [
+            RequestRecord::new(request("/line\nbreak", 0), "salt"),
+            RequestRecord::new(request("/caf\u{e9}", 1000), "salt"),
+        ];
+        let ndjson = encode(&rows, ExportFormat::Ndjson);
+        let text = std::str::from_utf8(&ndjson).unwrap();
+        assert!(text.ends_with('\n'));
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["path"], "/line\nbreak");
+        assert_eq!(lines[1]["path"], "/caf\u{e9}");
+        assert_eq!(lines[1]["timestamp"], 1000);
+        assert_eq!(lines[1]["time"], "1970-01-01T00:00:01.000Z");
+        assert!(encode::<RequestRecord>(&[], ExportFormat::Ndjson).is_empty());
+    }
+
+    #[test]
+    fn hashes_visitors_with_the_salt() {
+        let first = RequestRecord::new(request("/", 0), "salt");
+        let again = RequestRecord::new(request("/other", 5), "salt");
+        let resalted = RequestRecord::new(request("/", 0), "pepper");
+        assert_eq!(first.visitor, again.visitor);
+        assert_ne!(first.visitor, resalted.visitor);
+        assert_ne!(first.visitor, "hashed-ip");
+
+        let goatcounter = GoatcounterRecord::new(request("/", 1709251200000), "salt");
+        assert_eq!(goatcounter.session.len(), 32);
+        assert!(goatcounter.session.chars().all(|x| x.is_ascii_hexdigit()));
+        assert_eq!(goatcounter.date, "2024-03-01T00:00:00Z");
+    }
+
+    #[test]
+    fn goatcounter_exports_are_only_csv() {
+        assert_eq!(goatcounter_format(None), Ok(ExportFormat::Csv));
+        assert_eq!(goatcounter_format(Some("csv")), Ok(ExportFormat::Csv));
+        assert_eq!(
+            goatcounter_format(Some("ndjson")),
+            Err("goatcounter exports are always csv, leave out --format".to_string())
+        );
+        assert!(goatcounter_format(Some("xml")).is_err());
+    }
+
+    #[actix_web::test]
+    async fn streams_a_header_then_the_rows() {
+        let Some(db) = TestDb::new("export").await else {
+            return;
+        };
+        let collect = |stream: BoxStream<'static, Bytes>| async move {
+            let chunks: Vec<Bytes> = stream.collect().await;
+            String::from_utf8(chunks.concat()).unwrap()
+        };
+        let tz: Tz = "UTC".parse().unwrap();
+        let csv = collect(
+            export(
+                &db.conn,
+                "ivytime.gay",
+                ExportKind::Daily,
+                ExportFormat::Csv,
+                0,
+                i64::MAX,
+                tz,
+                "salt".to_string(),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(csv, "site,path,day,total_requests,unique_visitors\n");
+
+        for (path, visitor) in [("/b", "x"), ("/a", "x"), ("/a", "y")] {
+            db.conn
+                .new_request(AnalyticsRequest {
+                    site: "ivytime.gay".to_string(),
+                    hashed_ip: visitor.to_string(),
+                    path: path.to_string(),
+                    created_at_milis: 1709251200000,
+                })
+                .await;
+        }
+        let csv = collect(
+            export(
+                &db.conn,
+                "ivytime.gay",
+                ExportKind::Daily,
+                ExportFormat::Csv,
+                0,
+                i64::MAX,
+                tz,
+                "salt".to_string(),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(
+            csv,
+            "site,path,day,total_requests,unique_visitors\n\
+             ivytime.gay,/a,2024-03-01,2,2\n\
+             ivytime.gay,/b,2024-03-01,1,1\n"
+        );
+        let ndjson = collect(
+            export(
+                &db.conn,
+                "ivytime.gay",
+                ExportKind::Requests,
+                ExportFormat::Ndjson,
+                0,
+                i64::MAX,
+                tz,
+                "salt".to_string(),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(ndjson.lines().count(), 3);
+        assert!(
+            ndjson.starts_with("{\"site\":\"ivytime.gay\","),
+            "{}",
+            ndjson
+        );
+    }
+}
@@ -0,0 +1,686 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+
+use base64::Engine;
+use chrono::{DateTime, NaiveDate};
+use chrono_tz::Tz;
+use flate2::read::MultiGzDecoder;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    analytics::{sha256_hash, AnalyticsRequest},
+    config::SiteConfig,
+    db::conn::Conn,
+    deploy::current_time_milis,
+    range::start_of_day,
+    serve::analytics_path,
+};
+
+/// what importing a single file did
+#[derive(Debug, Default)]
+pub struct ImportOutcome {
+    /// requests recorded, or `None` if the file had already been imported
+    pub imported: Option<u64>,
+    /// rows that aren't page views ivyhost would have recorded, eg failed
+    /// requests, events or bots
+    pub skipped: u64,
+    /// rows that couldn't be parsed
+    pub invalid: u64,
+}
+
+/// the parts of a common or combined log format line analytics cares about
+#[derive(Debug, PartialEq, Eq)]
+struct LogLine<'a> {
+    ip: &'a str,
+    created_at_milis: i64,
+    target: &'a str,
+    status: u16,
+}
+
+/// splits off a `"quoted"` field, which may contain `\"` escapes
+fn quoted(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start().strip_prefix('"')?;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some((&line[..i], &line[i + 1..])),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// splits off a whitespace separated field
+fn field(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start();
+    let end = line.find(' ').unwrap_or(line.len());
+    (end > 0).then(|| (&line[..end], &line[end..]))
+}
+
+/// parses `host ident user [time] "request" status bytes`, ignoring anything
+/// after, eg the referer and user agent of the combined format
+fn parse_log_line(line: &str) -> Option<LogLine<'_>> {
+    let (ip, rest) = field(line)?;
+    let (_ident, rest) = field(rest)?;
+    let rest = rest.trim_start();
+    // the user may be quoted or contain spaces, the time is the first `[`
+    let rest = &rest[rest.find('[')? + 1..];
+    let (time, rest) = rest.split_once(']')?;
+    let created_at_milis = DateTime::parse_from_str(time, "%d/%b/%Y:%H:%M:%S %z")
+        .ok()?
+        .timestamp_millis();
+    let (request, rest) = quoted(rest)?;
+    let (status, _) = field(rest)?;
+    let status = status.parse().ok()?;
+    // `GET /path HTTP/1.1`, or just `GET /path` from http/0.9 clients
+    let mut parts = request.split(' ');
+    let _method = parts.next()?;
+    let target = parts.next()?;
+    Some(LogLine {
+        ip,
+        created_at_milis,
+        target,
+        status,
+    })
+}
+
+/// the path of a request target, without the query, like `req.path()`.
+/// proxies log absolute urls so those are cut down to their path
+fn target_path(target: &str) -> Option<&str> {
+    let target = match target.split_once("://") {
+        Some((_, rest)) => &rest[rest.find('/')?..],
+        None => target,
+    };
+    let path = target.split(['?', '#']).next()?;
+    path.starts_with('/').then_some(path)
+}
+
+/// reads a file, transparently decompressing it if it's gzipped
+fn open_file(file: &Path) -> Result<Box<dyn BufRead + Send>, String> {
+    let mut reader = BufReader::new(
+        File::open(file).map_err(|e| format!("failed to open {}: {}", file.display(), e))?,
+    );
+    let is_gzip = reader
+        .fill_buf()
+        .map_err(|e| format!("failed to read {}: {}", file.display(), e))?
+        .starts_with(&[0x1f, 0x8b]);
+    Ok(match is_gzip {
+        true => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        false => Box::new(reader),
+    })
+}
+
+/// a hash of the decompressed contents of a file, so it can be recognised if
+/// it's imported again however it's renamed or compressed
+fn hash_file(file: &Path) -> Result<String, String> {
+    let mut reader = open_file(file)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let read = reader
+            .read(&mut buf)
+            .map_err(|e| format!("failed to read {}: {}", file.display(), e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(base64::prelude::BASE64_STANDARD.encode(hasher.finalize()))
+}
+
+/// records the requests read from `file` as they're read, unless a file with
+/// the same contents was imported before
+async fn import_file(
+    conn: &impl Conn,
+    site: &SiteConfig,
+    file: &Path,
+    requests: impl Iterator<Item = Result<AnalyticsRequest, String>> + Send,
+) -> Result<Option<u64>, String> {
+    let source_hash = hash_file(file)?;
+    let name = file
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    conn.import_requests(
+        &site.domain,
+        &source_hash,
+        &name,
+        requests,
+        current_time_milis(),
+    )
+    .await
+}
+
+/// imports an nginx or apache access log in the common or combined log
+/// format, optionally gzipped, into `site`. requests get the same treatment
+/// as `simple_analytics` gives live ones: only successful ones count, ips are
+/// hashed and pages are recorded under their canonical path. importing the
+/// same file again does nothing, as long as its contents are the same, so a
+/// log should be imported once it's been rotated
+pub async fn import_access_log(
+    conn: &impl Conn,
+    site: &SiteConfig,
+    file: &Path,
+) -> Result<ImportOutcome, String> {
+    let mut outcome = ImportOutcome::default();
+    // resolving a path touches the disk, and logs repeat the same few a lot
+    let mut paths: HashMap<String, String> = HashMap::new();
+    let requests = open_file(file)?.split(b'\n').filter_map(|line| {
+        let line = match line {
+            Ok(x) => x,
+            Err(e) => return Some(Err(format!("failed to read {}: {}", file.display(), e))),
+        };
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end();
+        if text.is_empty() {
+            return None;
+        }
+        let Some(parsed) = parse_log_line(text) else {
+            outcome.invalid += 1;
+            return None;
+        };
+        if !(200..300).contains(&parsed.status) {
+            outcome.skipped += 1;
+            return None;
+        }
+        let Some(path) = target_path(parsed.target) else {
+            outcome.invalid += 1;
+            return None;
+        };
+        let path = paths
+            .entry(path.to_string())
+            .or_insert_with(|| analytics_path(site, path))
+            .clone();
+        Some(Ok(AnalyticsRequest {
+            site: site.domain.clone(),
+            hashed_ip: sha256_hash(parsed.ip.as_bytes()),
+            path,
+            created_at_milis: parsed.created_at_milis,
+        }))
+    });
+    let imported = import_file(conn, site, file, requests).await?;
+    outcome.imported = imported;
+    Ok(outcome)
+}
+
+type CsvReader = csv::Reader<Box<dyn BufRead + Send>>;
+
+/// a csv file, possibly gzipped, along with the index of each named column
+fn open_csv<const N: usize>(
+    file: &Path,
+    columns: [&str; N],
+) -> Result<(CsvReader, [usize; N]), String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(open_file(file)?);
+    let headers = reader
+        .headers()
+        .map_err(|e| format!("failed to read {}: {}", file.display(), e))?
+        .clone();
+    let mut indexes = [0; N];
+    for (index, column) in indexes.iter_mut().zip(columns) {
+        *index = headers
+            .iter()
+            .position(|x| x.trim() == column)
+            .ok_or_else(|| format!("{} has no {} column", file.display(), column))?;
+    }
+    Ok((reader, indexes))
+}
+
+/// a page view from a row of a goatcounter export
+#[derive(Debug, PartialEq, Eq)]
+struct GoatcounterView {
+    path: String,
+    /// the session, or something unique to the row when there's none
+    visitor: String,
+    created_at_milis: i64,
+}
+
+/// what a row of an export held
+#[derive(Debug, PartialEq, Eq)]
+enum Row<T> {
+    PageView(T),
+    /// eg events or bots
+    Skipped,
+    Invalid,
+}
+
+/// the `row`th row of a goatcounter export of `domain`, given the indexes of
+/// its path, event, session, bot and date columns
+fn parse_goatcounter_row(
+    record: &csv::StringRecord,
+    indexes: [usize; 5],
+    domain: &str,
+    row: usize,
+) -> Row<GoatcounterView> {
+    let column = |i: usize| record.get(indexes[i]).unwrap_or("").trim();
+    if column(1) == "true" || !matches!(column(3), "" | "0") {
+        return Row::Skipped;
+    }
+    let Ok(date) = DateTime::parse_from_rfc3339(column(4)) else {
+        return Row::Invalid;
+    };
+    let path = column(0);
+    if !path.starts_with('/') {
+        return Row::Invalid;
+    }
+    // without a session every row is its own visitor
+    let visitor = match column(2) {
+        "" => format!("goatcounter:{}:row:{}", domain, row),
+        session => format!("goatcounter:{}", session),
+    };
+    Row::PageView(GoatcounterView {
+        path: path.to_string(),
+        visitor,
+        created_at_milis: date.timestamp_millis(),
+    })
+}
+
+/// imports a goatcounter csv export into `site`. goatcounter records a row
+/// per page view, which maps straight onto requests with the session as the
+/// visitor. events and bots are skipped
+pub async fn import_goatcounter(
+    conn: &impl Conn,
+    site: &SiteConfig,
+    file: &Path,
+) -> Result<ImportOutcome, String> {
+    // the first column is the path prefixed with the export's version
+    let (reader, indexes) = open_csv(file, ["2Path", "Event", "Session", "Bot", "Date"])?;
+    let mut outcome = ImportOutcome::default();
+    let mut paths: HashMap<String, String> = HashMap::new();
+    let requests = reader
+        .into_records()
+        .enumerate()
+        .filter_map(|(row, record)| {
+            let record = match record {
+                Ok(x) => x,
+                Err(e) => return Some(Err(format!("failed to read {}: {}", file.display(), e))),
+            };
+            let view = match parse_goatcounter_row(&record, indexes, &site.domain, row) {
+                Row::PageView(x) => x,
+                Row::Skipped => {
+                    outcome.skipped += 1;
+                    return None;
+                }
+                Row::Invalid => {
+                    outcome.invalid += 1;
+                    return None;
+                }
+            };
+            let path = paths
+                .entry(view.path)
+                .or_insert_with_key(|path| analytics_path(site, path))
+                .clone();
+            Some(Ok(AnalyticsRequest {
+                site: site.domain.clone(),
+                hashed_ip: sha256_hash(view.visitor.as_bytes()),
+                path,
+                created_at_milis: view.created_at_milis,
+            }))
+        });
+    let imported = import_file(conn, site, file, requests).await?;
+    outcome.imported = imported;
+    Ok(outcome)
+}
+
+/// a page's views on one day of a plausible export
+#[derive(Debug, PartialEq, Eq)]
+struct PlausibleDay {
+    date: NaiveDate,
+    page: String,
+    visitors: u64,
+    pageviews: u64,
+}
+
+impl PlausibleDay {
+    /// the day's page views spread evenly over it in `tz` and shared out
+    /// between `visitors` made up visitors, made as they're needed
+    fn requests(self, site: &SiteConfig, tz: Tz) -> impl Iterator<Item = AnalyticsRequest> {
+        let path = analytics_path(site, &self.page);
+        let domain = site.domain.clone();
+        // around dst changes a day isn't 24 hours
+        let start = start_of_day(self.date, tz);
+        let end = self
+            .date
+            .succ_opt()
+            .map_or(start, |next| start_of_day(next, tz));
+        let pageviews = self.pageviews;
+        let visitors = self.visitors.clamp(1, pageviews.max(1));
+        let spacing = (end - start) / pageviews.max(1) as i64;
+        (0..pageviews).map(move |i| {
+            // the same made up visitors are shared between a day's pages
+            let visitor = format!("plausible:{}:{}:{}", domain, self.date, i % visitors);
+            AnalyticsRequest {
+                site: domain.clone(),
+                hashed_ip: sha256_hash(visitor.as_bytes()),
+                path: path.clone(),
+                created_at_milis: start + spacing * i as i64,
+            }
+        })
+    }
+}
+
+/// a row of plausible's `imported_pages` csv, given the indexes of its date,
+/// page, visitors and pageviews columns
+fn parse_plausible_row(record: &csv::StringRecord, indexes: [usize; 4]) -> Option<PlausibleDay> {
+    let column = |i: usize| record.get(indexes[i]).unwrap_or("").trim();
+    let date = NaiveDate::parse_from_str(column(0), "%Y-%m-%d").ok()?;
+    // the last day chrono can represent has no end to spread views over
+    date.succ_opt()?;
+    let page = column(1);
+    Some(PlausibleDay {
+        date,
+        page: page.starts_with('/').then(|| page.to_string())?,
+        visitors: column(2).parse().ok()?,
+        pageviews: column(3).parse().ok()?,
+    })
+}
+
+/// imports the `imported_pages` csv from a plausible export into `site`.
+/// plausible only exports daily totals per page, so each day's page views are
+/// spread evenly over the day in `tz` and shared out between that many made
+/// up visitors. a visitor returning on another day can't be told apart, so
+/// unique visitors over more than a day come out higher than plausible's
+pub async fn import_plausible(
+    conn: &impl Conn,
+    site: &SiteConfig,
+    file: &Path,
+    tz: Tz,
+) -> Result<ImportOutcome, String> {
+    let (reader, indexes) = open_csv(file, ["date", "page", "visitors", "pageviews"])?;
+    let mut outcome = ImportOutcome::default();
+    let requests = reader
+        .into_records()
+        .filter_map(|record| match record {
+            Ok(record) => {
+                let day = parse_plausible_row(&record, indexes);
+                if day.is_none() {
+                    outcome.invalid += 1;
+                }
+                day.map(Ok)
+            }
+            Err(e) => Some(Err(format!("failed to read {}: {}", file.display(), e))),
+        })
+        .flat_map(|day| {
+            let (day, error) = match day {
+                Ok(x) => (Some(x), None),
+                Err(e) => (None, Some(Err(e))),
+            };
+            day.into_iter()
+                .flat_map(move |x| x.requests(site, tz))
+                .map(Ok)
+                .chain(error)
+        });
+    let imported = import_file(conn, site, file, requests).await?;
+    outcome.imported = imported;
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    use crate::test_util::{test_config, write_file, TempDir, TestDb};
+
+    #[test]
+    fn parses_common_and_combined_log_lines() {
+        let combined = r#"203.0.113.7 - - [10/Oct/2024:13:55:36 +0200] "GET /blog/?page=2 HTTP/1.1" 200 2326 "https://example.com/" "Mozilla/5.0 (X11; Linux x86_64)""#;
+        assert_eq!(
+            parse_log_line(combined),
+            Some(LogLine {
+                ip: "203.0.113.7",
+                created_at_milis: 1728561336000,
+                target: "/blog/?page=2",
+                status: 200,
+            })
+        );
+        // users with spaces, http/0.9 requests and other methods
+        let common = r#"2001:db8::1 - "jane doe" [10/Oct/2024:11:55:36 +0000] "HEAD /about" 304 0"#;
+        let parsed = parse_log_line(common).unwrap();
+        assert_eq!(parsed.created_at_milis, 1728561336000);
+        assert_eq!((parsed.target, parsed.status), ("/about", 304));
+    }
+
+    #[test]
+    fn parses_quoted_requests_with_spaces_and_escapes() {
+        let line = r#"198.51.100.2 - - [10/Oct/2024:13:55:36 +0000] "POST /search \"a b\" HTTP/1.1" 201 12"#;
+        let parsed = parse_log_line(line).unwrap();
+        assert_eq!((parsed.target, parsed.status), ("/search", 201));
+        assert_eq!(
+            quoted(r#" "a \"b\" c" rest"#),
+            Some((r#"a \"b\" c"#, " rest"))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in [
+            "",
+            "not a log line",
+            r#"203.0.113.7 - - 10/Oct/2024:13:55:36 +0000 "GET / HTTP/1.1" 200 1"#,
+            r#"203.0.113.7 - - [yesterday] "GET / HTTP/1.1" 200 1"#,
+            r#"203.0.113.7 - - [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1 200 1"#,
+            r#"203.0.113.7 - - [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" ok 1"#,
+            r#"203.0.113.7 - - [10/Oct/2024:13:55:36 +0000] "-" 400 0"#,
+        ] {
+            assert_eq!(parse_log_line(line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn cuts_targets_down_to_their_path() {
+        assert_eq!(target_path("/blog/?page=2"), Some("/blog/"));
+        assert_eq!(target_path("/docs#install"), Some("/docs"));
+        assert_eq!(target_path("/"), Some("/"));
+        assert_eq!(target_path("http://example.com/a?b"), Some("/a"));
+        assert_eq!(target_path("http://example.com"), None);
+        assert_eq!(target_path("*"), None);
+        assert_eq!(target_path("example.com:443"), None);
+    }
+
+    /// a csv file's headers and rows
+    fn read_csv(text: &str) -> (csv::StringRecord, Vec<csv::StringRecord>) {
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(text.as_bytes());
+        let headers = reader.headers().unwrap().clone();
+        let rows = reader.records().map(|x| x.unwrap()).collect();
+        (headers, rows)
+    }
+
+    #[test]
+    fn reads_goatcounter_rows() {
+        let (headers, rows) = read_csv(concat!(
+            "2Path,Title,Event,UserAgent,Browser,System,Session,Bot,Referrer,Referrer scheme,Screen size,Location,FirstVisit,Date\n",
+            "/blog,Blog,false,Firefox,Firefox 128,Linux,abc,0,,,1920,NL,true,2024-10-10T13:55:36Z\n",
+            "/about,About,false,Firefox,Firefox 128,Linux,,,,,,,,2024-10-10T14:00:00+02:00\n",
+            "click-signup,,true,,,,abc,0,,,,,,2024-10-10T13:56:00Z\n",
+            "/blog,Blog,false,Googlebot,,,def,4,,,,,,2024-10-10T13:57:00Z\n",
+            "/blog,Blog,false,,,,ghi,0,,,,,,yesterday\n",
+            "blog,Blog,false,,,,ghi,0,,,,,,2024-10-10T13:57:00Z\n",
+            "/short\n",
+        ));
+        let indexes = ["2Path", "Event", "Session", "Bot", "Date"]
+            .map(|x| headers.iter().position(|y| y == x).unwrap());
+        let parsed: Vec<_> = rows
+            .iter()
+            .enumerate()
+            .map(|(row, x)| parse_goatcounter_row(x, indexes, "ivytime.gay", row))
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                Row::PageView(GoatcounterView {
+                    path: "/blog".to_string(),
+                    visitor: "goatcounter:abc".to_string(),
+                    created_at_milis: 1728568536000,
+                }),
+                Row::PageView(GoatcounterView {
+                    path: "/about".to_string(),
+                    visitor: "goatcounter:ivytime.gay:row:1".to_string(),
+                    created_at_milis: 1728561600000,
+                }),
+                Row::Skipped,
+                Row::Skipped,
+                Row::Invalid,
+                Row::Invalid,
+                Row::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_plausible_rows() {
+        let (headers, rows) = read_csv(concat!(
+            "date,hostname,page,visitors,pageviews,exits,time_on_page\n",
+            "2024-10-10,ivytime.gay,/blog,3,5,1,10\n",
+            "2024-10-10,ivytime.gay,blog,3,5,1,10\n",
+            "10/10/2024,ivytime.gay,/blog,3,5,1,10\n",
+            "2024-10-10,ivytime.gay,/blog,-1,5,1,10\n",
+            "2024-10-10,ivytime.gay,/blog\n",
+        ));
+        let indexes = ["date", "page", "visitors", "pageviews"]
+            .map(|x| headers.iter().position(|y| y == x).unwrap());
+        let parsed: Vec<_> = rows
+            .iter()
+            .map(|x| parse_plausible_row(x, indexes))
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                Some(PlausibleDay {
+                    date: NaiveDate::from_ymd_opt(2024, 10, 10).unwrap(),
+                    page: "/blog".to_string(),
+                    visitors: 3,
+                    pageviews: 5,
+                }),
+                None,
+                None,
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn spreads_plausible_views_over_the_local_day() {
+        let config = test_config(serde_json::json!([{
+            "domain": "ivytime.gay",
+            "site_repo": "https://example.com/site.git",
+            "branch": "main",
+        }]));
+        let day = |date: &str, visitors, pageviews| PlausibleDay {
+            date: NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap(),
+            page: "/blog".to_string(),
+            visitors,
+            pageviews,
+        };
+        let tz: Tz = "Europe/Amsterdam".parse().unwrap();
+        let hour = 60 * 60 * 1000;
+
+        let requests: Vec<_> = day("2024-10-10", 2, 4)
+            .requests(&config.sites[0], tz)
+            .collect();
+        let times: Vec<i64> = requests.iter().map(|x| x.created_at_milis).collect();
+        // midnight in amsterdam is 22:00 utc in the summer
+        let start = 1728511200000;
+        assert_eq!(times, [0, 6, 12, 18].map(|x| start + x * hour));
+        assert!(requests.iter().all(|x| x.path == "/blog"));
+        assert_eq!(requests[0].hashed_ip, requests[2].hashed_ip);
+        assert_ne!(requests[0].hashed_ip, requests[1].hashed_ip);
+
+        // the day the clocks go back lasts 25 hours
+        let times: Vec<i64> = day("2024-10-27", 1, 5)
+            .requests(&config.sites[0], tz)
+            .map(|x| x.created_at_milis)
+            .collect();
+        assert_eq!(times[1] - times[0], 5 * hour);
+        // more visitors than views can't happen, and days without views add
+        // nothing
+        let visitors: HashSet<_> = day("2024-10-10", 9, 3)
+            .requests(&config.sites[0], tz)
+            .map(|x| x.hashed_ip)
+            .collect();
+        assert_eq!(visitors.len(), 3);
+        assert_eq!(
+            day("2024-10-10", 0, 0)
+                .requests(&config.sites[0], tz)
+                .count(),
+            0
+        );
+    }
+
+    #[actix_web::test]
+    async fn imports_successful_page_views_once() {
+        let Some(db) = TestDb::new("import_log").await else {
+            return;
+        };
+        let dir = TempDir::new("import-log");
+        let config = test_config(serde_json::json!([{
+            "domain": "ivytime.gay",
+            "site_repo": "https://example.com/site.git",
+            "branch": "main",
+        }]));
+        let site = &config.sites[0];
+        let log = dir.path().join("access.log");
+        let line = |ip: &str, request: &str, status: u16| {
+            format!(
+                r#"{} - - [10/Oct/2024:13:55:36 +0000] "{}" {} 10 "-" "curl""#,
+                ip, request, status
+            )
+        };
+        let lines = [
+            line("203.0.113.1", "GET /?utm=x HTTP/1.1", 200),
+            line("203.0.113.2", "GET / HTTP/1.1", 200),
+            line("203.0.113.1", "GET /missing HTTP/1.1", 404),
+            line("203.0.113.1", "GET /moved HTTP/1.1", 301),
+            "garbage".to_string(),
+            String::new(),
+        ];
+        write_file(&log, &lines.join("\n"));
+
+        let outcome = import_access_log(&db.conn, site, &log).await.unwrap();
+        assert_eq!(outcome.imported, Some(2));
+        assert_eq!((outcome.skipped, outcome.invalid), (2, 1));
+        let outcome = import_access_log(&db.conn, site, &log).await.unwrap();
+        assert_eq!(outcome.imported, None);
+        let paths = db.conn.get_top_paths("ivytime.gay", 0, i64::MAX, 10).await;
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].path, "/");
+        assert_eq!((paths[0].total_requests, paths[0].total_unique), (2, 2));
+    }
+
+    #[actix_web::test]
+    async fn imports_plausible_days_bigger_than_a_batch() {
+        let Some(db) = TestDb::new("import_plausible").await else {
+            return;
+        };
+        let dir = TempDir::new("import-plausible");
+        let config = test_config(serde_json::json!([{
+            "domain": "ivytime.gay",
+            "site_repo": "https://example.com/site.git",
+            "branch": "main",
+        }]));
+        let file = dir.path().join("imported_pages_20241010_20241011.csv");
+        write_file(
+            &file,
+            "date,page,visitors,pageviews\n2024-10-10,/blog,20,25000\n2024-10-11,/blog,x,1\n",
+        );
+        let outcome = import_plausible(&db.conn, &config.sites[0], &file, chrono_tz::UTC)
+            .await
+            .unwrap();
+        assert_eq!(outcome.imported, Some(25000));
+        assert_eq!(outcome.invalid, 1);
+        let paths = db.conn.get_top_paths("ivytime.gay", 0, i64::MAX, 10).await;
+        assert_eq!(
+            (paths[0].total_requests, paths[0].total_unique),
+            (25000, 20)
+        );
+    }
+}
@@ -1,5 +1,22 @@
 pub mod analytics;
 pub mod analytics_routes;
+pub mod api;
+pub mod auth;
+pub mod cache;
+pub mod compress;
 pub mod config;
 pub mod db;
+pub mod deploy;
+pub mod export;
+pub mod import;
+pub mod notify;
+pub mod poll;
 pub mod pull;
+pub mod range;
+pub mod redirects;
+pub mod security;
+pub mod serve;
+pub mod tls;
+
+#[cfg(test)]
+mod test_util;
@@ -1,36 +1,120 @@
-use actix_files::{self as fs, NamedFile};
 use actix_web::{
-    dev::{fn_service, ServiceRequest, ServiceResponse},
+    dev::fn_service,
     http::Error,
     middleware::from_fn,
     post,
-    web::Data,
-    App, HttpResponse, HttpServer,
+    web::{self, Data},
+    App, HttpRequest, HttpResponse, HttpServer,
 };
-use git2::Repository;
+use futures_util::StreamExt;
 use ivyhost::{
     analytics::simple_analytics,
     analytics_routes::get_routes,
-    config::Config,
-    db::conn::Conn,
-    pull::{do_fetch, do_merge},
+    auth::hash_password,
+    config::{Config, SiteConfig},
+    db::{conn::Conn, pg::PgConn},
+    deploy::{current_time_milis, deploy, sync_previews, DeployTrigger},
+    export::{
+        export, export_goatcounter, goatcounter_format, parse_time, visitor_salt, ExportFormat,
+        ExportKind,
+    },
+    import::{import_access_log, import_goatcounter, import_plausible},
+    poll::poll_remote,
+    security::security_headers,
+    serve::serve_site,
+    tls::{redirect_to_https, watch_certs, CertResolver},
 };
+use serde::Deserialize;
+use std::{io::Write, sync::Arc, time::Duration};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let config = Config::get_config().expect("failed to load config");
-    if let Err(git_refresh_err) = git_refresh(&config.site_repo, &config.branch) {
-        println!("{}", git_refresh_err);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(|x| x.as_str()) {
+        Some("hash-password") => return print_password_hash(),
+        Some("export") => {
+            let config = Config::get_config().expect("failed to load config");
+            if let Err(e) = run_export(&config, &args[1..]).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some("import") => {
+            let config = Config::get_config().expect("failed to load config");
+            if let Err(e) = run_import(&config, &args[1..]).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        _ => {}
     }
+    let config = Config::get_config().expect("failed to load config");
     start_application(config).await
 }
 
-pub async fn start_application(config: Config) -> std::io::Result<()> {
+pub async fn start_application(mut config: Config) -> std::io::Result<()> {
     let conn = config.create_conn();
     if let Err(x) = conn.init().await {
-        eprintln!("{}", x);
-        return Ok(());
+        return Err(std::io::Error::other(x));
+    }
+    conn.claim_unassigned(&config.sites[0].domain).await;
+    if !config.public_dashboard && config.dashboard_users.is_empty() && config.api_tokens.is_empty()
+    {
+        println!(
+            "warning: no dashboard_users or api_tokens configured, /analytics can't be accessed"
+        );
+    }
+
+    let mut usable = 0;
+    for site in &mut config.sites {
+        deploy(site, &conn, DeployTrigger::Startup).await;
+        if let Err(x) = site.validate_paths() {
+            // the site stays in the config so its hosts don't fall through to
+            // another site, it just isn't polled and serves no previews
+            eprintln!("{}", x);
+            eprintln!(
+                "{}: disabled until the problem is fixed and ivyhost restarted",
+                site.domain
+            );
+            site.preview_domain = None;
+            continue;
+        }
+        usable += 1;
+        sync_previews(site, &conn, DeployTrigger::Startup).await;
+        if let Some(interval) = site.poll_interval_secs {
+            println!(
+                "{}: polling {} every {}s",
+                site.domain, site.site_repo, interval
+            );
+            actix_web::rt::spawn(poll_remote(
+                site.clone(),
+                conn.clone(),
+                Duration::from_secs(interval),
+            ));
+        }
     }
+    if usable == 0 {
+        return Err(std::io::Error::other("no site can be served"));
+    }
+
+    let tls = match CertResolver::from_config(&config) {
+        Ok(x) => x.map(Arc::new),
+        Err(x) => return Err(std::io::Error::other(x)),
+    };
+    let tls = match (config.tls_port, tls) {
+        (Some(port), Some(resolver)) => {
+            watch_certs(resolver.clone());
+            Some((port, resolver.server_config()))
+        }
+        (Some(_), None) => {
+            return Err(std::io::Error::other(
+                "tls_port is set but no site has tls_cert_path and tls_key_path",
+            ));
+        }
+        (None, _) => None,
+    };
 
     let bind = config.bind_address.clone();
     let port = config.port;
@@ -39,59 +123,193 @@ pub async fn start_application(config: Config) -> std::io::Result<()> {
         &config.bind_address, &config.port
     );
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(Data::new(conn.to_owned()))
             .app_data(Data::new(config.to_owned()))
             .service(refresh)
             .service(get_routes())
-            .service(
-                fs::Files::new("/", "./static/repo/public")
-                    .use_hidden_files()
-                    .index_file("index.html")
-                    .show_files_listing()
-                    .default_handler(fn_service(|req: ServiceRequest| async {
-                        let (req, _) = req.into_parts();
-                        let file = NamedFile::open_async("./static/repo/public/404.html").await?;
-                        let res = file.into_response(&req);
-                        Ok(ServiceResponse::new(req, res))
-                    })),
-            )
+            .default_service(fn_service(serve_site))
             .wrap(from_fn(simple_analytics))
+            .wrap(from_fn(security_headers))
+            .wrap(from_fn(redirect_to_https))
     })
-    .bind((bind, port))?
-    .run()
-    .await
+    .bind((bind.clone(), port))?;
+    let server = match tls {
+        Some((tls_port, tls_config)) => {
+            println!("starting server at https://{}:{}", bind, tls_port);
+            server.bind_rustls_0_23((bind, tls_port), tls_config)?
+        }
+        None => server,
+    };
+    server.run().await
 }
 
-fn git_refresh(url: &str, branch: &str) -> Result<(), String> {
-    let repo = match Repository::open("./static/repo") {
-        Ok(repo) => repo,
-        Err(_e) => match Repository::clone(url, "./static/repo") {
-            Ok(repo) => repo,
-            Err(e) => panic!("failed to clone: {}", e),
-        },
+/// reads a password from stdin and prints its hash for `dashboard_users`
+fn print_password_hash() -> std::io::Result<()> {
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    match hash_password(password) {
+        Ok(hash) => println!("{}", hash),
+        Err(e) => eprintln!("{}", e),
+    }
+    Ok(())
+}
+
+/// the value following `name` in `args`, eg `--site example.com`
+fn flag<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|x| x == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|x| x.as_str())
+}
+
+/// everything in `args` that isn't a `--flag value` pair
+fn positional(args: &[String]) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg.starts_with("--") {
+            args.next();
+        } else {
+            out.push(arg.as_str());
+        }
+    }
+    out
+}
+
+/// the site given by `--site`, or the first one
+fn flag_site<'a>(config: &'a Config, args: &[String]) -> Result<&'a SiteConfig, String> {
+    match flag(args, "--site") {
+        Some(domain) => config
+            .get_site(domain)
+            .ok_or_else(|| format!("site {} not found", domain)),
+        None => Ok(&config.sites[0]),
+    }
+}
+
+/// `ivyhost import <logs|goatcounter|plausible> [--site domain] <files>`
+/// imports access logs, a goatcounter csv export or plausible's
+/// `imported_pages` csv. files may be gzipped
+async fn run_import(config: &Config, args: &[String]) -> Result<(), String> {
+    let usage = "usage: ivyhost import <logs|goatcounter|plausible> [--site domain] <file>...";
+    let site = flag_site(config, args)?;
+    let args = positional(args);
+    let (Some(&source), files) = (args.first(), args.get(1..).unwrap_or_default()) else {
+        return Err(usage.to_string());
     };
+    if files.is_empty() || !matches!(source, "logs" | "goatcounter" | "plausible") {
+        return Err(usage.to_string());
+    }
+    let conn = config.create_conn();
+    conn.init().await?;
+    for file in files {
+        let path = std::path::Path::new(file);
+        let outcome = match source {
+            "logs" => import_access_log(&conn, site, path).await?,
+            "goatcounter" => import_goatcounter(&conn, site, path).await?,
+            _ => import_plausible(&conn, site, path, config.timezone).await?,
+        };
+        match outcome.imported {
+            Some(count) => println!(
+                "{}: imported {} requests into {}, skipped {} rows that aren't page views and {} unreadable ones",
+                file, count, site.domain, outcome.skipped, outcome.invalid
+            ),
+            None => println!("{}: already imported into {}", file, site.domain),
+        }
+    }
+    Ok(())
+}
 
-    //git pull
-    let Ok(mut remote) = repo.find_remote("origin") else {
-        return Err("failed to find remote".to_string());
+/// `ivyhost export <requests|daily|goatcounter> [--site domain] [--from date]
+/// [--to date] [--format csv|ndjson]` writes analytics to stdout
+async fn run_export(config: &Config, args: &[String]) -> Result<(), String> {
+    let kind = args
+        .first()
+        .ok_or("usage: ivyhost export <requests|daily|goatcounter> [--site domain] [--from YYYY-MM-DD] [--to YYYY-MM-DD] [--format csv|ndjson]")?;
+    let site = flag_site(config, args)?;
+    let from = flag(args, "--from")
+        .map(|x| parse_time(x, false, config.timezone))
+        .transpose()?
+        .unwrap_or(0);
+    let to = flag(args, "--to")
+        .map(|x| parse_time(x, true, config.timezone))
+        .transpose()?
+        .unwrap_or_else(|| current_time_milis() + 1);
+    let format: ExportFormat = match kind.as_str() {
+        "goatcounter" => goatcounter_format(flag(args, "--format"))?,
+        _ => flag(args, "--format")
+            .map(|x| x.parse())
+            .transpose()?
+            .unwrap_or_default(),
     };
-    let Ok(fetch_commit) = do_fetch(&repo, &[branch], &mut remote) else {
-        return Err("failed to fetch commit".to_string());
+
+    // no migrations here, they'd print to stdout in the middle of the export
+    let conn = config.create_conn();
+    let mut chunks = match kind.as_str() {
+        "goatcounter" => {
+            export_goatcounter(&conn, &site.domain, from, to, visitor_salt(config)).await
+        }
+        kind => {
+            let kind: ExportKind = kind.parse().map_err(|_| {
+                format!(
+                    "unknown export {}, use requests, daily or goatcounter",
+                    kind
+                )
+            })?;
+            export(
+                &conn,
+                &site.domain,
+                kind,
+                format,
+                from,
+                to,
+                config.timezone,
+                visitor_salt(config),
+            )
+            .await
+        }
     };
-    let merge_res = do_merge(&repo, &branch, fetch_commit);
-    match merge_res {
-        Ok(_) => Ok(()),
-        Err(err) => Err(err.to_string()),
+    let mut stdout = std::io::stdout().lock();
+    while let Some(chunk) = chunks.next().await {
+        match stdout.write_all(&chunk) {
+            // piped into something like `head` that stopped reading
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(format!("failed to write export: {}", e)),
+            Ok(()) => {}
+        }
     }
+    stdout
+        .flush()
+        .map_err(|e| format!("failed to write export: {}", e))
 }
 
+#[derive(Deserialize, Debug)]
+struct RefreshInfo {
+    site: Option<String>,
+}
+
+/// deploys the site given by the `site` query param, or the one matching the
+/// request's host when it's omitted. branch previews are synced along with it
 #[post("/refresh")]
-pub async fn refresh(state: Data<Config>) -> Result<HttpResponse, Error> {
-    let res = git_refresh(&state.site_repo, &state.branch);
-    if let Err(res) = res {
-        println!("{}", res);
+pub async fn refresh(
+    req: HttpRequest,
+    info: web::Query<RefreshInfo>,
+    state: Data<Config>,
+    conn: Data<PgConn>,
+) -> Result<HttpResponse, Error> {
+    let site = match &info.site {
+        Some(domain) => match state.get_site(domain) {
+            Some(site) => site,
+            None => return Ok(HttpResponse::NotFound().body("unknown site")),
+        },
+        None => state.site_for_host(req.connection_info().host()),
+    };
+    let outcome = deploy(site, &conn, DeployTrigger::Webhook).await;
+    sync_previews(site, &conn, DeployTrigger::Webhook).await;
+    match outcome.error {
+        Some(err) => Ok(HttpResponse::InternalServerError().body(err)),
+        None => Ok(HttpResponse::Ok().body("refreshed")),
     }
-    Ok(HttpResponse::Ok().body("refreshed"))
 }
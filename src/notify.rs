@@ -0,0 +1,328 @@
+use std::time::Duration;
+
+use actix_web::rt::time::sleep;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{config::SiteConfig, deploy::DeployTrigger};
+
+const MAX_ATTEMPTS: u32 = 4;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .user_agent(concat!("ivyhost/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .expect("failed to build http client");
+}
+
+/// an outgoing webhook fired when a site deploys
+#[derive(Deserialize, Debug, Clone)]
+pub struct NotificationConfig {
+    pub url: String,
+    #[serde(default)]
+    pub format: NotificationFormat,
+    /// defaults to every event
+    #[serde(default = "all_events")]
+    pub events: Vec<DeployEvent>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationFormat {
+    /// the [`DeployNotification`] as is
+    #[default]
+    Json,
+    Discord,
+    Slack,
+    /// matrix-hookshot style generic webhooks
+    Matrix,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployEvent {
+    Start,
+    Success,
+    /// nothing gets published, the site keeps serving the last good deploy
+    Failure,
+    /// the site went back to the version before its last deploy, either
+    /// because the deploy failed after publishing or by hand
+    Rollback,
+}
+
+fn all_events() -> Vec<DeployEvent> {
+    vec![
+        DeployEvent::Start,
+        DeployEvent::Success,
+        DeployEvent::Failure,
+        DeployEvent::Rollback,
+    ]
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DeployNotification {
+    pub event: DeployEvent,
+    pub site: String,
+    pub trigger: &'static str,
+    pub commit_id: Option<String>,
+    pub commit_message: Option<String>,
+    pub duration_milis: Option<i64>,
+    pub error: Option<String>,
+}
+
+impl DeployNotification {
+    pub fn new(event: DeployEvent, site: &SiteConfig, trigger: DeployTrigger) -> Self {
+        DeployNotification {
+            event,
+            site: site.domain.clone(),
+            trigger: trigger.as_str(),
+            commit_id: None,
+            commit_message: None,
+            duration_milis: None,
+            error: None,
+        }
+    }
+
+    /// a one line human readable summary for chat formats
+    pub fn summary(&self) -> String {
+        let commit = match (&self.commit_id, &self.commit_message) {
+            (Some(id), Some(message)) => format!(" {} \"{}\"", &id[..id.len().min(7)], message),
+            (Some(id), None) => format!(" {}", &id[..id.len().min(7)]),
+            _ => String::new(),
+        };
+        let duration = match self.duration_milis {
+            Some(x) => format!(" in {:.1}s", x as f64 / 1000.0),
+            None => String::new(),
+        };
+        match self.event {
+            DeployEvent::Start => format!("{}: deploy started ({})", self.site, self.trigger),
+            DeployEvent::Success => format!("{}: deployed{}{}", self.site, commit, duration),
+            DeployEvent::Failure => format!(
+                "{}: deploy of{} failed{}: {}",
+                self.site,
+                commit,
+                duration,
+                self.error.as_deref().unwrap_or("unknown error")
+            ),
+            DeployEvent::Rollback => format!(
+                "{}: rolled back to the previous deploy ({})",
+                self.site, self.trigger
+            ),
+        }
+    }
+
+    fn body(&self, format: NotificationFormat) -> serde_json::Value {
+        match format {
+            NotificationFormat::Json => json!(self),
+            NotificationFormat::Discord => json!({ "content": self.summary() }),
+            NotificationFormat::Slack => json!({ "text": self.summary() }),
+            NotificationFormat::Matrix => json!({
+                "text": self.summary(),
+                "msgtype": "m.notice",
+            }),
+        }
+    }
+}
+
+/// sends `notification` to every webhook subscribed to its event. the
+/// requests run in the background so a slow endpoint never holds up a deploy
+pub fn notify(notifications: &[NotificationConfig], notification: DeployNotification) {
+    for config in notifications {
+        if !config.events.contains(&notification.event) {
+            continue;
+        }
+        let url = config.url.clone();
+        let body = notification.body(config.format);
+        actix_web::rt::spawn(async move {
+            if let Err(e) = send(&url, &body).await {
+                println!("failed to send deploy notification to {}: {}", url, e);
+            }
+        });
+    }
+}
+
+/// posts `body` to `url`, retrying with exponential backoff on connection
+/// errors, 429s and 5xx responses
+pub async fn send(url: &str, body: &serde_json::Value) -> Result<(), String> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let err = match CLIENT.post(url).json(body).send().await {
+            Ok(res) if res.status().is_success() => return Ok(()),
+            Ok(res) if res.status().is_server_error() || res.status().as_u16() == 429 => {
+                format!("server responded {}", res.status())
+            }
+            Ok(res) => return Err(format!("server responded {}", res.status())),
+            Err(e) => e.to_string(),
+        };
+        if attempt >= MAX_ATTEMPTS {
+            return Err(format!("giving up after {} attempts: {}", attempt, err));
+        }
+        sleep(Duration::from_secs(1 << (attempt - 1))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    use super::*;
+
+    /// a webhook endpoint on localhost answering each request with the next
+    /// status in `statuses`, and sending the bodies it got down the channel
+    fn stand_in(statuses: Vec<u16>) -> (String, mpsc::Receiver<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let _ = tx.send(serde_json::from_slice(&body).unwrap());
+                let response = format!(
+                    "HTTP/1.1 {} stand-in\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn failure() -> DeployNotification {
+        DeployNotification {
+            event: DeployEvent::Failure,
+            site: "ivytime.gay".to_string(),
+            trigger: "webhook",
+            commit_id: Some("0123456789abcdef".to_string()),
+            commit_message: Some("add a post".to_string()),
+            duration_milis: Some(1500),
+            error: Some("build exited with 1".to_string()),
+        }
+    }
+
+    #[actix_web::test]
+    async fn sends_every_format() {
+        let summary =
+            "ivytime.gay: deploy of 0123456 \"add a post\" failed in 1.5s: build exited with 1";
+        let formats = [
+            NotificationFormat::Json,
+            NotificationFormat::Discord,
+            NotificationFormat::Slack,
+            NotificationFormat::Matrix,
+        ];
+        let (url, rx) = stand_in(vec![200; formats.len()]);
+        for format in formats {
+            send(&url, &failure().body(format)).await.unwrap();
+        }
+
+        let json = rx.recv().unwrap();
+        assert_eq!(json["event"], "failure");
+        assert_eq!(json["commit_id"], "0123456789abcdef");
+        assert_eq!(json["commit_message"], "add a post");
+        assert_eq!(json["duration_milis"], 1500);
+        assert_eq!(json["error"], "build exited with 1");
+        assert_eq!(rx.recv().unwrap(), json!({ "content": summary }));
+        assert_eq!(rx.recv().unwrap(), json!({ "text": summary }));
+        assert_eq!(
+            rx.recv().unwrap(),
+            json!({ "text": summary, "msgtype": "m.notice" })
+        );
+    }
+
+    #[actix_web::test]
+    async fn retries_server_errors_only() {
+        let (url, rx) = stand_in(vec![503, 200]);
+        send(&url, &json!({})).await.expect("succeeds on the retry");
+        assert_eq!(rx.try_iter().count(), 2);
+
+        let (url, rx) = stand_in(vec![404]);
+        assert!(send(&url, &json!({})).await.is_err());
+        assert_eq!(rx.try_iter().count(), 1);
+    }
+
+    #[actix_web::test]
+    async fn only_notifies_subscribed_events() {
+        let (url, rx) = stand_in(vec![200]);
+        let configs = [NotificationConfig {
+            url,
+            format: NotificationFormat::Json,
+            events: vec![DeployEvent::Failure],
+        }];
+        let mut success = failure();
+        success.event = DeployEvent::Success;
+        notify(&configs, success);
+        notify(&configs, failure());
+        // the notifications are sent from tasks on this runtime, so wait on it
+        let mut received = Vec::new();
+        for _ in 0..50 {
+            received.extend(rx.try_iter());
+            if !received.is_empty() {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["event"], "failure");
+    }
+
+    #[actix_web::test]
+    async fn rollbacks_reach_every_target() {
+        let (first, first_rx) = stand_in(vec![200]);
+        let (second, second_rx) = stand_in(vec![200]);
+        let configs: Vec<_> = [
+            (first, NotificationFormat::Json),
+            (second, NotificationFormat::Slack),
+        ]
+        .into_iter()
+        .map(|(url, format)| NotificationConfig {
+            url,
+            format,
+            events: all_events(),
+        })
+        .collect();
+        let mut rollback = failure();
+        rollback.event = DeployEvent::Rollback;
+        rollback.trigger = "manual";
+        notify(&configs, rollback);
+
+        let mut json = None;
+        let mut slack = None;
+        for _ in 0..50 {
+            json = json.or_else(|| first_rx.try_recv().ok());
+            slack = slack.or_else(|| second_rx.try_recv().ok());
+            if json.is_some() && slack.is_some() {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(json.expect("json target notified")["event"], "rollback");
+        assert_eq!(
+            slack.expect("slack target notified"),
+            json!({ "text": "ivytime.gay: rolled back to the previous deploy (manual)" })
+        );
+    }
+}
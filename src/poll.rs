@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use actix_web::rt::time::sleep;
+use rand::Rng;
+
+use crate::{
+    config::SiteConfig,
+    db::pg::PgConn,
+    deploy::{deploy, has_new_commit, sync_previews, DeployTrigger},
+};
+
+/// never wait longer than this between polls, no matter how many fetches failed
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// polls the remote every `interval` and deploys whenever a new commit shows
+/// up, for hosts where webhooks can't be configured. runs forever, so it
+/// should be spawned onto the runtime
+pub async fn poll_remote(site: SiteConfig, conn: PgConn, interval: Duration) {
+    let mut failures: u32 = 0;
+    loop {
+        sleep(next_delay(interval, failures)).await;
+
+        match has_new_commit(&site).await {
+            Ok(true) => {
+                let outcome = deploy(&site, &conn, DeployTrigger::Poll).await;
+                match outcome.is_success() {
+                    true => failures = 0,
+                    false => failures = failures.saturating_add(1),
+                }
+            }
+            Ok(false) => failures = 0,
+            Err(e) => {
+                failures = failures.saturating_add(1);
+                println!(
+                    "{}: poll failed ({} in a row): {}",
+                    site.domain, failures, e
+                );
+            }
+        }
+        // previews follow every branch, so they're checked even when the site itself is unchanged
+        sync_previews(&site, &conn, DeployTrigger::Poll).await;
+    }
+}
+
+/// doubles the interval for every consecutive failure and adds up to 10%
+/// jitter so several instances don't all hit the remote at once
+fn next_delay(interval: Duration, failures: u32) -> Duration {
+    let backoff = interval
+        .saturating_mul(2u32.saturating_pow(failures.min(16)))
+        .min(MAX_BACKOFF.max(interval));
+    let jitter = rand::thread_rng().gen_range(0.0..0.1);
+    backoff.mul_f64(1.0 + jitter)
+}
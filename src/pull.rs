@@ -13,17 +13,138 @@
  */
 //borrowed from https://github.com/rust-lang/git2-rs/blob/master/examples/pull.rs
 
-use git2::Repository;
+use git2::{Cred, CredentialType, Repository};
 use std::io::{self, Write};
+use std::path::Path;
 use std::str;
 
-pub fn do_fetch<'a>(
-    repo: &'a git2::Repository,
-    refs: &[&str],
-    remote: &'a mut git2::Remote,
-) -> Result<git2::AnnotatedCommit<'a>, git2::Error> {
+/// credentials used to authenticate against the remote when cloning or fetching.
+/// everything is optional, public repositories need none of it
+#[derive(Debug, Clone, Default)]
+pub struct GitCredentials {
+    /// username for ssh remotes that don't specify one in the url, defaults to `git`
+    pub ssh_username: Option<String>,
+    pub ssh_key_path: Option<String>,
+    pub ssh_public_key_path: Option<String>,
+    pub ssh_key_passphrase: Option<String>,
+    /// try keys loaded into the running ssh agent before the key file
+    pub ssh_agent: bool,
+    pub https_username: Option<String>,
+    pub https_token: Option<String>,
+}
+
+/// builds the callbacks used for both clone and fetch, offering whatever
+/// [`CredentialPicker`] picks
+pub fn remote_callbacks(credentials: &GitCredentials) -> git2::RemoteCallbacks<'_> {
     let mut cb = git2::RemoteCallbacks::new();
 
+    let mut picker = CredentialPicker::default();
+    cb.credentials(move |_url, username_from_url, allowed| {
+        match picker.next(credentials, username_from_url, allowed) {
+            Some(Credential::Username(username)) => Cred::username(username),
+            Some(Credential::Agent(username)) => Cred::ssh_key_from_agent(username),
+            Some(Credential::KeyFile(username, key)) => Cred::ssh_key(
+                username,
+                credentials.ssh_public_key_path.as_deref().map(Path::new),
+                Path::new(key),
+                credentials.ssh_key_passphrase.as_deref(),
+            ),
+            Some(Credential::UserPass(username, token)) => {
+                Cred::userpass_plaintext(username, token)
+            }
+            None => Err(git2::Error::from_str(
+                "remote requires authentication but no usable credentials are configured",
+            )),
+        }
+    });
+
+    cb
+}
+
+/// a credential to offer the remote
+#[derive(Debug, PartialEq, Eq)]
+enum Credential<'a> {
+    Username(&'a str),
+    Agent(&'a str),
+    /// username and private key path
+    KeyFile(&'a str, &'a str),
+    /// username and token
+    UserPass(&'a str, &'a str),
+}
+
+/// picks the credentials to offer. libgit2 keeps calling the credentials
+/// callback until it gets something that works, so each method is only
+/// offered once to avoid looping forever on bad credentials
+#[derive(Debug, Default)]
+struct CredentialPicker {
+    tried_agent: bool,
+    tried_key: bool,
+    tried_userpass: bool,
+}
+
+impl CredentialPicker {
+    fn next<'a>(
+        &mut self,
+        credentials: &'a GitCredentials,
+        username_from_url: Option<&'a str>,
+        allowed: CredentialType,
+    ) -> Option<Credential<'a>> {
+        let ssh_username = username_from_url
+            .or(credentials.ssh_username.as_deref())
+            .unwrap_or("git");
+
+        if allowed.contains(CredentialType::USERNAME) {
+            return Some(Credential::Username(ssh_username));
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            if credentials.ssh_agent && !self.tried_agent {
+                self.tried_agent = true;
+                return Some(Credential::Agent(ssh_username));
+            }
+            if let Some(key) = &credentials.ssh_key_path {
+                if !self.tried_key {
+                    self.tried_key = true;
+                    return Some(Credential::KeyFile(ssh_username, key));
+                }
+            }
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && !self.tried_userpass {
+            if let Some(token) = &credentials.https_token {
+                self.tried_userpass = true;
+                // most forges accept any username alongside a token
+                let username = credentials
+                    .https_username
+                    .as_deref()
+                    .or(username_from_url)
+                    .unwrap_or("git");
+                return Some(Credential::UserPass(username, token));
+            }
+        }
+        None
+    }
+}
+
+/// clones `url` into `path` using the configured credentials
+pub fn do_clone(
+    url: &str,
+    path: &Path,
+    credentials: &GitCredentials,
+) -> Result<Repository, git2::Error> {
+    let mut fo = git2::FetchOptions::new();
+    fo.remote_callbacks(remote_callbacks(credentials));
+    git2::build::RepoBuilder::new()
+        .fetch_options(fo)
+        .clone(url, path)
+}
+
+/// fetches `refs` from the remote without touching the working tree
+pub fn fetch_refs(
+    refs: &[&str],
+    remote: &mut git2::Remote,
+    credentials: &GitCredentials,
+) -> Result<(), git2::Error> {
+    let mut cb = remote_callbacks(credentials);
+
     // Print out our transfer progress.
     cb.transfer_progress(|stats| {
         if stats.received_objects() == stats.total_objects() {
@@ -50,6 +171,8 @@ pub fn do_fetch<'a>(
     // Always fetch all tags.
     // Perform a download and also update tips
     fo.download_tags(git2::AutotagOption::All);
+    // drop remote tracking branches that were deleted on the remote
+    fo.prune(git2::FetchPrune::On);
     println!("Fetching {} for repo", remote.name().unwrap());
     remote.fetch(refs, Some(&mut fo), None)?;
 
@@ -73,9 +196,192 @@ pub fn do_fetch<'a>(
             stats.received_bytes()
         );
     }
+    Ok(())
+}
+
+pub fn do_fetch<'a>(
+    repo: &'a git2::Repository,
+    refs: &[&str],
+    remote: &'a mut git2::Remote,
+    credentials: &GitCredentials,
+) -> Result<git2::AnnotatedCommit<'a>, git2::Error> {
+    fetch_refs(refs, remote, credentials)?;
+
+    // with tags being downloaded too, the first line of FETCH_HEAD can be a
+    // tag rather than the ref we asked for, so look for the one marked for merge
+    let mut merge_oid = None;
+    repo.fetchhead_foreach(|_name, _url, oid, is_merge| {
+        if is_merge && merge_oid.is_none() {
+            merge_oid = Some(*oid);
+        }
+        true
+    })?;
+    match merge_oid {
+        Some(oid) => repo.find_annotated_commit(oid),
+        None => {
+            let fetch_head = repo.find_reference("FETCH_HEAD")?;
+            repo.reference_to_annotated_commit(&fetch_head)
+        }
+    }
+}
+
+/// which commit of the remote gets deployed
+#[derive(Debug, Clone)]
+pub enum RefSelector {
+    /// follow the tip of a branch, merging like `git pull`
+    Branch(String),
+    /// the highest semver tag matching a glob, eg `v*`, and whether
+    /// prereleases like `v2.0.0-rc1` count
+    LatestTag(String, bool),
+    /// an exact tag, branch or commit id
+    Pinned(String),
+}
+
+/// fetches every branch and tag, used when deploying something other than a branch tip
+pub fn fetch_all(
+    remote: &mut git2::Remote,
+    credentials: &GitCredentials,
+) -> Result<(), git2::Error> {
+    fetch_refs(
+        &[
+            "+refs/heads/*:refs/remotes/origin/*",
+            "+refs/tags/*:refs/tags/*",
+        ],
+        remote,
+        credentials,
+    )
+}
+
+/// every branch on the remote as of the last [`fetch_all`], with the commit it points to
+pub fn remote_branches(repo: &Repository) -> Result<Vec<(String, git2::Oid)>, git2::Error> {
+    let mut branches = Vec::new();
+    for branch in repo.branches(Some(git2::BranchType::Remote))? {
+        let (branch, _) = branch?;
+        let Some(name) = branch.name()? else {
+            continue;
+        };
+        let Some(name) = name.strip_prefix("origin/") else {
+            continue;
+        };
+        if name == "HEAD" {
+            continue;
+        }
+        let oid = branch.get().peel_to_commit()?.id();
+        branches.push((name.to_string(), oid));
+    }
+    Ok(branches)
+}
+
+/// finds the tag matching `pattern` with the highest semver version. any
+/// prefix before the version such as the `v` in `v1.2.0` is ignored, tags
+/// that don't parse as semver are skipped. prereleases like `v2.0.0-rc1` are
+/// only considered with `allow_prerelease`
+pub fn latest_tag(
+    repo: &Repository,
+    pattern: &str,
+    allow_prerelease: bool,
+) -> Result<git2::Oid, git2::Error> {
+    let matcher = globset::Glob::new(pattern)
+        .map_err(|e| git2::Error::from_str(&format!("invalid tag pattern: {}", e)))?
+        .compile_matcher();
+
+    let mut latest: Option<(semver::Version, String)> = None;
+    for name in repo.tag_names(None)?.iter().flatten() {
+        if !matcher.is_match(name) {
+            continue;
+        }
+        let Some(version_start) = name.find(|c: char| c.is_ascii_digit()) else {
+            continue;
+        };
+        let Ok(version) = semver::Version::parse(&name[version_start..]) else {
+            continue;
+        };
+        if !version.pre.is_empty() && !allow_prerelease {
+            continue;
+        }
+        if latest.as_ref().is_none_or(|(x, _)| version > *x) {
+            latest = Some((version, name.to_string()));
+        }
+    }
+
+    let Some((_, name)) = latest else {
+        return Err(git2::Error::from_str(&format!(
+            "no semver tags match {}",
+            pattern
+        )));
+    };
+    println!("latest tag matching {} is {}", pattern, name);
+    let commit = repo
+        .find_reference(&format!("refs/tags/{}", name))?
+        .peel_to_commit()?;
+    Ok(commit.id())
+}
+
+/// resolves a tag, remote branch or commit id to a commit. tags win over
+/// branches of the same name
+pub fn resolve_pinned(repo: &Repository, name: &str) -> Result<git2::Oid, git2::Error> {
+    for refname in [
+        format!("refs/tags/{}", name),
+        format!("refs/remotes/origin/{}", name),
+    ] {
+        if let Ok(reference) = repo.find_reference(&refname) {
+            return Ok(reference.peel_to_commit()?.id());
+        }
+    }
+    Ok(repo.revparse_single(name)?.peel_to_commit()?.id())
+}
+
+/// checks out `oid` with a detached HEAD, throwing away any local changes
+pub fn checkout_detached(repo: &Repository, oid: git2::Oid) -> Result<(), git2::Error> {
+    println!("Checking out {}", oid);
+    repo.set_head_detached(oid)?;
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+    Ok(())
+}
+
+/// initializes and updates every submodule to the commit recorded in the
+/// checkout, recursing into nested submodules. a failing submodule doesn't stop
+/// the others from updating, all the errors are collected into the result
+pub fn update_submodules(repo: &Repository, credentials: &GitCredentials) -> Result<(), String> {
+    let submodules = repo
+        .submodules()
+        .map_err(|e| format!("failed to list submodules: {}", e))?;
+
+    let mut errors = Vec::new();
+    for mut submodule in submodules {
+        let name = submodule.name().unwrap_or("unnamed").to_string();
+        println!("Updating submodule {}", name);
+
+        let mut fo = git2::FetchOptions::new();
+        fo.remote_callbacks(remote_callbacks(credentials));
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.force();
+        let mut opts = git2::SubmoduleUpdateOptions::new();
+        opts.fetch(fo).checkout(checkout).allow_fetch(true);
+
+        // pick up url changes made in .gitmodules since the last update
+        if let Err(e) = submodule.sync() {
+            errors.push(format!("{}: failed to sync: {}", name, e));
+            continue;
+        }
+        if let Err(e) = submodule.update(true, Some(&mut opts)) {
+            errors.push(format!("{}: {}", name, e));
+            continue;
+        }
+        match submodule.open() {
+            Ok(sub_repo) => {
+                if let Err(e) = update_submodules(&sub_repo, credentials) {
+                    errors.push(format!("{}/{}", name, e));
+                }
+            }
+            Err(e) => errors.push(format!("{}: failed to open: {}", name, e)),
+        }
+    }
 
-    let fetch_head = repo.find_reference("FETCH_HEAD")?;
-    Ok(repo.reference_to_annotated_commit(&fetch_head)?)
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors.join("\n")),
+    }
 }
 
 pub fn fast_forward(
@@ -177,9 +483,201 @@ pub fn do_merge<'a>(
     } else if analysis.0.is_normal() {
         // do a normal merge
         let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
-        normal_merge(&repo, &head_commit, &fetch_commit)?;
+        normal_merge(repo, &head_commit, &fetch_commit)?;
     } else {
         println!("Nothing to do...");
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{commit_files, file_url, init_repo, TempDir};
+
+    #[test]
+    fn clones_and_fetches_from_a_file_remote() {
+        let dir = TempDir::new("pull");
+        let origin = init_repo(&dir.path().join("origin"));
+        commit_files(&origin, &[("public/index.html", "first")], "first");
+        let credentials = GitCredentials::default();
+
+        let clone_path = dir.path().join("clone");
+        let repo = do_clone(
+            &file_url(&dir.path().join("origin")),
+            &clone_path,
+            &credentials,
+        )
+        .expect("failed to clone");
+        assert_eq!(
+            std::fs::read_to_string(clone_path.join("public/index.html")).unwrap(),
+            "first"
+        );
+
+        let second = commit_files(&origin, &[("public/index.html", "second")], "second");
+        let mut remote = repo.find_remote("origin").unwrap();
+        let fetched =
+            do_fetch(&repo, &["main"], &mut remote, &credentials).expect("failed to fetch");
+        assert_eq!(fetched.id(), second);
+        do_merge(&repo, "main", fetched).expect("failed to merge");
+        assert_eq!(
+            std::fs::read_to_string(clone_path.join("public/index.html")).unwrap(),
+            "second"
+        );
+    }
+
+    #[test]
+    fn latest_tag_skips_prereleases_unless_asked() {
+        let dir = TempDir::new("tags");
+        let repo = init_repo(dir.path());
+        let mut tagged = std::collections::HashMap::new();
+        for tag in ["v1.2.0", "v1.10.0", "v2.0.0-rc1", "not-a-version"] {
+            let commit = commit_files(&repo, &[("index.html", tag)], tag);
+            let target = repo.find_object(commit, None).unwrap();
+            repo.tag_lightweight(tag, &target, false).unwrap();
+            tagged.insert(tag, commit);
+        }
+
+        assert_eq!(latest_tag(&repo, "v*", false).unwrap(), tagged["v1.10.0"]);
+        assert!(latest_tag(&repo, "v2*", false).is_err());
+        // a dash in the pattern doesn't let prereleases in by itself
+        assert!(latest_tag(&repo, "v*-rc*", false).is_err());
+        assert_eq!(latest_tag(&repo, "v*", true).unwrap(), tagged["v2.0.0-rc1"]);
+    }
+
+    #[test]
+    fn offers_ssh_credentials_once_each() {
+        let credentials = GitCredentials {
+            ssh_username: Some("deploy".to_string()),
+            ssh_key_path: Some("/keys/id_ed25519".to_string()),
+            ssh_agent: true,
+            ..Default::default()
+        };
+        let mut picker = CredentialPicker::default();
+        assert_eq!(
+            picker.next(&credentials, None, CredentialType::USERNAME),
+            Some(Credential::Username("deploy"))
+        );
+        // the username in the url wins over the configured one
+        assert_eq!(
+            picker.next(&credentials, Some("git"), CredentialType::SSH_KEY),
+            Some(Credential::Agent("git"))
+        );
+        assert_eq!(
+            picker.next(&credentials, Some("git"), CredentialType::SSH_KEY),
+            Some(Credential::KeyFile("git", "/keys/id_ed25519"))
+        );
+        assert_eq!(
+            picker.next(&credentials, Some("git"), CredentialType::SSH_KEY),
+            None
+        );
+    }
+
+    #[test]
+    fn skips_the_agent_unless_enabled() {
+        let credentials = GitCredentials {
+            ssh_key_path: Some("/keys/id_ed25519".to_string()),
+            ..Default::default()
+        };
+        let mut picker = CredentialPicker::default();
+        assert_eq!(
+            picker.next(&credentials, None, CredentialType::SSH_KEY),
+            Some(Credential::KeyFile("git", "/keys/id_ed25519"))
+        );
+        assert_eq!(
+            picker.next(&credentials, None, CredentialType::SSH_KEY),
+            None
+        );
+    }
+
+    #[test]
+    fn offers_a_token_once() {
+        let credentials = GitCredentials {
+            https_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let allowed = CredentialType::USER_PASS_PLAINTEXT;
+        let mut picker = CredentialPicker::default();
+        assert_eq!(
+            picker.next(&credentials, Some("ivy"), allowed),
+            Some(Credential::UserPass("ivy", "secret"))
+        );
+        assert_eq!(picker.next(&credentials, Some("ivy"), allowed), None);
+
+        let credentials = GitCredentials {
+            https_username: Some("x-access-token".to_string()),
+            ..credentials
+        };
+        let mut picker = CredentialPicker::default();
+        assert_eq!(
+            picker.next(&credentials, Some("ivy"), allowed),
+            Some(Credential::UserPass("x-access-token", "secret"))
+        );
+        // nothing configured for ssh, so a key can't be offered
+        assert_eq!(
+            picker.next(&credentials, None, CredentialType::SSH_KEY),
+            None
+        );
+    }
+
+    /// adds the repo at `url` as a submodule at `path` and commits it
+    fn add_submodule(repo: &Repository, url: &str, path: &str) {
+        let mut submodule = repo.submodule(url, Path::new(path), true).unwrap();
+        submodule.clone(None).expect("failed to clone submodule");
+        submodule.add_finalize().unwrap();
+        commit_files(repo, &[], &format!("add {}", path));
+    }
+
+    #[test]
+    fn updates_submodules_recursively_and_collects_errors() {
+        let dir = TempDir::new("submodules");
+        let nested = init_repo(&dir.path().join("nested"));
+        commit_files(&nested, &[("fonts.css", "nested")], "nested");
+        let theme = init_repo(&dir.path().join("theme"));
+        commit_files(&theme, &[("theme.css", "theme")], "theme");
+        add_submodule(&theme, &file_url(&dir.path().join("nested")), "nested");
+        let origin = init_repo(&dir.path().join("origin"));
+        commit_files(&origin, &[("index.html", "home")], "first");
+        add_submodule(&origin, &file_url(&dir.path().join("theme")), "themes/ivy");
+
+        let credentials = GitCredentials::default();
+        let clone_path = dir.path().join("clone");
+        let repo = do_clone(
+            &file_url(&dir.path().join("origin")),
+            &clone_path,
+            &credentials,
+        )
+        .expect("failed to clone");
+        update_submodules(&repo, &credentials).expect("failed to update submodules");
+        let read = |x: &str| std::fs::read_to_string(clone_path.join(x)).unwrap();
+        assert_eq!(read("themes/ivy/theme.css"), "theme");
+        assert_eq!(read("themes/ivy/nested/fonts.css"), "nested");
+
+        // a submodule that can't be fetched fails the update, the others
+        // are still brought up to date
+        let broken = init_repo(&dir.path().join("broken"));
+        commit_files(&broken, &[("broken.txt", "broken")], "broken");
+        add_submodule(&origin, &file_url(&dir.path().join("broken")), "broken");
+        std::fs::remove_dir_all(dir.path().join("broken")).unwrap();
+        commit_files(&theme, &[("theme.css", "theme v2")], "theme v2");
+        let theme_head = theme.head().unwrap().target().unwrap();
+        let mut index = origin.index().unwrap();
+        let mut entry = index.get_path(Path::new("themes/ivy"), 0).unwrap();
+        entry.id = theme_head;
+        index.add(&entry).unwrap();
+        index.write().unwrap();
+        let tree = origin.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let parent = origin.head().unwrap().peel_to_commit().unwrap();
+        origin
+            .commit(Some("HEAD"), &sig, &sig, "bump theme", &tree, &[&parent])
+            .unwrap();
+
+        let mut remote = repo.find_remote("origin").unwrap();
+        let fetched = do_fetch(&repo, &["main"], &mut remote, &credentials).unwrap();
+        do_merge(&repo, "main", fetched).unwrap();
+        let error = update_submodules(&repo, &credentials).unwrap_err();
+        assert!(error.starts_with("broken:"), "{}", error);
+        assert_eq!(read("themes/ivy/theme.css"), "theme v2");
+    }
+}
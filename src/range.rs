@@ -0,0 +1,648 @@
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, Offset, TimeZone};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::export::parse_time;
+
+/// more bars than this don't fit on a graph
+pub const MAX_BUCKETS: i64 = 400;
+
+/// ranges that can be picked with one click, all of them in whole days in the
+/// reporting timezone
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    #[serde(rename = "today")]
+    Today,
+    #[serde(rename = "7d")]
+    Last7Days,
+    #[serde(rename = "30d")]
+    Last30Days,
+    #[serde(rename = "month")]
+    ThisMonth,
+    #[serde(rename = "year")]
+    LastYear,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 5] = [
+        Preset::Today,
+        Preset::Last7Days,
+        Preset::Last30Days,
+        Preset::ThisMonth,
+        Preset::LastYear,
+    ];
+
+    /// the value used in query strings
+    pub fn key(&self) -> &'static str {
+        match self {
+            Preset::Today => "today",
+            Preset::Last7Days => "7d",
+            Preset::Last30Days => "30d",
+            Preset::ThisMonth => "month",
+            Preset::LastYear => "year",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Preset::Today => "today",
+            Preset::Last7Days => "last 7 days",
+            Preset::Last30Days => "last 30 days",
+            Preset::ThisMonth => "this month",
+            Preset::LastYear => "last year",
+        }
+    }
+
+    /// the days covered, from the start of the first to the start of the day
+    /// after the last
+    fn days(&self, today: NaiveDate) -> (NaiveDate, NaiveDate) {
+        let tomorrow = today + Duration::days(1);
+        match self {
+            Preset::Today => (today, tomorrow),
+            Preset::Last7Days => (today - Duration::days(6), tomorrow),
+            Preset::Last30Days => (today - Duration::days(29), tomorrow),
+            Preset::ThisMonth => {
+                let first = today.with_day(1).expect("every month has a first day");
+                (first, first + Months::new(1))
+            }
+            Preset::LastYear => (today - Duration::days(364), tomorrow),
+        }
+    }
+}
+
+/// how much time each bar of a graph covers. bars line up with the calendar
+/// in the reporting timezone, so around dst changes a day can be 23 or 25
+/// hours long
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Interval {
+    Hour,
+    Day,
+    /// iso weeks, starting on monday
+    Week,
+    /// calendar months
+    Month,
+}
+
+impl Interval {
+    pub const ALL: [Interval; 4] = [
+        Interval::Hour,
+        Interval::Day,
+        Interval::Week,
+        Interval::Month,
+    ];
+
+    pub fn key(&self) -> &'static str {
+        match self {
+            Interval::Hour => "hour",
+            Interval::Day => "day",
+            Interval::Week => "week",
+            Interval::Month => "month",
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            Interval::Hour => "Hourly",
+            Interval::Day => "Daily",
+            Interval::Week => "Weekly",
+            Interval::Month => "Monthly",
+        }
+    }
+
+    /// the usual length of a bar, only used to pick an interval
+    fn millis(&self) -> i64 {
+        let hour = 60 * 60 * 1000;
+        match self {
+            Interval::Hour => hour,
+            Interval::Day => 24 * hour,
+            Interval::Week => 7 * 24 * hour,
+            Interval::Month => 30 * 24 * hour,
+        }
+    }
+
+    /// an interval giving a sensible number of bars for a range this long
+    fn for_span(span: i64) -> Interval {
+        let day = Interval::Day.millis();
+        match span {
+            x if x <= 2 * day => Interval::Hour,
+            x if x <= 92 * day => Interval::Day,
+            x if x <= 731 * day => Interval::Week,
+            _ => Interval::Month,
+        }
+    }
+
+    /// the start of the bar `time` falls in
+    fn floor(&self, time: i64, tz: Tz) -> Option<i64> {
+        let local = DateTime::from_timestamp_millis(time)?.with_timezone(&tz);
+        let day = local.date_naive();
+        Some(match self {
+            Interval::Hour => {
+                // some timezones are a half or quarter hour off utc, so hours
+                // are floored in local time
+                let hour = Interval::Hour.millis();
+                let offset = local.offset().fix().local_minus_utc() as i64 * 1000;
+                (time + offset).div_euclid(hour) * hour - offset
+            }
+            Interval::Day => start_of_day(day, tz),
+            Interval::Week => start_of_day(
+                day - Duration::days(day.weekday().num_days_from_monday() as i64),
+                tz,
+            ),
+            Interval::Month => start_of_day(day.with_day(1)?, tz),
+        })
+    }
+
+    /// the start of the bar after the one starting at `start`
+    fn next(&self, start: i64, tz: Tz) -> Option<i64> {
+        let day = DateTime::from_timestamp_millis(start)?
+            .with_timezone(&tz)
+            .date_naive();
+        let next = match self {
+            Interval::Hour => self.floor(start + Interval::Hour.millis(), tz)?,
+            Interval::Day => start_of_day(day.succ_opt()?, tz),
+            Interval::Week => start_of_day(day + Duration::days(7), tz),
+            Interval::Month => start_of_day(day.checked_add_months(Months::new(1))?, tz),
+        };
+        // a dst change can pull the floored hour back onto `start`
+        Some(next.max(start + Interval::Hour.millis()))
+    }
+
+    /// the start of every bar covering `from` to `to`, followed by the end of
+    /// the last one. `None` if it takes more than `max` bars
+    pub fn bounds(&self, from: i64, to: i64, tz: Tz, max: i64) -> Option<Vec<i64>> {
+        let mut bounds = vec![self.floor(from, tz)?];
+        while bounds[bounds.len() - 1] < to {
+            if bounds.len() as i64 > max {
+                return None;
+            }
+            bounds.push(self.next(bounds[bounds.len() - 1], tz)?);
+        }
+        Some(bounds)
+    }
+}
+
+/// the range query params, shared by the dashboard pages that show graphs
+#[derive(Deserialize, Debug, Default)]
+pub struct RangeInfo {
+    pub range: Option<Preset>,
+    /// `YYYY-MM-DD` or milliseconds, takes priority over `range`
+    pub from: Option<String>,
+    /// `YYYY-MM-DD` inclusive or milliseconds, defaults to now
+    pub to: Option<String>,
+    /// picked from the length of the range if it's left out
+    pub interval: Option<Interval>,
+    /// also show the period before, to compare with
+    #[serde(default)]
+    pub compare: bool,
+}
+
+/// the time a graph covers and how it's split into bars
+#[derive(Debug, Clone)]
+pub struct GraphRange {
+    pub from: i64,
+    pub to: i64,
+    pub interval: Interval,
+    /// the preset the range came from, if any
+    pub preset: Option<Preset>,
+    /// whether the interval was picked rather than worked out
+    explicit_interval: bool,
+    pub compare: bool,
+    tz: Tz,
+    bounds: Vec<i64>,
+}
+
+impl GraphRange {
+    /// the range asked for, defaulting to the last 30 days. dates are days in
+    /// `tz`
+    pub fn from_info(info: &RangeInfo, now: i64, tz: Tz) -> Result<GraphRange, String> {
+        let (from, to, preset) = match &info.from {
+            Some(from) => {
+                let from = parse_time(from, false, tz)?;
+                let to = match &info.to {
+                    Some(to) => parse_time(to, true, tz)?,
+                    None => now,
+                };
+                (from, to, None)
+            }
+            None => {
+                let preset = info.range.unwrap_or(Preset::Last30Days);
+                let today = day_in(now, tz).ok_or("time out of range")?;
+                let (first, last) = preset.days(today);
+                (
+                    start_of_day(first, tz),
+                    start_of_day(last, tz),
+                    Some(preset),
+                )
+            }
+        };
+        if from >= to {
+            return Err("the range must end after it starts".to_string());
+        }
+        // millisecond times can be far enough apart to overflow
+        let span = to.checked_sub(from).ok_or("the range is too long")?;
+        let interval = info.interval.unwrap_or(Interval::for_span(span));
+        let bounds = interval.bounds(from, to, tz, MAX_BUCKETS).ok_or_else(|| {
+            format!(
+                "too many {}s in that range, pick a longer interval",
+                interval.key()
+            )
+        })?;
+        Ok(GraphRange {
+            from,
+            to,
+            interval,
+            preset,
+            explicit_interval: info.interval.is_some(),
+            compare: info.compare,
+            tz,
+            bounds,
+        })
+    }
+
+    /// the bars of the graph, see `Interval::bounds`. the first and last may
+    /// run past the range to fill out the calendar
+    pub fn bounds(&self) -> &[i64] {
+        &self.bounds
+    }
+
+    /// as many bars again, ending where these start
+    pub fn previous_bounds(&self) -> Option<Vec<i64>> {
+        let mut bounds = vec![self.bounds[0]];
+        for _ in 1..self.bounds.len() {
+            bounds.push(self.interval.floor(bounds[bounds.len() - 1] - 1, self.tz)?);
+        }
+        bounds.reverse();
+        Some(bounds)
+    }
+
+    /// how much of the range has gone by at `now`, everything once it's over
+    pub fn elapsed(&self, now: i64) -> i64 {
+        let (first, last) = (self.bounds[0], self.bounds[self.bounds.len() - 1]);
+        match now >= last {
+            true => i64::MAX,
+            false => now.max(first) - first,
+        }
+    }
+
+    /// the days of `previous_bounds` compared with, eg `2024-09-01 to 2024-09-30`
+    pub fn previous_title(&self, previous: &[i64], now: i64) -> String {
+        let (first, last) = (previous[0], previous[previous.len() - 1]);
+        let end = first.saturating_add(self.elapsed(now)).min(last);
+        format!(
+            "{} to {}",
+            self.format_day(first),
+            self.format_day(end.max(first + 1) - 1)
+        )
+    }
+
+    /// a title for the graphs, eg `Daily, last 30 days`
+    pub fn title(&self) -> String {
+        match self.preset {
+            Some(preset) => format!("{}, {}", self.interval.title(), preset.label()),
+            None => format!(
+                "{}, {} to {}",
+                self.interval.title(),
+                self.first_day(),
+                self.last_day()
+            ),
+        }
+    }
+
+    /// the first day covered, as `YYYY-MM-DD`
+    pub fn first_day(&self) -> String {
+        self.format_day(self.from)
+    }
+
+    /// the last day covered, as `YYYY-MM-DD`
+    pub fn last_day(&self) -> String {
+        self.format_day(self.to - 1)
+    }
+
+    fn format_day(&self, time: i64) -> String {
+        day_in(time, self.tz)
+            .map(|x| x.format("%Y-%m-%d").to_string())
+            .unwrap_or_default()
+    }
+
+    /// whether `time` is midnight in the reporting timezone
+    fn is_midnight(&self, time: i64) -> bool {
+        day_in(time, self.tz).is_some_and(|x| start_of_day(x, self.tz) == time)
+    }
+
+    /// query params reproducing this range, so links can keep it
+    pub fn query(&self) -> String {
+        self.query_with(self.compare)
+    }
+
+    /// like `query` but comparing or not
+    pub fn query_with(&self, compare: bool) -> String {
+        let mut query = self.range_query();
+        if self.explicit_interval {
+            query += &format!("&interval={}", self.interval.key());
+        }
+        if compare {
+            query += "&compare=true";
+        }
+        query
+    }
+
+    /// like `query` but leaving out the interval and comparison
+    pub fn range_query(&self) -> String {
+        match self.preset {
+            Some(preset) => format!("range={}", preset.key()),
+            None if self.is_midnight(self.from) && self.is_midnight(self.to) => {
+                format!("from={}&to={}", self.first_day(), self.last_day())
+            }
+            None => format!("from={}&to={}", self.from, self.to),
+        }
+    }
+}
+
+/// the start of `day` in `tz`. when a dst change skips midnight the day
+/// starts at the first time that exists, and when midnight happens twice it
+/// starts at the first one
+pub fn start_of_day(day: NaiveDate, tz: Tz) -> i64 {
+    let mut time = day.and_hms_opt(0, 0, 0).expect("midnight is a valid time");
+    loop {
+        if let Some(x) = tz.from_local_datetime(&time).earliest() {
+            return x.timestamp_millis();
+        }
+        // dst changes move clocks by at most a few hours, in quarter hours
+        time += Duration::minutes(15);
+    }
+}
+
+/// the day `time` falls on in `tz`
+pub fn day_in(time: i64, tz: Tz) -> Option<NaiveDate> {
+    DateTime::from_timestamp_millis(time).map(|x| x.with_timezone(&tz).date_naive())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Query;
+
+    use super::*;
+
+    const HOUR: i64 = 60 * 60 * 1000;
+
+    fn amsterdam() -> Tz {
+        "Europe/Amsterdam".parse().unwrap()
+    }
+
+    fn day(date: &str) -> i64 {
+        start_of_day(date.parse().unwrap(), amsterdam())
+    }
+
+    /// 2024-10-10 14:00 in amsterdam
+    const NOW: i64 = 1728561600000;
+
+    fn range(query: &str) -> Result<GraphRange, String> {
+        let info = Query::<RangeInfo>::from_query(query).unwrap();
+        GraphRange::from_info(&info, NOW, amsterdam())
+    }
+
+    #[test]
+    fn presets_cover_whole_local_days() {
+        let last_30_days = range("").unwrap();
+        assert_eq!(last_30_days.preset, Some(Preset::Last30Days));
+        assert_eq!(
+            (last_30_days.from, last_30_days.to),
+            (day("2024-09-11"), day("2024-10-11"))
+        );
+        assert_eq!(last_30_days.interval, Interval::Day);
+        assert_eq!(last_30_days.bounds().len(), 31);
+
+        let today = range("range=today").unwrap();
+        assert_eq!(
+            (today.from, today.to),
+            (day("2024-10-10"), day("2024-10-11"))
+        );
+        assert_eq!(today.interval, Interval::Hour);
+        assert_eq!(today.bounds().len(), 25);
+
+        let week = range("range=7d").unwrap();
+        assert_eq!((week.from, week.to), (day("2024-10-04"), day("2024-10-11")));
+        // the clocks go back on the 27th, so october has an extra hour
+        let month = range("range=month").unwrap();
+        assert_eq!(
+            (month.from, month.to),
+            (day("2024-10-01"), day("2024-11-01"))
+        );
+        assert_eq!(month.to - month.from, 31 * 24 * HOUR + HOUR);
+        assert_eq!(month.bounds().len(), 32);
+        let year = range("range=year").unwrap();
+        assert_eq!((year.from, year.to), (day("2023-10-12"), day("2024-10-11")));
+        assert_eq!(year.interval, Interval::Week);
+        assert_eq!(year.title(), "Weekly, last year");
+    }
+
+    #[test]
+    fn custom_ranges_include_their_last_day() {
+        let custom = range("from=2024-10-01&to=2024-10-07").unwrap();
+        assert_eq!(custom.preset, None);
+        assert_eq!(
+            (custom.from, custom.to),
+            (day("2024-10-01"), day("2024-10-08"))
+        );
+        assert_eq!(custom.title(), "Daily, 2024-10-01 to 2024-10-07");
+        // `from` wins over `range`, and `to` defaults to now
+        let open = range("range=year&from=2024-10-09").unwrap();
+        assert_eq!(
+            (open.from, open.to, open.preset),
+            (day("2024-10-09"), NOW, None)
+        );
+        assert_eq!(open.interval, Interval::Hour);
+        // a `to` without a `from` is ignored
+        assert_eq!(
+            range("to=2024-01-01").unwrap().preset,
+            Some(Preset::Last30Days)
+        );
+        let millis = range("from=1728511200000&to=1728547200000").unwrap();
+        assert_eq!((millis.from, millis.to), (1728511200000, 1728547200000));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for query in [
+            "from=2024-10-07&to=2024-10-01",
+            "from=1000&to=1000",
+            "from=2024-13-01",
+            "from=yesterday",
+            "from=2024-10-01&to=soon",
+        ] {
+            assert!(range(query).is_err(), "{}", query);
+        }
+        assert_eq!(
+            range("range=year&interval=hour").unwrap_err(),
+            "too many hours in that range, pick a longer interval"
+        );
+        assert!(Query::<RangeInfo>::from_query("range=decade").is_err());
+    }
+
+    #[test]
+    fn previous_bounds_end_where_the_range_starts() {
+        let week = range("range=7d").unwrap();
+        let previous = week.previous_bounds().unwrap();
+        assert_eq!(previous.len(), week.bounds().len());
+        assert_eq!(previous[0], day("2024-09-27"));
+        assert_eq!(previous[previous.len() - 1], week.bounds()[0]);
+        assert_eq!(
+            week.previous_title(&previous, NOW),
+            // only as much of the previous week as has gone by of this one
+            "2024-09-27 to 2024-10-03"
+        );
+        assert_eq!(
+            week.previous_title(&previous, week.from + 36 * HOUR),
+            "2024-09-27 to 2024-09-28"
+        );
+
+        let months = range("from=2024-03-01&to=2024-04-30&interval=month").unwrap();
+        assert_eq!(
+            months.previous_bounds().unwrap(),
+            [day("2024-01-01"), day("2024-02-01"), day("2024-03-01")]
+        );
+    }
+
+    #[test]
+    fn queries_keep_the_range() {
+        assert_eq!(range("range=7d").unwrap().query(), "range=7d");
+        assert_eq!(range("").unwrap().query(), "range=30d");
+        let custom = range("from=2024-10-01&to=2024-10-07&interval=hour&compare=true").unwrap();
+        assert_eq!(
+            custom.query(),
+            "from=2024-10-01&to=2024-10-07&interval=hour&compare=true"
+        );
+        assert_eq!(
+            custom.query_with(false),
+            "from=2024-10-01&to=2024-10-07&interval=hour"
+        );
+        assert_eq!(custom.range_query(), "from=2024-10-01&to=2024-10-07");
+        // picked intervals are kept, worked out ones aren't
+        assert_eq!(
+            range("range=7d&interval=day").unwrap().query(),
+            "range=7d&interval=day"
+        );
+        // times that aren't midnight can't be written as days
+        let millis = range("from=1728511200000&to=1728547200000").unwrap();
+        assert_eq!(millis.query(), "from=1728511200000&to=1728547200000");
+        let open = range("from=2024-10-09").unwrap();
+        assert_eq!(open.range_query(), format!("from={}&to={}", open.from, NOW));
+        for query in ["range=month", "from=2024-10-01&to=2024-10-07&compare=true"] {
+            let parsed = range(query).unwrap();
+            let again = range(&parsed.query()).unwrap();
+            assert_eq!((again.from, again.to), (parsed.from, parsed.to));
+        }
+    }
+
+    #[test]
+    fn huge_ranges_are_errors() {
+        assert_eq!(
+            range("from=-9000000000000000000&to=9000000000000000000").unwrap_err(),
+            "the range is too long"
+        );
+        assert!(range("from=-9000000000000000000&to=0").is_err());
+        assert!(range(&format!("from=0&to={}", i64::MAX)).is_err());
+    }
+
+    #[test]
+    fn days_start_at_the_first_local_midnight() {
+        // the clocks go forward at 2:00, so the day is an hour short
+        assert_eq!(day("2024-03-31"), 1711839600000);
+        assert_eq!(day("2024-04-01") - day("2024-03-31"), 23 * HOUR);
+        assert_eq!(day("2024-10-28") - day("2024-10-27"), 25 * HOUR);
+        // santiago skips from midnight straight to 1:00
+        let santiago: Tz = "America/Santiago".parse().unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 9, 8).unwrap();
+        assert_eq!(start_of_day(date, santiago), 1725768000000);
+        // while havana has midnight twice when the clocks go back
+        let havana: Tz = "America/Havana".parse().unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 11, 3).unwrap();
+        assert_eq!(start_of_day(date, havana), 1730606400000);
+        assert_eq!(day_in(1730606400000 - 1, havana), date.pred_opt());
+    }
+
+    #[test]
+    fn days_and_hours_follow_dst_changes() {
+        let tz = amsterdam();
+        // a 23 and a 25 hour day
+        for (date, hours) in [("2024-03-31", 23), ("2024-10-27", 25)] {
+            let start = day(date);
+            assert_eq!(Interval::Day.floor(start + 20 * HOUR, tz), Some(start));
+            assert_eq!(Interval::Day.next(start, tz), Some(start + hours * HOUR));
+            let bounds = Interval::Hour
+                .bounds(start, start + hours * HOUR, tz, MAX_BUCKETS)
+                .unwrap();
+            assert_eq!(bounds.len() as i64, hours + 1);
+            assert!(bounds.windows(2).all(|x| x[1] - x[0] == HOUR));
+        }
+        // hours in zones a half hour off utc start on the local hour
+        let kolkata: Tz = "Asia/Kolkata".parse().unwrap();
+        let quarter_to_eleven = 1728537300000;
+        assert_eq!(
+            Interval::Hour.floor(quarter_to_eleven, kolkata),
+            Some(quarter_to_eleven - 45 * 60 * 1000)
+        );
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        let tz = amsterdam();
+        // a wednesday, and a week that starts in the year before
+        assert_eq!(
+            Interval::Week.floor(day("2024-10-09") + HOUR, tz),
+            Some(day("2024-10-07"))
+        );
+        assert_eq!(
+            Interval::Week.floor(day("2025-01-01"), tz),
+            Some(day("2024-12-30"))
+        );
+        assert_eq!(
+            Interval::Week.floor(day("2024-10-07"), tz),
+            Some(day("2024-10-07"))
+        );
+        assert_eq!(
+            Interval::Week.next(day("2024-10-21"), tz),
+            Some(day("2024-10-28"))
+        );
+    }
+
+    #[test]
+    fn months_follow_the_calendar() {
+        let tz = amsterdam();
+        assert_eq!(
+            Interval::Month.floor(day("2024-02-29") + HOUR, tz),
+            Some(day("2024-02-01"))
+        );
+        assert_eq!(
+            Interval::Month.bounds(day("2024-01-15"), day("2024-04-10"), tz, MAX_BUCKETS),
+            Some(vec![
+                day("2024-01-01"),
+                day("2024-02-01"),
+                day("2024-03-01"),
+                day("2024-04-01"),
+                day("2024-05-01"),
+            ])
+        );
+        assert_eq!(
+            Interval::Month.next(day("2023-12-01"), tz),
+            Some(day("2024-01-01"))
+        );
+    }
+
+    #[test]
+    fn bounds_are_capped() {
+        let tz = chrono_tz::UTC;
+        let bounds = Interval::Hour.bounds(0, MAX_BUCKETS * HOUR, tz, MAX_BUCKETS);
+        assert_eq!(bounds.map(|x| x.len() as i64), Some(MAX_BUCKETS + 1));
+        assert_eq!(
+            Interval::Hour.bounds(0, MAX_BUCKETS * HOUR + 1, tz, MAX_BUCKETS),
+            None
+        );
+        // a range inside one bar still gets that bar
+        assert_eq!(
+            Interval::Day.bounds(HOUR, 2 * HOUR, tz, MAX_BUCKETS),
+            Some(vec![0, 24 * HOUR])
+        );
+    }
+}
@@ -0,0 +1,458 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use actix_web::http::header::{HeaderName, HeaderValue};
+use lazy_static::lazy_static;
+
+pub const REDIRECTS_FILE: &str = "_redirects";
+pub const HEADERS_FILE: &str = "_headers";
+
+lazy_static! {
+    /// the rules of every deployed site keyed by the version they were loaded
+    /// from. versions that have been removed are dropped whenever one is added
+    static ref RULES: RwLock<HashMap<PathBuf, Arc<SiteRules>>> = RwLock::new(HashMap::new());
+}
+
+/// the netlify style `_redirects` and `_headers` of a deployed site
+#[derive(Debug, Default)]
+pub struct SiteRules {
+    redirects: Vec<Redirect>,
+    headers: Vec<HeaderRule>,
+}
+
+/// a line of `_redirects`, eg `/blog/:year/* /posts/:splat 301`
+#[derive(Debug)]
+struct Redirect {
+    from: PathPattern,
+    to: String,
+    /// 301, 302, 307 and 308 redirect while 200 serves `to` in place
+    status: u16,
+    /// by default a rule is skipped when a file exists at its path, rules
+    /// ending in `!` apply regardless
+    force: bool,
+}
+
+/// a block of `_headers`, a path followed by indented `Name: value` lines
+#[derive(Debug)]
+struct HeaderRule {
+    path: PathPattern,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    /// `:name`, matches any single segment
+    Placeholder(String),
+    /// a trailing `*`, matches the rest of the path
+    Splat,
+}
+
+#[derive(Debug)]
+struct PathPattern {
+    segments: Vec<Segment>,
+}
+
+impl PathPattern {
+    fn parse(pattern: &str) -> Result<PathPattern, String> {
+        if !pattern.starts_with('/') {
+            return Err(format!("path {} must start with /", pattern));
+        }
+        let parts: Vec<_> = pattern.split('/').filter(|x| !x.is_empty()).collect();
+        let mut segments = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            let segment = match part.strip_prefix(':') {
+                _ if *part == "*" && i + 1 == parts.len() => Segment::Splat,
+                _ if *part == "*" => {
+                    return Err(format!("{}: * must be the last segment", pattern));
+                }
+                Some(name) if !name.is_empty() => Segment::Placeholder(name.to_string()),
+                _ => Segment::Literal(part.to_string()),
+            };
+            segments.push(segment);
+        }
+        Ok(PathPattern { segments })
+    }
+
+    /// the values of the placeholders and splat if `path` matches. trailing
+    /// slashes are ignored
+    fn matches(&self, path: &str) -> Option<HashMap<&str, String>> {
+        let parts: Vec<_> = path.split('/').filter(|x| !x.is_empty()).collect();
+        let mut captures = HashMap::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Splat => {
+                    captures.insert("splat", parts.get(i..).unwrap_or_default().join("/"));
+                    return Some(captures);
+                }
+                Segment::Placeholder(name) => {
+                    captures.insert(name.as_str(), parts.get(i)?.to_string());
+                }
+                Segment::Literal(x) => {
+                    if parts.get(i) != Some(&x.as_str()) {
+                        return None;
+                    }
+                }
+            }
+        }
+        (parts.len() == self.segments.len()).then_some(captures)
+    }
+}
+
+/// `to` with every `:name` replaced by its captured value
+fn substitute(to: &str, captures: &HashMap<&str, String>) -> String {
+    let mut names: Vec<_> = captures.keys().collect();
+    // longest first so `:splat` isn't clobbered by a placeholder named `:s`
+    names.sort_by_key(|x| std::cmp::Reverse(x.len()));
+    let mut target = to.to_string();
+    for name in names {
+        target = target.replace(&format!(":{}", name), &captures[name]);
+    }
+    target
+}
+
+/// what a request should get according to `_redirects`
+pub enum RedirectAction {
+    Redirect {
+        status: u16,
+        location: String,
+    },
+    /// serve this path instead, without telling the client
+    Rewrite(String),
+}
+
+impl SiteRules {
+    /// the first redirect matching `path`. `file_exists` is whether the site
+    /// has a file at `path`, which shadows any rule that isn't forced
+    pub fn redirect(&self, path: &str, file_exists: bool) -> Option<RedirectAction> {
+        self.redirects
+            .iter()
+            .filter(|x| x.force || !file_exists)
+            .find_map(|redirect| {
+                let captures = redirect.from.matches(path)?;
+                let target = substitute(&redirect.to, &captures);
+                Some(match redirect.status {
+                    200 => RedirectAction::Rewrite(target),
+                    status => RedirectAction::Redirect {
+                        status,
+                        location: target,
+                    },
+                })
+            })
+    }
+
+    /// the custom headers of every `_headers` block matching `path`
+    pub fn headers<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> impl Iterator<Item = &'a (HeaderName, HeaderValue)> + 'a {
+        self.headers
+            .iter()
+            .filter(move |x| x.path.matches(path).is_some())
+            .flat_map(|x| x.headers.iter())
+    }
+}
+
+/// parses the `_redirects` and `_headers` in `dir`, either may be missing.
+/// every bad line is reported rather than just the first
+pub fn load_rules(dir: &Path) -> Result<SiteRules, String> {
+    let mut errors = Vec::new();
+    let mut rules = SiteRules::default();
+    if let Ok(text) = fs::read_to_string(dir.join(REDIRECTS_FILE)) {
+        rules.redirects = parse_redirects(&text, &mut errors);
+    }
+    if let Ok(text) = fs::read_to_string(dir.join(HEADERS_FILE)) {
+        rules.headers = parse_headers(&text, &mut errors);
+    }
+    match errors.is_empty() {
+        true => Ok(rules),
+        false => Err(errors.join("\n")),
+    }
+}
+
+fn parse_redirects(text: &str, errors: &mut Vec<String>) -> Vec<Redirect> {
+    let mut redirects = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_redirect(line) {
+            Ok(x) => redirects.push(x),
+            Err(e) => errors.push(format!("{} line {}: {}", REDIRECTS_FILE, i + 1, e)),
+        }
+    }
+    redirects
+}
+
+fn parse_redirect(line: &str) -> Result<Redirect, String> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    // conditions are fields like `id=:id` or `Country=nz`, unlike targets
+    // which are paths or urls that may have a query of their own
+    if fields
+        .iter()
+        .skip(1)
+        .any(|x| x.contains('=') && !x.starts_with('/') && !x.contains("://"))
+    {
+        return Err("query and country conditions aren't supported".to_string());
+    }
+    let (from, to, status) = match fields[..] {
+        [from, to] => (from, to, "301"),
+        [from, to, status] => (from, to, status),
+        _ => return Err("expected `from to [status]`".to_string()),
+    };
+    let (status, force) = match status.strip_suffix('!') {
+        Some(x) => (x, true),
+        None => (status, false),
+    };
+    let status = match status.parse::<u16>() {
+        Ok(x @ (200 | 301 | 302 | 307 | 308)) => x,
+        _ => return Err(format!("unsupported status {}", status)),
+    };
+    if status == 200 && !to.starts_with('/') {
+        return Err(format!("can't rewrite to {}, proxying isn't supported", to));
+    }
+    Ok(Redirect {
+        from: PathPattern::parse(from)?,
+        to: to.to_string(),
+        status,
+        force,
+    })
+}
+
+fn parse_headers(text: &str, errors: &mut Vec<String>) -> Vec<HeaderRule> {
+    let mut rules: Vec<HeaderRule> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let error = |e: String| format!("{} line {}: {}", HEADERS_FILE, i + 1, e);
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with(char::is_whitespace) {
+            match PathPattern::parse(trimmed) {
+                Ok(path) => rules.push(HeaderRule {
+                    path,
+                    headers: Vec::new(),
+                }),
+                Err(e) => errors.push(error(e)),
+            }
+            continue;
+        }
+        let Some(rule) = rules.last_mut() else {
+            errors.push(error("header before any path".to_string()));
+            continue;
+        };
+        let Some((name, value)) = trimmed.split_once(':') else {
+            errors.push(error(format!("expected `Name: value`, got {}", trimmed)));
+            continue;
+        };
+        let name = HeaderName::try_from(name.trim());
+        let value = HeaderValue::try_from(value.trim());
+        match (name, value) {
+            (Ok(name), Ok(value)) => rule.headers.push((name, value)),
+            _ => errors.push(error(format!("invalid header {}", trimmed))),
+        }
+    }
+    rules
+}
+
+/// replaces the rules served from `live`, called after it's published
+pub fn set_rules(live: &Path, rules: SiteRules) {
+    insert_rules(live, Arc::new(rules));
+}
+
+fn insert_rules(live: &Path, rules: Arc<SiteRules>) {
+    let mut all = RULES.write().unwrap_or_else(|e| e.into_inner());
+    // versions that have since been replaced, or previews that are gone
+    all.retain(|dir, _| dir.is_dir());
+    all.insert(live.to_path_buf(), rules);
+}
+
+/// the rules served from `live`. they're loaded from disk the first time,
+/// for sites that were published before the server started
+pub fn site_rules(live: &Path) -> Arc<SiteRules> {
+    if let Some(rules) = RULES.read().unwrap_or_else(|e| e.into_inner()).get(live) {
+        return rules.clone();
+    }
+    let rules = load_rules(live).unwrap_or_else(|e| {
+        println!("ignoring invalid rules in {}: {}", live.display(), e);
+        SiteRules::default()
+    });
+    let rules = Arc::new(rules);
+    insert_rules(live, rules.clone());
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{write_file, TempDir};
+
+    fn rules(redirects: &str, headers: &str) -> SiteRules {
+        let mut errors = Vec::new();
+        let rules = SiteRules {
+            redirects: parse_redirects(redirects, &mut errors),
+            headers: parse_headers(headers, &mut errors),
+        };
+        assert!(errors.is_empty(), "{:?}", errors);
+        rules
+    }
+
+    /// the status and location, or `200` and the path for rewrites
+    fn redirect(rules: &SiteRules, path: &str, file_exists: bool) -> Option<(u16, String)> {
+        rules
+            .redirect(path, file_exists)
+            .map(|action| match action {
+                RedirectAction::Redirect { status, location } => (status, location),
+                RedirectAction::Rewrite(path) => (200, path),
+            })
+    }
+
+    #[test]
+    fn redirects_with_splats_and_placeholders() {
+        let rules = rules(
+            "
+            # comments and blank lines are skipped
+
+            /blog/:year/:slug /posts/:year-:slug
+            /docs/* /guide/:splat 302
+            /old /new 308
+            /app/* /index.html 200
+            /shadowed /elsewhere
+            /forced /elsewhere 301!
+            ",
+            "",
+        );
+        let cases = [
+            ("/blog/2024/hello", Some((301, "/posts/2024-hello"))),
+            ("/blog/2024/hello/", Some((301, "/posts/2024-hello"))),
+            ("/blog/2024", None),
+            ("/blog/2024/hello/more", None),
+            ("/docs/a/b.html", Some((302, "/guide/a/b.html"))),
+            ("/docs", Some((302, "/guide/"))),
+            ("/old", Some((308, "/new"))),
+            ("/app/settings/profile", Some((200, "/index.html"))),
+            ("/other", None),
+        ];
+        for (path, expected) in cases {
+            let expected = expected.map(|(status, to)| (status, to.to_string()));
+            assert_eq!(redirect(&rules, path, false), expected, "{}", path);
+        }
+        // a file at the path wins unless the rule is forced
+        assert_eq!(redirect(&rules, "/shadowed", true), None);
+        assert_eq!(
+            redirect(&rules, "/forced", true),
+            Some((301, "/elsewhere".to_string()))
+        );
+    }
+
+    #[test]
+    fn splats_arent_clobbered_by_shorter_placeholders() {
+        let rules = rules("/:s/* /:s/x/:splat", "");
+        assert_eq!(
+            redirect(&rules, "/a/b/c", false),
+            Some((301, "/a/x/b/c".to_string()))
+        );
+    }
+
+    #[test]
+    fn reports_every_invalid_line() {
+        let mut errors = Vec::new();
+        let redirects = parse_redirects(
+            "
+            /ok /fine?from=ok
+            /search q=:term /find
+            /a /b 404
+            /a https://example.com 200
+            no-slash /b
+            /*/b /c
+            ",
+            &mut errors,
+        );
+        assert_eq!(redirects.len(), 1);
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+        // query conditions aren't supported, so the line is rejected
+        assert!(errors[0].contains("line 3"), "{}", errors[0]);
+        assert!(errors[0].contains("query"), "{}", errors[0]);
+        assert!(
+            errors[1].contains("unsupported status 404"),
+            "{}",
+            errors[1]
+        );
+        assert!(errors[2].contains("proxying"), "{}", errors[2]);
+        assert!(errors[3].contains("must start with /"), "{}", errors[3]);
+        assert!(errors[4].contains("last segment"), "{}", errors[4]);
+    }
+
+    #[test]
+    fn header_blocks_apply_to_matching_paths() {
+        let rules = rules(
+            "",
+            "/*
+  X-Frame-Options: DENY
+/assets/*
+    Cache-Control: public, max-age=60
+\tAccess-Control-Allow-Origin: *
+# a comment between blocks
+/feed.xml
+  Content-Type: application/rss+xml
+",
+        );
+        let headers = |path| -> Vec<(String, String)> {
+            rules
+                .headers(path)
+                .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+                .collect()
+        };
+        assert_eq!(
+            headers("/assets/app.js"),
+            [
+                ("x-frame-options", "DENY"),
+                ("cache-control", "public, max-age=60"),
+                ("access-control-allow-origin", "*"),
+            ]
+            .map(|(x, y)| (x.to_string(), y.to_string()))
+        );
+        assert_eq!(headers("/feed.xml").len(), 2);
+        assert_eq!(headers("/").len(), 1);
+    }
+
+    #[test]
+    fn rejects_headers_outside_a_block() {
+        let mut errors = Vec::new();
+        let headers = parse_headers(
+            "  X-Early: 1
+/ok
+  not a header
+  Bad Name: 1
+  X-Fine: 1
+",
+            &mut errors,
+        );
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[0].headers.len(), 1);
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].contains("before any path"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn forgets_the_rules_of_removed_versions() {
+        let dir = TempDir::new("rules");
+        let first = dir.path().join("1");
+        let second = dir.path().join("2");
+        write_file(&first.join(REDIRECTS_FILE), "/a /b");
+        write_file(&second.join(REDIRECTS_FILE), "/a /c");
+        set_rules(&first, load_rules(&first).unwrap());
+        assert!(site_rules(&first).redirect("/a", false).is_some());
+
+        fs::remove_dir_all(&first).unwrap();
+        set_rules(&second, load_rules(&second).unwrap());
+        let all = RULES.read().unwrap();
+        assert!(!all.contains_key(&first));
+        assert!(all.contains_key(&second));
+    }
+}
@@ -0,0 +1,336 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{
+        HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, PERMISSIONS_POLICY, REFERRER_POLICY,
+        STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+    },
+    middleware::Next,
+    web::Data,
+    Error,
+};
+use serde::Deserialize;
+
+use crate::config::Config;
+
+/// security headers added to every response. anything left out falls back
+/// to the defaults, set a header to `""` to leave it out entirely. headers
+/// the response already has, eg from `_headers`, are never replaced
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SecurityHeaders {
+    pub content_security_policy: Option<String>,
+    /// off unless set, since browsers keep to https for as long as it says
+    /// even if the site stops serving it. only sent over https
+    pub strict_transport_security: Option<String>,
+    pub x_content_type_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+    /// added to the csp as `frame-ancestors` and mirrored in `X-Frame-Options`
+    /// for older browsers, eg `'none'` or `'self'`
+    pub frame_ancestors: Option<String>,
+}
+
+impl SecurityHeaders {
+    /// defaults for served sites, which may embed or load whatever they like
+    pub fn site_defaults() -> SecurityHeaders {
+        SecurityHeaders {
+            content_security_policy: None,
+            strict_transport_security: None,
+            x_content_type_options: Some("nosniff".to_string()),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+            permissions_policy: None,
+            frame_ancestors: None,
+        }
+    }
+
+    /// defaults for the analytics dashboard, which only ever loads its own
+    /// resources. the script hash is the site selector's `onchange` handler
+    pub fn dashboard_defaults() -> SecurityHeaders {
+        SecurityHeaders {
+            content_security_policy: Some(
+                "default-src 'self'; \
+                 script-src 'unsafe-hashes' 'sha256-osjxnKEPL/pQJbFk1dKsF7PYFmTyMWGmVSiL9inhxJY='; \
+                 style-src 'self' 'unsafe-inline'; img-src 'self' data:; object-src 'none'; \
+                 base-uri 'none'; form-action 'self'"
+                    .to_string(),
+            ),
+            strict_transport_security: None,
+            x_content_type_options: Some("nosniff".to_string()),
+            referrer_policy: Some("no-referrer".to_string()),
+            permissions_policy: Some(
+                "camera=(), microphone=(), geolocation=(), payment=(), usb=()".to_string(),
+            ),
+            frame_ancestors: Some("'none'".to_string()),
+        }
+    }
+
+    /// checks that every header that's set is a valid header value
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in self.headers(&SecurityHeaders::default()) {
+            if HeaderValue::from_str(&value).is_err() {
+                return Err(format!("invalid {} header {}", name, value));
+            }
+        }
+        Ok(())
+    }
+
+    /// the headers to send, with anything unset taken from `defaults`
+    pub fn headers(&self, defaults: &SecurityHeaders) -> Vec<(HeaderName, String)> {
+        let pick = |x: &Option<String>, default: &Option<String>| {
+            x.clone()
+                .or_else(|| default.clone())
+                .filter(|x| !x.is_empty())
+        };
+        let frame_ancestors = pick(&self.frame_ancestors, &defaults.frame_ancestors);
+        let csp = pick(
+            &self.content_security_policy,
+            &defaults.content_security_policy,
+        );
+        let csp = match (csp, &frame_ancestors) {
+            (Some(csp), Some(x)) if !csp.contains("frame-ancestors") => Some(format!(
+                "{}; frame-ancestors {}",
+                csp.trim_end_matches([';', ' ']),
+                x
+            )),
+            (None, Some(x)) => Some(format!("frame-ancestors {}", x)),
+            (csp, _) => csp,
+        };
+        let frame_options = frame_ancestors.and_then(|x| match x.as_str() {
+            "'none'" => Some("DENY".to_string()),
+            "'self'" => Some("SAMEORIGIN".to_string()),
+            // X-Frame-Options can't express a list of origins
+            _ => None,
+        });
+
+        [
+            (CONTENT_SECURITY_POLICY, csp),
+            (
+                STRICT_TRANSPORT_SECURITY,
+                pick(
+                    &self.strict_transport_security,
+                    &defaults.strict_transport_security,
+                ),
+            ),
+            (
+                X_CONTENT_TYPE_OPTIONS,
+                pick(
+                    &self.x_content_type_options,
+                    &defaults.x_content_type_options,
+                ),
+            ),
+            (
+                REFERRER_POLICY,
+                pick(&self.referrer_policy, &defaults.referrer_policy),
+            ),
+            (
+                PERMISSIONS_POLICY,
+                pick(&self.permissions_policy, &defaults.permissions_policy),
+            ),
+            (X_FRAME_OPTIONS, frame_options),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect()
+    }
+}
+
+/// adds the dashboard's security headers to `/analytics` and the site's to
+/// everything else
+pub async fn security_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let config = req
+        .app_data::<Data<Config>>()
+        .expect("missing config from app data")
+        .clone();
+    let is_dashboard = req.path() == "/analytics" || req.path().starts_with("/analytics/");
+    let host = req.connection_info().host().to_string();
+    let https = req.connection_info().scheme() == "https";
+
+    let mut res = next.call(req).await?;
+
+    let headers = match is_dashboard {
+        true => config
+            .dashboard_security_headers
+            .headers(&SecurityHeaders::dashboard_defaults()),
+        false => match config.resolve_host(&host) {
+            Some(site) => site
+                .security_headers
+                .headers(&SecurityHeaders::site_defaults()),
+            None => SecurityHeaders::default().headers(&SecurityHeaders::site_defaults()),
+        },
+    };
+    for (name, value) in headers {
+        if res.headers().contains_key(&name) || (name == STRICT_TRANSPORT_SECURITY && !https) {
+            continue;
+        }
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            res.headers_mut().insert(name, value);
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        dev::fn_service,
+        middleware::from_fn,
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    use super::*;
+    use crate::{
+        serve::serve_site,
+        test_util::{test_config, write_file, TempDir},
+    };
+
+    /// the security headers of a response to `req`, by name
+    async fn headers_for(config: Config, req: TestRequest) -> Vec<(String, String)> {
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(config))
+                .route(
+                    "/analytics/overview",
+                    web::get().to(|| async { HttpResponse::Ok().finish() }),
+                )
+                .default_service(fn_service(serve_site))
+                .wrap(from_fn(security_headers)),
+        )
+        .await;
+        let res = call_service(&app, req.to_request()).await;
+        let mut headers: Vec<_> = res
+            .headers()
+            .iter()
+            .filter(|(name, _)| {
+                [
+                    CONTENT_SECURITY_POLICY,
+                    STRICT_TRANSPORT_SECURITY,
+                    X_CONTENT_TYPE_OPTIONS,
+                    REFERRER_POLICY,
+                    PERMISSIONS_POLICY,
+                    X_FRAME_OPTIONS,
+                ]
+                .contains(name)
+            })
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+            .collect();
+        headers.sort();
+        headers
+    }
+
+    fn names(headers: &[(String, String)]) -> Vec<&str> {
+        headers.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn site(dir: &TempDir, security_headers: serde_json::Value) -> Config {
+        let live = dir.path().join("live");
+        write_file(&live.join("index.html"), "home");
+        write_file(
+            &live.join("_headers"),
+            "/embed/*\n  X-Frame-Options: ALLOWALL\n  Referrer-Policy: no-referrer\n",
+        );
+        write_file(&live.join("embed/index.html"), "embed");
+        test_config(serde_json::json!([{
+            "domain": "ivytime.gay",
+            "site_repo": "https://example.com/site.git",
+            "branch": "main",
+            "live_dir": live,
+            "security_headers": security_headers,
+        }]))
+    }
+
+    #[actix_web::test]
+    async fn dashboard_and_sites_get_their_own_defaults() {
+        let dir = TempDir::new("security");
+        let config = site(&dir, serde_json::json!({}));
+
+        let dashboard = headers_for(
+            config.clone(),
+            TestRequest::get().uri("/analytics/overview"),
+        )
+        .await;
+        assert_eq!(
+            names(&dashboard),
+            [
+                "content-security-policy",
+                "permissions-policy",
+                "referrer-policy",
+                "x-content-type-options",
+                "x-frame-options",
+            ]
+        );
+        let csp = header(&dashboard, "content-security-policy").unwrap();
+        assert!(csp.ends_with("; frame-ancestors 'none'"), "{}", csp);
+        assert_eq!(header(&dashboard, "x-frame-options"), Some("DENY"));
+        assert_eq!(header(&dashboard, "referrer-policy"), Some("no-referrer"));
+
+        let site = headers_for(config, TestRequest::get().uri("/")).await;
+        assert_eq!(
+            site,
+            [
+                ("referrer-policy", "strict-origin-when-cross-origin"),
+                ("x-content-type-options", "nosniff"),
+            ]
+            .map(|(x, y)| (x.to_string(), y.to_string()))
+        );
+    }
+
+    #[actix_web::test]
+    async fn hsts_is_opt_in_and_https_only() {
+        let dir = TempDir::new("security-hsts");
+        let config = site(&dir, serde_json::json!({}));
+        let https = || {
+            TestRequest::get()
+                .uri("/")
+                .insert_header(("x-forwarded-proto", "https"))
+        };
+        let headers = headers_for(config, https()).await;
+        assert_eq!(header(&headers, "strict-transport-security"), None);
+
+        let config = site(
+            &dir,
+            serde_json::json!({ "strict_transport_security": "max-age=600" }),
+        );
+        let headers = headers_for(config.clone(), https()).await;
+        assert_eq!(
+            header(&headers, "strict-transport-security"),
+            Some("max-age=600")
+        );
+        let headers = headers_for(config, TestRequest::get().uri("/")).await;
+        assert_eq!(header(&headers, "strict-transport-security"), None);
+    }
+
+    #[actix_web::test]
+    async fn site_headers_win_over_the_defaults() {
+        let dir = TempDir::new("security-override");
+        let config = site(
+            &dir,
+            serde_json::json!({
+                "frame_ancestors": "'self'",
+                "x_content_type_options": "",
+            }),
+        );
+        let headers = headers_for(config.clone(), TestRequest::get().uri("/")).await;
+        assert_eq!(
+            header(&headers, "content-security-policy"),
+            Some("frame-ancestors 'self'")
+        );
+        assert_eq!(header(&headers, "x-frame-options"), Some("SAMEORIGIN"));
+        assert_eq!(header(&headers, "x-content-type-options"), None);
+
+        // `_headers` beats both the defaults and the config
+        let headers = headers_for(config, TestRequest::get().uri("/embed/")).await;
+        assert_eq!(header(&headers, "x-frame-options"), Some("ALLOWALL"));
+        assert_eq!(header(&headers, "referrer-policy"), Some("no-referrer"));
+    }
+}
@@ -0,0 +1,709 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::File,
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+};
+
+use actix_files::{Files, FilesService, NamedFile};
+use actix_web::{
+    dev::{fn_service, Service, ServiceFactory, ServiceRequest, ServiceResponse},
+    http::{
+        header::{
+            AcceptEncoding, ContentEncoding, Header, HeaderValue, CACHE_CONTROL, LOCATION, VARY,
+        },
+        Method, StatusCode, Uri,
+    },
+    web::Data,
+    Error, HttpMessage, HttpResponse,
+};
+use percent_encoding::percent_decode_str;
+
+use crate::{
+    cache::CachePolicy,
+    compress::{sibling_path, to_encoding, PRECOMPRESSED},
+    config::{Config, SiteConfig, TrailingSlash},
+    redirects::{site_rules, RedirectAction, HEADERS_FILE, REDIRECTS_FILE},
+};
+
+thread_local! {
+    /// services keyed by the version of a site they serve. they are built
+    /// lazily since previews come and go while the server is running, and
+    /// live per worker since `Files` can't be shared between threads
+    static FILES: RefCell<HashMap<PathBuf, SiteService>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Clone)]
+struct SiteService {
+    files: FilesService,
+    cache: Rc<CachePolicy>,
+}
+
+/// the default service, serves the static files of whichever site or branch
+/// preview the request's host belongs to
+pub async fn serve_site(req: ServiceRequest) -> Result<ServiceResponse, Error> {
+    let config = req
+        .app_data::<Data<Config>>()
+        .expect("missing config from app data")
+        .clone();
+    let Some(site) = config.resolve_host(req.connection_info().host()) else {
+        let (req, _) = req.into_parts();
+        return Ok(ServiceResponse::new(req, HttpResponse::NotFound().finish()));
+    };
+    // the live dir links to the version currently published, resolving it
+    // once means the whole request is served from the same version
+    let Some(live_path) = site.live_path().canonicalize().ok().filter(|x| x.is_dir()) else {
+        // a preview that was just deleted, or a site that never deployed
+        let (req, _) = req.into_parts();
+        return Ok(ServiceResponse::new(req, HttpResponse::NotFound().finish()));
+    };
+
+    let service = site_service(&site, &live_path).await?;
+    let rules = site_rules(&live_path);
+    let request_path = req.path().to_string();
+    let mut resolved = resolve_file(&site, &live_path, &request_path);
+    let mut req = req;
+    match rules.redirect(&request_path, resolved.is_some()) {
+        Some(RedirectAction::Redirect { status, location }) => {
+            let status =
+                StatusCode::from_u16(status).expect("redirect status is validated on parse");
+            let mut res = redirect(req, status, location);
+            for (name, value) in rules.headers(&request_path) {
+                res.headers_mut().append(name.clone(), value.clone());
+            }
+            return Ok(res);
+        }
+        Some(RedirectAction::Rewrite(target)) => {
+            let uri = match req.query_string() {
+                "" => target,
+                _ if target.contains('?') => target,
+                query => format!("{}?{}", target, query),
+            };
+            let uri: Uri = uri
+                .parse()
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+            req.match_info_mut().get_mut().update(&uri);
+            req.head_mut().uri = uri;
+            resolved = resolve_file(&site, &live_path, req.path());
+        }
+        None => {
+            if resolved.as_ref().is_some_and(|x| is_page(&x.file)) {
+                let canonical =
+                    canonical_path(&request_path, &site.index_file, site.trailing_slash);
+                if site.trailing_slash.is_some() && canonical != request_path {
+                    return Ok(redirect(req, StatusCode::MOVED_PERMANENTLY, canonical));
+                }
+                req.extensions_mut().insert(CanonicalPath(canonical));
+            }
+        }
+    }
+
+    let cache_control = resolved
+        .as_ref()
+        .and_then(|x| x.file.strip_prefix(&live_path).ok())
+        .and_then(|x| service.cache.cache_control(x))
+        .map(HeaderValue::from_str);
+    let precompressed = resolved
+        .as_ref()
+        .and_then(|x| find_precompressed(&req, &x.file));
+    let readable = matches!(*req.method(), Method::GET | Method::HEAD);
+
+    let mut res = match (precompressed_file(&precompressed), resolved) {
+        (Some((named, encoding)), _) => {
+            let (req, _) = req.into_parts();
+            let res = named.set_content_encoding(encoding).into_response(&req);
+            ServiceResponse::new(req, res)
+        }
+        // `Files` doesn't know about clean urls so those are served directly
+        (None, Some(Resolved { file, clean: true })) if readable => {
+            let named = NamedFile::open_async(file).await?;
+            let (req, _) = req.into_parts();
+            let res = named.into_response(&req);
+            ServiceResponse::new(req, res)
+        }
+        _ => service.files.call(req).await?,
+    };
+    if precompressed.is_some() {
+        res.headers_mut()
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    // custom headers go first so they can override the cache rules
+    for (name, value) in rules.headers(&request_path) {
+        res.headers_mut().append(name.clone(), value.clone());
+    }
+    let status = res.status();
+    if let Some(Ok(value)) = cache_control {
+        if (status.is_success() || status == StatusCode::NOT_MODIFIED)
+            && !res.headers().contains_key(CACHE_CONTROL)
+        {
+            res.headers_mut().insert(CACHE_CONTROL, value);
+        }
+    }
+    Ok(res)
+}
+
+/// the cached [`SiteService`] for `live_path`, building it on first use
+async fn site_service(site: &SiteConfig, live_path: &Path) -> Result<SiteService, Error> {
+    if let Some(service) = FILES.with(|x| x.borrow().get(live_path).cloned()) {
+        return Ok(service);
+    }
+    let files = site_files(site, live_path)
+        .new_service(())
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("failed to serve site"))?;
+    let cache =
+        CachePolicy::new(&site.cache_rules).map_err(actix_web::error::ErrorInternalServerError)?;
+    let service = SiteService {
+        files,
+        cache: Rc::new(cache),
+    };
+    FILES.with(|x| {
+        let mut files = x.borrow_mut();
+        // versions that have since been replaced, or previews that are gone
+        files.retain(|dir, _| dir.is_dir());
+        files.insert(live_path.to_path_buf(), service.clone())
+    });
+    Ok(service)
+}
+
+/// opens the precompressed sibling the client should get, if any
+fn precompressed_file(
+    precompressed: &Option<Precompressed>,
+) -> Option<(NamedFile, ContentEncoding)> {
+    let Some(Precompressed {
+        file,
+        encoding: Some((encoding, extension)),
+    }) = precompressed
+    else {
+        return None;
+    };
+    let named = File::open(sibling_path(file, extension))
+        .and_then(|x| NamedFile::from_file(x, file))
+        .ok()?;
+    Some((named, *encoding))
+}
+
+/// a requested file that has precompressed siblings, along with the one the
+/// client should get. `None` means the client is getting the original
+struct Precompressed {
+    file: PathBuf,
+    encoding: Option<(ContentEncoding, &'static str)>,
+}
+
+fn find_precompressed(req: &ServiceRequest, file: &Path) -> Option<Precompressed> {
+    if !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return None;
+    }
+    let available: Vec<_> = PRECOMPRESSED
+        .iter()
+        .filter(|(_, extension)| sibling_path(file, extension).is_file())
+        .collect();
+    if available.is_empty() {
+        return None;
+    }
+    let accepted = AcceptEncoding::parse(req).unwrap_or(AcceptEncoding(Vec::new()));
+    let supported: Vec<_> = available
+        .iter()
+        .map(|(encoding, _)| to_encoding(*encoding))
+        .chain(std::iter::once(to_encoding(ContentEncoding::Identity)))
+        .collect();
+    let chosen = accepted.negotiate(supported.iter());
+    let encoding = available
+        .into_iter()
+        .find(|(encoding, _)| Some(to_encoding(*encoding)) == chosen)
+        .copied();
+    Some(Precompressed {
+        file: file.to_path_buf(),
+        encoding,
+    })
+}
+
+/// the path a page was requested at, after clean urls and the trailing
+/// slash policy. set on the request for analytics
+pub struct CanonicalPath(pub String);
+
+/// a request path mapped onto a file in the live dir
+struct Resolved {
+    file: PathBuf,
+    /// found by trying `<path>.html`, which `Files` wouldn't find on its own
+    clean: bool,
+}
+
+/// maps a request path onto a file under `root` like `Files` does, but also
+/// resolving clean urls. `/about` tries `about`, `about.html` and then
+/// `about/index.html`, while `/about/` prefers the index. paths `Files`
+/// would refuse to serve resolve to nothing
+fn resolve_file(site: &SiteConfig, root: &Path, request_path: &str) -> Option<Resolved> {
+    let root = root.canonicalize().ok()?;
+    let resolved = find_file(site, &root, request_path)?;
+    // whatever got through the checks on the path, never leave the live dir
+    let file = resolved.file.canonicalize().ok()?;
+    file.starts_with(&root).then_some(resolved)
+}
+
+fn find_file(site: &SiteConfig, root: &Path, request_path: &str) -> Option<Resolved> {
+    let mut path = root.to_path_buf();
+    for segment in request_path.split('/') {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        match segment.as_ref() {
+            "" | "." => continue,
+            ".." => return None,
+            // an encoded `/` would let a single segment hold a whole path
+            x if x.contains(['/', '\\', '\0']) => return None,
+            x if !matches!(Path::new(x).components().next(), Some(Component::Normal(_))) => {
+                return None
+            }
+            x => path.push(x),
+        }
+    }
+    if !site.is_servable(path.strip_prefix(root).ok()?) {
+        return None;
+    }
+    let is_dir_path = request_path.ends_with('/');
+    if !is_dir_path && path.is_file() {
+        return Some(Resolved {
+            file: path,
+            clean: false,
+        });
+    }
+    let index = path.join(&site.index_file);
+    if is_dir_path && index.is_file() {
+        return Some(Resolved {
+            file: index,
+            clean: false,
+        });
+    }
+    let html = sibling_path(&path, "html");
+    if path != root && html.is_file() {
+        return Some(Resolved {
+            file: html,
+            clean: true,
+        });
+    }
+    index.is_file().then_some(Resolved {
+        file: index,
+        clean: false,
+    })
+}
+
+fn is_page(file: &Path) -> bool {
+    file.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| x.eq_ignore_ascii_case("html") || x.eq_ignore_ascii_case("htm"))
+}
+
+/// the path a request to `path` is recorded under, the same as if it had
+/// been served from the current live dir. used when importing old traffic
+pub fn analytics_path(site: &SiteConfig, path: &str) -> String {
+    match resolve_file(site, &site.live_path(), path) {
+        Some(x) if is_page(&x.file) => canonical_path(path, &site.index_file, site.trailing_slash),
+        _ => path.to_string(),
+    }
+}
+
+/// the canonical form of a page's path, eg `/blog/index.html`, `/blog.html`
+/// and `/blog/` all become `/blog`, or `/blog/` if trailing slashes are on
+fn canonical_path(path: &str, index_file: &str, policy: Option<TrailingSlash>) -> String {
+    let path = path
+        .strip_suffix(index_file)
+        .filter(|x| x.ends_with('/'))
+        .unwrap_or(path);
+    let path = path
+        .strip_suffix(".html")
+        .or_else(|| path.strip_suffix(".htm"))
+        .unwrap_or(path);
+    let mut path = path.trim_end_matches('/').to_string();
+    if path.is_empty() || policy == Some(TrailingSlash::Always) {
+        path.push('/');
+    }
+    path
+}
+
+/// a redirect to `location`, keeping the request's query string
+fn redirect(req: ServiceRequest, status: StatusCode, location: String) -> ServiceResponse {
+    let location = match req.query_string() {
+        "" => location,
+        _ if location.contains('?') => location,
+        query => format!("{}?{}", location, query),
+    };
+    let (req, _) = req.into_parts();
+    let res = HttpResponse::build(status)
+        .insert_header((LOCATION, location))
+        .finish();
+    ServiceResponse::new(req, res)
+}
+
+fn site_files(site: &SiteConfig, live_path: &Path) -> Files {
+    let error_page = live_path.join(&site.error_page);
+    let filter_site = site.clone();
+    // hidden files are let through here so `path_filter` can apply the allowlist
+    let files = Files::new("/", live_path)
+        .use_hidden_files()
+        .path_filter(move |path, _| {
+            filter_site.is_servable(path)
+                && !path.starts_with(REDIRECTS_FILE)
+                && !path.starts_with(HEADERS_FILE)
+        })
+        .index_file(site.index_file.clone());
+    let files = match site.show_files_listing {
+        true => files.show_files_listing(),
+        false => files,
+    };
+    files.default_handler(fn_service(move |req: ServiceRequest| {
+        let error_page = error_page.clone();
+        async move {
+            let (req, _) = req.into_parts();
+            let res = match NamedFile::open_async(error_page).await {
+                Ok(file) => {
+                    let mut res = file.into_response(&req);
+                    *res.status_mut() = StatusCode::NOT_FOUND;
+                    res
+                }
+                Err(_) => HttpResponse::NotFound().body("404 not found"),
+            };
+            Ok(ServiceResponse::new(req, res))
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        middleware::{from_fn, Next},
+        test::{call_service, init_service, read_body, TestRequest},
+        App,
+    };
+
+    use super::*;
+    use crate::{
+        analytics::recorded_path,
+        test_util::{test_config, write_file, TempDir},
+    };
+
+    fn test_site(live: &Path) -> SiteConfig {
+        serde_json::from_value(serde_json::json!({
+            "domain": "ivytime.gay",
+            "site_repo": "https://example.com/site.git",
+            "branch": "main",
+            "live_dir": live.to_string_lossy(),
+        }))
+        .expect("valid site")
+    }
+
+    /// a deployed site with a couple of files that must never be served
+    fn deployed_site(dir: &TempDir) -> PathBuf {
+        let live = dir.path().join("live");
+        write_file(&live.join("index.html"), "home");
+        write_file(&live.join("docs/guide.html"), "guide");
+        write_file(&live.join(".env"), "SECRET=1");
+        write_file(&live.join(".htaccess"), "deny from all");
+        write_file(&live.join(".git/config"), "[core]");
+        write_file(&live.join(".well-known/security.txt"), "Contact: ivy");
+        write_file(&dir.path().join("secret.html"), "secret");
+        write_file(&dir.path().join("secret.txt"), "secret");
+        live
+    }
+
+    async fn get(site: SiteConfig, paths: &[&str]) -> Vec<(StatusCode, String)> {
+        let mut config = test_config(serde_json::json!([]));
+        config.sites.push(site);
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(config))
+                .default_service(fn_service(serve_site)),
+        )
+        .await;
+        let mut responses = Vec::new();
+        for path in paths {
+            let res = call_service(&app, TestRequest::get().uri(path).to_request()).await;
+            let status = res.status();
+            let body = read_body(res).await;
+            responses.push((status, String::from_utf8_lossy(&body).to_string()));
+        }
+        responses
+    }
+
+    #[actix_web::test]
+    async fn serves_precompressed_siblings_the_client_accepts() {
+        let dir = TempDir::new("precompressed");
+        let live = dir.path().join("live");
+        write_file(&live.join("app.js"), "original");
+        write_file(&live.join("app.js.br"), "brotli");
+        write_file(&live.join("app.js.gz"), "gzip");
+        write_file(&live.join("gz-only.js"), "original");
+        write_file(&live.join("gz-only.js.gz"), "gzip");
+        write_file(&live.join("style.css"), "original");
+        let mut config = test_config(serde_json::json!([]));
+        config.sites.push(test_site(&live));
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(config))
+                .default_service(fn_service(serve_site)),
+        )
+        .await;
+
+        let cases = [
+            ("/app.js", Some("br, gzip"), "brotli"),
+            ("/app.js", Some("gzip, br;q=0.5"), "gzip"),
+            ("/app.js", Some("gzip;q=0.2, br;q=0.9"), "brotli"),
+            ("/app.js", Some("br;q=0, gzip"), "gzip"),
+            ("/app.js", Some("identity"), "original"),
+            ("/app.js", None, "original"),
+            ("/gz-only.js", Some("br"), "original"),
+            ("/gz-only.js", Some("br, gzip"), "gzip"),
+            ("/style.css", Some("br, gzip"), "original"),
+        ];
+        for (path, accept, expected) in cases {
+            let mut req = TestRequest::get().uri(path);
+            if let Some(accept) = accept {
+                req = req.insert_header(("accept-encoding", accept));
+            }
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK, "{} {:?}", path, accept);
+            let header = |name| {
+                res.headers()
+                    .get(name)
+                    .map(|x: &HeaderValue| x.to_str().unwrap().to_string())
+            };
+            let encoding = header("content-encoding");
+            let vary = header("vary");
+            let content_type = header("content-type");
+            let body = read_body(res).await;
+            assert_eq!(body, expected, "{} {:?}", path, accept);
+            let expected_encoding = match expected {
+                "brotli" => Some("br"),
+                "gzip" => Some("gzip"),
+                _ => None,
+            };
+            assert_eq!(
+                encoding.as_deref(),
+                expected_encoding,
+                "{} {:?}",
+                path,
+                accept
+            );
+            // the type is the original's, not that of a .br or .gz file
+            assert!(
+                content_type.as_deref().unwrap().contains("javascript") || path == "/style.css",
+                "{:?}",
+                content_type
+            );
+            // caches must keep the variants apart whenever there are any
+            let varies = vary.is_some_and(|x| x.to_ascii_lowercase().contains("accept-encoding"));
+            assert_eq!(varies, path != "/style.css", "{} {:?}", path, accept);
+        }
+    }
+
+    #[actix_web::test]
+    async fn applies_redirects_with_the_query_string() {
+        let dir = TempDir::new("redirects");
+        let live = dir.path().join("live");
+        write_file(&live.join("index.html"), "home");
+        write_file(&live.join("app.html"), "app");
+        write_file(
+            &live.join(REDIRECTS_FILE),
+            "/old/* /new/:splat 302\n/tagged /new?from=tag 302\n/app/* /app.html 200",
+        );
+        let mut config = test_config(serde_json::json!([]));
+        config.sites.push(test_site(&live));
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(config))
+                .default_service(fn_service(serve_site)),
+        )
+        .await;
+
+        for (path, location) in [
+            ("/old/a?page=2", "/new/a?page=2"),
+            ("/old/a", "/new/a"),
+            ("/tagged?page=2", "/new?from=tag"),
+        ] {
+            let res = call_service(&app, TestRequest::get().uri(path).to_request()).await;
+            assert_eq!(res.status(), StatusCode::FOUND, "{}", path);
+            assert_eq!(res.headers().get(LOCATION).unwrap(), location, "{}", path);
+        }
+        let res = call_service(
+            &app,
+            TestRequest::get().uri("/app/settings?tab=1").to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(read_body(res).await, "app");
+    }
+
+    #[test]
+    fn canonical_paths_follow_the_trailing_slash_policy() {
+        let cases = [
+            ("/", "/", "/", "/"),
+            ("/index.html", "/", "/", "/"),
+            ("/about.html", "/about", "/about/", "/about"),
+            ("/about", "/about", "/about/", "/about"),
+            ("/dir/", "/dir", "/dir/", "/dir"),
+            ("/dir/index.html", "/dir", "/dir/", "/dir"),
+            ("/old.htm", "/old", "/old/", "/old"),
+            // only a whole index file name is stripped
+            ("/notindex.html", "/notindex", "/notindex/", "/notindex"),
+        ];
+        for (path, unset, always, never) in cases {
+            assert_eq!(canonical_path(path, "index.html", None), unset, "{}", path);
+            let always_path = canonical_path(path, "index.html", Some(TrailingSlash::Always));
+            assert_eq!(always_path, always, "{}", path);
+            let never_path = canonical_path(path, "index.html", Some(TrailingSlash::Never));
+            assert_eq!(never_path, never, "{}", path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn redirects_pages_to_their_canonical_path() {
+        let dir = TempDir::new("canonical");
+        let live = dir.path().join("live");
+        write_file(&live.join("index.html"), "home");
+        write_file(&live.join("about.html"), "about");
+        write_file(&live.join("dir/index.html"), "dir");
+        write_file(&live.join("style.css"), "css");
+
+        // (path, status without a policy, with always, with never), with the
+        // path that's recorded or redirected to after each
+        let cases = [
+            ("/index.html", (200, "/"), (301, "/"), (301, "/")),
+            (
+                "/about.html",
+                (200, "/about"),
+                (301, "/about/"),
+                (301, "/about"),
+            ),
+            ("/about", (200, "/about"), (301, "/about/"), (200, "/about")),
+            ("/dir/", (200, "/dir"), (200, "/dir/"), (301, "/dir")),
+            ("/dir", (200, "/dir"), (301, "/dir/"), (200, "/dir")),
+            ("/", (200, "/"), (200, "/"), (200, "/")),
+            // only pages have a canonical path
+            (
+                "/style.css",
+                (200, "/style.css"),
+                (200, "/style.css"),
+                (200, "/style.css"),
+            ),
+        ];
+        let policies = [None, Some("always"), Some("never")];
+        for (i, policy) in policies.into_iter().enumerate() {
+            let mut site = test_site(&live);
+            site.trailing_slash = policy.map(|x| serde_json::from_value(x.into()).unwrap());
+            let mut config = test_config(serde_json::json!([]));
+            config.sites.push(site);
+            let recorded = Rc::new(RefCell::new(Vec::new()));
+            let log = recorded.clone();
+            let app = init_service(
+                App::new()
+                    .app_data(Data::new(config))
+                    .default_service(fn_service(serve_site))
+                    .wrap(from_fn(move |req: ServiceRequest, next: Next<_>| {
+                        let log = log.clone();
+                        async move {
+                            let path = req.path().to_string();
+                            let res = next.call(req).await?;
+                            log.borrow_mut().push(recorded_path(&res, path));
+                            Ok::<_, Error>(res)
+                        }
+                    })),
+            )
+            .await;
+
+            for case in cases {
+                let (path, expected) = (case.0, [case.1, case.2, case.3][i]);
+                let res = call_service(&app, TestRequest::get().uri(path).to_request()).await;
+                let status = res.status().as_u16();
+                let location = res.headers().get(LOCATION).cloned();
+                assert_eq!(status, expected.0, "{} with {:?}", path, policy);
+                match status {
+                    301 => assert_eq!(location.unwrap(), expected.1, "{} {:?}", path, policy),
+                    _ => assert_eq!(
+                        recorded.borrow().last().unwrap(),
+                        expected.1,
+                        "{} with {:?}",
+                        path,
+                        policy
+                    ),
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn serves_pages_but_no_listings_or_hidden_files() {
+        let dir = TempDir::new("serve");
+        let live = deployed_site(&dir);
+        let paths = [
+            "/",
+            "/docs/guide",
+            "/.well-known/security.txt",
+            "/docs/",
+            "/docs",
+            "/.env",
+            "/.htaccess",
+            "/.git/config",
+            "/..%2Fsecret.txt",
+            "/..%2Fsecret",
+        ];
+        let responses = get(test_site(&live), &paths).await;
+        let ok: Vec<_> = responses[..3]
+            .iter()
+            .map(|(_, body)| body.as_str())
+            .collect();
+        assert_eq!(ok, ["home", "guide", "Contact: ivy"]);
+        for (path, (status, _)) in paths.iter().zip(&responses).take(8).skip(3) {
+            assert_eq!(*status, StatusCode::NOT_FOUND, "{}", path);
+        }
+        // `Files` refuses these as bad requests rather than not found
+        for (path, (status, body)) in paths.iter().zip(&responses).skip(8) {
+            assert!(status.is_client_error(), "{}", path);
+            assert!(!body.contains("secret"), "{}", path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn hidden_files_dont_open_up_traversal() {
+        let dir = TempDir::new("serve-hidden");
+        let live = deployed_site(&dir);
+        let mut site = test_site(&live);
+        site.use_hidden_files = true;
+        let paths = [
+            "/.env",
+            "/..%2Fsecret.txt",
+            "/..%2Fsecret",
+            "/..%2F..%2Fetc%2Fpasswd",
+        ];
+        let responses = get(site, &paths).await;
+        assert_eq!(responses[0], (StatusCode::OK, "SECRET=1".to_string()));
+        for (path, (status, body)) in paths.iter().zip(&responses).skip(1) {
+            assert!(status.is_client_error(), "{}", path);
+            assert!(!body.contains("secret"), "{}", path);
+        }
+    }
+
+    #[test]
+    fn resolve_file_stays_in_the_live_dir() {
+        let dir = TempDir::new("resolve");
+        let live = dir.path().join("live");
+        write_file(&live.join("index.html"), "home");
+        write_file(&live.join("about.html"), "about");
+        write_file(&dir.path().join("secret.html"), "secret");
+        write_file(&dir.path().join("secret.txt"), "secret");
+        write_file(&dir.path().join("secret.txt.gz"), "secret");
+        let mut site = test_site(&live);
+        // otherwise `..` is refused as a hidden file
+        site.use_hidden_files = true;
+
+        assert!(resolve_file(&site, &live, "/about").is_some_and(|x| x.clean));
+        for path in [
+            "/..%2Fsecret",
+            "/..%2Fsecret.txt",
+            "/..%2F..%2Fetc%2Fpasswd",
+            "/%2E%2E/secret",
+            "/..%5Csecret",
+            "/a%2F..%2F..%2Fsecret",
+        ] {
+            assert!(resolve_file(&site, &live, path).is_none(), "{}", path);
+        }
+    }
+}
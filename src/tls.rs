@@ -0,0 +1,453 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header::LOCATION, Method},
+    middleware::Next,
+    rt::{
+        signal::unix::{signal, SignalKind},
+        time::sleep,
+    },
+    web::Data,
+    Error, HttpResponse,
+};
+use rustls::{
+    crypto::ring::{default_provider, sign::any_supported_type},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+use crate::config::{strip_port, Config};
+
+/// how often certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// where a site's certificate comes from and which hosts it's for
+#[derive(Debug)]
+struct CertSource {
+    domain: String,
+    hosts: Vec<String>,
+    preview_domain: Option<String>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+/// picks a site's certificate by SNI. the certificates can be swapped out
+/// while the server is running, see [`watch_certs`]
+#[derive(Debug)]
+pub struct CertResolver {
+    sources: Vec<CertSource>,
+    /// lines up with `sources`
+    keys: RwLock<Vec<Arc<CertifiedKey>>>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl CertResolver {
+    /// a resolver for every site with a certificate, or `None` if no site has one
+    pub fn from_config(config: &Config) -> Result<Option<CertResolver>, String> {
+        let mut sources = Vec::new();
+        for site in &config.sites {
+            let (cert_path, key_path) = match (&site.tls_cert_path, &site.tls_key_path) {
+                (Some(cert), Some(key)) => (cert, key),
+                (None, None) => continue,
+                _ => {
+                    return Err(format!(
+                        "{}: tls_cert_path and tls_key_path must be set together",
+                        site.domain
+                    ))
+                }
+            };
+            sources.push(CertSource {
+                domain: site.domain.clone(),
+                hosts: site.hosts().map(|x| x.to_string()).collect(),
+                preview_domain: site.preview_domain.clone(),
+                cert_path: PathBuf::from(cert_path),
+                key_path: PathBuf::from(key_path),
+            });
+        }
+        if sources.is_empty() {
+            return Ok(None);
+        }
+        let keys = sources
+            .iter()
+            .map(|x| load_key(x).map(Arc::new))
+            .collect::<Result<_, String>>()?;
+        let modified = sources.iter().map(modified).collect();
+        Ok(Some(CertResolver {
+            sources,
+            keys: RwLock::new(keys),
+            modified: Mutex::new(modified),
+        }))
+    }
+
+    /// reloads every certificate. one that fails to load keeps serving the
+    /// previous version so a half written file doesn't take the site down
+    pub fn reload(&self) {
+        // read everything before taking the lock so handshakes aren't held up
+        let loaded: Vec<_> = self
+            .sources
+            .iter()
+            .map(|x| (modified(x), load_key(x)))
+            .collect();
+        let mut keys = self.keys.write().unwrap_or_else(|e| e.into_inner());
+        let mut last_modified = self.modified.lock().unwrap_or_else(|e| e.into_inner());
+        for (i, (source, (modified, key))) in self.sources.iter().zip(loaded).enumerate() {
+            last_modified[i] = modified;
+            match key {
+                Ok(key) => {
+                    keys[i] = Arc::new(key);
+                    println!("{}: reloaded tls certificate", source.domain);
+                }
+                Err(e) => println!("{}: keeping the previous certificate: {}", source.domain, e),
+            }
+        }
+    }
+
+    /// whether any certificate or key changed on disk since it was last loaded
+    fn changed(&self) -> bool {
+        let last_modified = self.modified.lock().unwrap_or_else(|e| e.into_inner());
+        self.sources
+            .iter()
+            .zip(last_modified.iter())
+            .any(|(source, last)| modified(source) != *last)
+    }
+
+    /// a rustls config serving whichever certificate the resolver picks
+    pub fn server_config(self: Arc<Self>) -> ServerConfig {
+        ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_no_client_auth()
+            .with_cert_resolver(self)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        // clients that don't send SNI get the first site's certificate
+        let Some(name) = client_hello.server_name() else {
+            return keys.first().cloned();
+        };
+        let index = self
+            .sources
+            .iter()
+            .position(|x| x.hosts.iter().any(|host| host.eq_ignore_ascii_case(name)))
+            .or_else(|| {
+                self.sources.iter().position(|x| {
+                    x.preview_domain.as_ref().is_some_and(|domain| {
+                        name.to_ascii_lowercase()
+                            .ends_with(&format!(".{}", domain.to_ascii_lowercase()))
+                    })
+                })
+            })
+            .unwrap_or(0);
+        keys.get(index).cloned()
+    }
+}
+
+fn modified(source: &CertSource) -> Option<SystemTime> {
+    let cert = fs::metadata(&source.cert_path)
+        .and_then(|x| x.modified())
+        .ok()?;
+    let key = fs::metadata(&source.key_path)
+        .and_then(|x| x.modified())
+        .ok()?;
+    Some(cert.max(key))
+}
+
+fn load_key(source: &CertSource) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(&source.cert_path)
+        .and_then(|x| x.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("failed to read {}: {}", source.cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", source.cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(&source.key_path)
+        .map_err(|e| format!("failed to read {}: {}", source.key_path.display(), e))?;
+    let key = any_supported_type(&key)
+        .map_err(|e| format!("unsupported key {}: {}", source.key_path.display(), e))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// reloads the certificates on SIGHUP, and whenever their files change
+pub fn watch_certs(resolver: Arc<CertResolver>) {
+    let hangup = resolver.clone();
+    actix_web::rt::spawn(async move {
+        let mut signals = match signal(SignalKind::hangup()) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while signals.recv().await.is_some() {
+            hangup.reload();
+        }
+    });
+    actix_web::rt::spawn(async move {
+        loop {
+            sleep(WATCH_INTERVAL).await;
+            if resolver.changed() {
+                resolver.reload();
+            }
+        }
+    });
+}
+
+/// sends plain http requests to the https listener when `redirect_to_https` is on
+pub async fn redirect_to_https(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let config = req
+        .app_data::<Data<Config>>()
+        .expect("missing config from app data");
+    let tls_port = match config.tls_port {
+        Some(port) if config.redirect_to_https => port,
+        _ => return next.call(req).await.map(|x| x.map_into_left_body()),
+    };
+    if req.connection_info().scheme() == "https" {
+        return next.call(req).await.map(|x| x.map_into_left_body());
+    }
+
+    let host = req.connection_info().host().to_string();
+    let host = strip_port(&host);
+    let authority = match tls_port {
+        443 => host.to_string(),
+        port => format!("{}:{}", host, port),
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|x| x.as_str())
+        .unwrap_or("/");
+    let location = format!("https://{}{}", authority, path);
+    // 308 so webhooks posting to /refresh keep their method and body
+    let mut res = match *req.method() {
+        Method::GET | Method::HEAD => HttpResponse::MovedPermanently(),
+        _ => HttpResponse::PermanentRedirect(),
+    };
+    let res = res.insert_header((LOCATION, location)).finish();
+    Ok(req.into_response(res).map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use actix_web::{
+        http::StatusCode,
+        middleware::from_fn,
+        test::{call_service, init_service, TestRequest},
+        App,
+    };
+    use rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        pki_types::{ServerName, UnixTime},
+        ClientConfig, ClientConnection, DigitallySignedStruct, ServerConnection, SignatureScheme,
+    };
+
+    use super::*;
+    use crate::test_util::{test_config, TempDir};
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/tls")
+            .join(name)
+    }
+
+    fn cert_of(domain: &str) -> CertificateDer<'static> {
+        CertificateDer::from_pem_file(fixture(&format!("{}.pem", domain))).unwrap()
+    }
+
+    /// the fixtures are self signed, the tests only care which one is sent
+    #[derive(Debug)]
+    struct AcceptAny;
+
+    impl ServerCertVerifier for AcceptAny {
+        fn verify_server_cert(
+            &self,
+            _: &CertificateDer<'_>,
+            _: &[CertificateDer<'_>],
+            _: &ServerName<'_>,
+            _: &[u8],
+            _: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+        fn verify_tls12_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+        fn verify_tls13_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+
+    /// runs a handshake in memory, returning the certificate the server sent
+    fn handshake(server: &Arc<ServerConfig>, name: &str) -> CertificateDer<'static> {
+        let client = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAny))
+            .with_no_client_auth();
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        let mut client = ClientConnection::new(Arc::new(client), name).unwrap();
+        let mut server = ServerConnection::new(server.clone()).unwrap();
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut buf.as_slice()).unwrap();
+            server.process_new_packets().unwrap();
+            let mut buf = Vec::new();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut buf.as_slice()).unwrap();
+            client.process_new_packets().unwrap();
+        }
+        client.peer_certificates().unwrap()[0].clone().into_owned()
+    }
+
+    fn site(domain: &str, cert: &Path, key: &Path) -> serde_json::Value {
+        serde_json::json!({
+            "domain": domain,
+            "aliases": [format!("www.{}", domain)],
+            "site_repo": "https://example.com/site.git",
+            "branch": "main",
+            "tls_cert_path": cert,
+            "tls_key_path": key,
+        })
+    }
+
+    #[test]
+    fn picks_certificates_by_sni() {
+        let config = test_config(serde_json::json!([
+            site(
+                "one.test",
+                &fixture("one.test.pem"),
+                &fixture("one.test.key")
+            ),
+            site(
+                "two.test",
+                &fixture("two.test.pem"),
+                &fixture("two.test.key")
+            ),
+        ]));
+        let resolver = CertResolver::from_config(&config).unwrap().unwrap();
+        let server = Arc::new(Arc::new(resolver).server_config());
+
+        assert_eq!(handshake(&server, "one.test"), cert_of("one.test"));
+        assert_eq!(handshake(&server, "two.test"), cert_of("two.test"));
+        assert_eq!(handshake(&server, "www.two.test"), cert_of("two.test"));
+        // unknown names fall back to the first site
+        assert_eq!(handshake(&server, "three.test"), cert_of("one.test"));
+    }
+
+    #[test]
+    fn reloads_changed_certificates() {
+        let dir = TempDir::new("tls");
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        fs::copy(fixture("one.test.pem"), &cert).unwrap();
+        fs::copy(fixture("one.test.key"), &key).unwrap();
+        let config = test_config(serde_json::json!([site("one.test", &cert, &key)]));
+        let resolver = Arc::new(CertResolver::from_config(&config).unwrap().unwrap());
+        let server = Arc::new(resolver.clone().server_config());
+        assert_eq!(handshake(&server, "one.test"), cert_of("one.test"));
+        assert!(!resolver.changed());
+
+        // renewed certificates get a newer mtime, make sure it differs
+        std::thread::sleep(Duration::from_millis(20));
+        fs::copy(fixture("two.test.pem"), &cert).unwrap();
+        fs::copy(fixture("two.test.key"), &key).unwrap();
+        assert!(resolver.changed());
+        resolver.reload();
+        assert!(!resolver.changed());
+        assert_eq!(handshake(&server, "one.test"), cert_of("two.test"));
+
+        // a broken file keeps the certificate that was working
+        fs::write(&key, "not a key").unwrap();
+        resolver.reload();
+        assert_eq!(handshake(&server, "one.test"), cert_of("two.test"));
+    }
+
+    #[actix_web::test]
+    async fn redirects_http_keeping_the_path_and_query() {
+        let mut config = test_config(serde_json::json!([{
+            "domain": "one.test",
+            "site_repo": "https://example.com/site.git",
+            "branch": "main",
+        }]));
+        config.tls_port = Some(8443);
+        config.redirect_to_https = true;
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(config))
+                .wrap(from_fn(redirect_to_https))
+                .default_service(actix_web::web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let cases = [
+            (
+                Method::GET,
+                "one.test",
+                StatusCode::MOVED_PERMANENTLY,
+                "one.test",
+            ),
+            (
+                Method::POST,
+                "one.test:8029",
+                StatusCode::PERMANENT_REDIRECT,
+                "one.test",
+            ),
+            (
+                Method::GET,
+                "[::1]:8029",
+                StatusCode::MOVED_PERMANENTLY,
+                "[::1]",
+            ),
+        ];
+        for (method, host, status, redirected_host) in cases {
+            let req = TestRequest::default()
+                .method(method)
+                .uri("/blog/post?page=2&sort=new")
+                .insert_header(("host", host))
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status(), status);
+            assert_eq!(
+                res.headers().get(LOCATION).unwrap().to_str().unwrap(),
+                format!("https://{}:8443/blog/post?page=2&sort=new", redirected_host)
+            );
+        }
+
+        let req = TestRequest::get()
+            .uri("https://one.test/blog/post")
+            .insert_header(("host", "one.test"))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    }
+}